@@ -173,6 +173,15 @@ So if you could go ahead and try to remember to do that from now on, that'd be g
                     RuntimeError::CPULimitReached => {
                         eprintln!("Script exceeded the configured CPU limit.");
                     }
+                    RuntimeError::InvalidRegex(reason) => {
+                        eprintln!("Invalid regular expression: {reason}.");
+                    }
+                    RuntimeError::RegexLimitReached => {
+                        eprintln!("Regular expression exceeded the configured backtrack limit.");
+                    }
+                    error => {
+                        eprintln!("{error}");
+                    }
                 }
                 input = true.into();
             }
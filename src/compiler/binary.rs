@@ -0,0 +1,190 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::fmt::Display;
+
+use crate::{compiler::grammar::Capability, Compiler, Sieve};
+
+/// Identifies a compiled Sieve script.
+pub const MAGIC: [u8; 4] = *b"SIEV";
+/// Magic, compiler version, capability fingerprint and source hash.
+pub const HEADER_LEN: usize = 4 + 4 + 8 + 8;
+
+/// Header of a compiled script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub version: u32,
+    pub capabilities: u64,
+    pub source_hash: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The data is not a compiled Sieve script.
+    InvalidFormat,
+    /// The script was compiled by a different version of the compiler
+    /// or with a different set of capabilities and has to be recompiled.
+    RecompileNeeded { version: u32, source_hash: u64 },
+    /// The header is valid but the script could not be decoded.
+    Corrupted(String),
+}
+
+impl Header {
+    pub fn new(sieve: &Sieve) -> Self {
+        Header {
+            version: Compiler::VERSION,
+            capabilities: sieve.capabilities,
+            source_hash: sieve.source_hash,
+        }
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let header = bytes
+            .get(..HEADER_LEN)
+            .filter(|header| header[..4] == MAGIC)
+            .ok_or(DecodeError::InvalidFormat)?;
+        Ok(Header {
+            version: u32::from_le_bytes(header[4..8].try_into().unwrap()),
+            capabilities: u64::from_le_bytes(header[8..16].try_into().unwrap()),
+            source_hash: u64::from_le_bytes(header[16..24].try_into().unwrap()),
+        })
+    }
+
+    /// Parses the header, failing if the script has to be recompiled
+    /// by `compiler`.
+    pub fn parse_current(bytes: &[u8], compiler: &Compiler) -> Result<Self, DecodeError> {
+        let header = Header::parse(bytes)?;
+        if header.version == Compiler::VERSION
+            && header.capabilities == compiler.capability_fingerprint()
+        {
+            Ok(header)
+        } else {
+            Err(DecodeError::RecompileNeeded {
+                version: header.version,
+                source_hash: header.source_hash,
+            })
+        }
+    }
+
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&MAGIC);
+        buf.extend_from_slice(&self.version.to_le_bytes());
+        buf.extend_from_slice(&self.capabilities.to_le_bytes());
+        buf.extend_from_slice(&self.source_hash.to_le_bytes());
+    }
+}
+
+impl Sieve {
+    /// Hash of the source the script was compiled from.
+    pub fn source_hash(&self) -> u64 {
+        self.source_hash
+    }
+
+    pub fn is_compiled_from(&self, script: &[u8]) -> bool {
+        self.source_hash == source_hash(script)
+    }
+
+    /// Encodes the script prefixed with a header that identifies the compiler
+    /// version and capabilities it was built with.
+    #[cfg(feature = "rkyv")]
+    pub fn to_bytes(&self) -> Result<Vec<u8>, rkyv::rancor::Error> {
+        self.to_bytes_with(|sieve| {
+            rkyv::to_bytes::<rkyv::rancor::Error>(sieve).map(|payload| payload.into_vec())
+        })
+    }
+
+    /// Decodes and verifies a script encoded with [`Sieve::to_bytes`].
+    /// The archive is deserialized, scripts cannot be run from an
+    /// `ArchivedSieve` directly.
+    #[cfg(feature = "rkyv")]
+    pub fn from_bytes(bytes: &[u8], compiler: &Compiler) -> Result<Sieve, DecodeError> {
+        Sieve::from_bytes_with(bytes, compiler, |payload| {
+            let mut aligned = rkyv::util::AlignedVec::<16>::with_capacity(payload.len());
+            aligned.extend_from_slice(payload);
+            rkyv::from_bytes::<Sieve, rkyv::rancor::Error>(&aligned)
+        })
+    }
+
+    /// Encodes the script with `serialize`, prefixed with the same header
+    /// as [`Sieve::to_bytes`].
+    #[cfg(any(test, feature = "serde", feature = "rkyv"))]
+    pub fn to_bytes_with<E>(
+        &self,
+        serialize: impl FnOnce(&Sieve) -> Result<Vec<u8>, E>,
+    ) -> Result<Vec<u8>, E> {
+        let payload = serialize(self)?;
+        let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+        Header::new(self).write(&mut bytes);
+        bytes.extend_from_slice(&payload);
+        Ok(bytes)
+    }
+
+    /// Decodes with `deserialize` and verifies a script encoded with
+    /// [`Sieve::to_bytes_with`].
+    #[cfg(any(test, feature = "serde", feature = "rkyv"))]
+    pub fn from_bytes_with<E: Display>(
+        bytes: &[u8],
+        compiler: &Compiler,
+        deserialize: impl FnOnce(&[u8]) -> Result<Sieve, E>,
+    ) -> Result<Sieve, DecodeError> {
+        let header = Header::parse_current(bytes, compiler)?;
+        let sieve = deserialize(&bytes[HEADER_LEN..])
+            .map_err(|err| DecodeError::Corrupted(err.to_string()))?;
+        if sieve.source_hash != header.source_hash || sieve.capabilities != header.capabilities {
+            Err(DecodeError::Corrupted("header mismatch".to_string()))
+        } else if let Err(err) = sieve.verify() {
+            Err(DecodeError::Corrupted(err.to_string()))
+        } else {
+            Ok(sieve)
+        }
+    }
+}
+
+impl Compiler {
+    /// Hash of the capabilities enabled in the compiler, scripts compiled
+    /// with a different set have to be recompiled.
+    pub fn capability_fingerprint(&self) -> u64 {
+        let mut capabilities = Vec::new();
+        for capability in Capability::all() {
+            capabilities.extend_from_slice(capability.to_string().as_bytes());
+            capabilities.push(b' ');
+        }
+        if self.no_capability_check {
+            capabilities.extend_from_slice(b"no-capability-check ");
+        }
+
+        // External functions are referenced by id
+        let mut functions = self.functions.iter().collect::<Vec<_>>();
+        functions.sort_unstable();
+        for (name, (id, num_args)) in functions {
+            capabilities.extend_from_slice(format!("{name}/{id}/{num_args} ").as_bytes());
+        }
+
+        source_hash(&capabilities)
+    }
+}
+
+/// FNV-1a, which unlike the hashers in `std` is stable across releases.
+pub fn source_hash(script: &[u8]) -> u64 {
+    script.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::InvalidFormat => write!(f, "Not a compiled Sieve script"),
+            DecodeError::RecompileNeeded { version, .. } => write!(
+                f,
+                "Script compiled by an incompatible compiler (version {version}), recompile needed"
+            ),
+            DecodeError::Corrupted(err) => write!(f, "Corrupted compiled script: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
@@ -0,0 +1,490 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::fmt::Write;
+
+use crate::Compiler;
+
+use super::{
+    grammar::Capability,
+    lexer::{
+        tokenizer::{Comment, TokenInfo, Tokenizer},
+        StringConstant, Token,
+    },
+    CompileError, ErrorType,
+};
+
+const INDENT: &str = "    ";
+const MAX_LINE_LENGTH: usize = 80;
+
+#[derive(Debug)]
+struct Command {
+    name: String,
+    arguments: Vec<Argument>,
+    block: Option<Block>,
+    comments: Vec<Comment>,
+    trailing_comment: Option<Comment>,
+    line_num: usize,
+    end_line_num: usize,
+}
+
+#[derive(Debug, Default)]
+struct Block {
+    commands: Vec<Command>,
+    comments: Vec<Comment>,
+    end_line_num: usize,
+}
+
+#[derive(Debug)]
+struct Test {
+    name: String,
+    arguments: Vec<Argument>,
+}
+
+#[derive(Debug)]
+enum Argument {
+    Tag(String),
+    Number(usize),
+    String(Vec<u8>),
+    StringList(Vec<Vec<u8>>),
+    Test(Test),
+    TestList(Vec<Test>),
+}
+
+struct Parser<'x> {
+    tokens: Tokenizer<'x>,
+}
+
+impl Compiler {
+    /// Parses and validates a Sieve script and returns it as canonical,
+    /// formatted Sieve source. Comments are kept next to the command they
+    /// precede (or follow on the same line) and all top-level `require`
+    /// commands are merged into a single deduplicated list.
+    pub fn format(&self, script: &[u8]) -> Result<String, CompileError> {
+        self.compile(script)?;
+
+        let mut parser = Parser {
+            tokens: Tokenizer::new(self, script).with_comments(),
+        };
+        let mut block = parser.parse_block(true)?;
+        block.merge_requires();
+
+        let mut out = String::with_capacity(script.len());
+        block.write(&mut out, 0);
+        Ok(out)
+    }
+}
+
+impl Parser<'_> {
+    fn parse_block(&mut self, is_root: bool) -> Result<Block, CompileError> {
+        let mut block = Block::default();
+
+        loop {
+            let token_info = match self.tokens.next() {
+                Some(token_info) => Some(token_info?),
+                None if is_root => None,
+                None => return Err(self.unexpected_eof()),
+            };
+            let mut comments = self.take_comments(token_info.as_ref());
+            if let Some(command) = block.commands.last_mut() {
+                if command.trailing_comment.is_none()
+                    && comments
+                        .first()
+                        .is_some_and(|comment| comment.line_num == command.end_line_num)
+                {
+                    command.trailing_comment = Some(comments.remove(0));
+                }
+            }
+
+            let Some(token_info) = token_info else {
+                block.comments = comments;
+                return Ok(block);
+            };
+
+            let name = match token_info.token {
+                Token::CurlyClose if !is_root => {
+                    block.comments = comments;
+                    block.end_line_num = token_info.line_num;
+                    return Ok(block);
+                }
+                Token::Identifier(word) => word.to_string(),
+                Token::Unknown(name) if !name.starts_with(':') => name,
+                token => {
+                    return Err(TokenInfo {
+                        token,
+                        line_num: token_info.line_num,
+                        line_pos: token_info.line_pos,
+                    }
+                    .expected("command"))
+                }
+            };
+
+            let mut command = Command {
+                name,
+                arguments: self.parse_arguments(false)?,
+                block: None,
+                comments,
+                trailing_comment: None,
+                line_num: token_info.line_num,
+                end_line_num: token_info.line_num,
+            };
+
+            let token_info = self.tokens.unwrap_next()?;
+            let comments = self.take_comments(Some(&token_info));
+            command.comments.extend(comments);
+            command.end_line_num = token_info.line_num;
+            match token_info.token {
+                Token::Semicolon => {}
+                Token::CurlyOpen => {
+                    let block = self.parse_block(false)?;
+                    command.end_line_num = block.end_line_num;
+                    command.block = Some(block);
+                }
+                _ => return Err(token_info.expected("';' or '{'")),
+            }
+
+            block.commands.push(command);
+        }
+    }
+
+    fn parse_arguments(&mut self, is_test: bool) -> Result<Vec<Argument>, CompileError> {
+        let mut arguments = Vec::new();
+
+        loop {
+            match self.tokens.peek() {
+                Some(Ok(token_info)) => match &token_info.token {
+                    Token::Semicolon | Token::CurlyOpen => break,
+                    Token::Comma | Token::ParenthesisClose if is_test => break,
+                    _ => (),
+                },
+                Some(Err(err)) => return Err(err),
+                None => return Err(self.unexpected_eof()),
+            }
+
+            let token_info = self.tokens.unwrap_next()?;
+            arguments.push(match token_info.token {
+                Token::Tag(word) => Argument::Tag(format!(":{word}")),
+                Token::Unknown(tag) if tag.starts_with(':') => Argument::Tag(tag),
+                Token::Number(number) => Argument::Number(number),
+                Token::StringConstant(_) | Token::StringVariable(_) => {
+                    Argument::String(token_info.token.into_bytes())
+                }
+                Token::BracketOpen => {
+                    let mut strings = Vec::new();
+                    loop {
+                        let token_info = self.tokens.unwrap_next()?;
+                        match token_info.token {
+                            Token::StringConstant(_) | Token::StringVariable(_) => {
+                                strings.push(token_info.token.into_bytes());
+                            }
+                            Token::Comma => (),
+                            Token::BracketClose => break,
+                            _ => return Err(token_info.expected("string")),
+                        }
+                    }
+                    Argument::StringList(strings)
+                }
+                Token::Identifier(word) => Argument::Test(Test {
+                    name: word.to_string(),
+                    arguments: self.parse_arguments(true)?,
+                }),
+                Token::Unknown(name) => Argument::Test(Test {
+                    name,
+                    arguments: self.parse_arguments(true)?,
+                }),
+                Token::ParenthesisOpen => {
+                    let mut tests = Vec::new();
+                    loop {
+                        let token_info = self.tokens.unwrap_next()?;
+                        match token_info.token {
+                            Token::Identifier(word) => tests.push(Test {
+                                name: word.to_string(),
+                                arguments: self.parse_arguments(true)?,
+                            }),
+                            Token::Unknown(name) if !name.starts_with(':') => tests.push(Test {
+                                name,
+                                arguments: self.parse_arguments(true)?,
+                            }),
+                            Token::Comma => (),
+                            Token::ParenthesisClose => break,
+                            _ => return Err(token_info.expected("test")),
+                        }
+                    }
+                    Argument::TestList(tests)
+                }
+                _ => return Err(token_info.expected("argument")),
+            });
+        }
+
+        Ok(arguments)
+    }
+
+    fn take_comments(&mut self, token_info: Option<&TokenInfo>) -> Vec<Comment> {
+        let Some(comments) = &mut self.tokens.comments else {
+            return Vec::new();
+        };
+        let count = if let Some(token_info) = token_info {
+            comments
+                .iter()
+                .take_while(|comment| {
+                    (comment.line_num, comment.line_pos)
+                        < (token_info.line_num, token_info.line_pos)
+                })
+                .count()
+        } else {
+            comments.len()
+        };
+        comments.drain(..count).collect()
+    }
+
+    fn unexpected_eof(&self) -> CompileError {
+        CompileError {
+            line_num: self.tokens.line_num,
+            line_pos: self.tokens.pos - self.tokens.line_start,
+            error_type: ErrorType::UnexpectedEOF,
+        }
+    }
+}
+
+impl Token {
+    fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Token::StringVariable(bytes) => bytes,
+            Token::StringConstant(StringConstant::String(string)) => string.into_bytes(),
+            token => token.to_string().into_bytes(),
+        }
+    }
+}
+
+impl Block {
+    fn merge_requires(&mut self) {
+        let mut capabilities: Vec<Vec<u8>> = Vec::new();
+        let mut comments = Vec::new();
+        let mut first_pos = None;
+        let mut pos = 0;
+
+        while pos < self.commands.len() {
+            let command = &self.commands[pos];
+            let names = match command.arguments.as_slice() {
+                [Argument::String(name)] if command.name == "require" => std::slice::from_ref(name),
+                [Argument::StringList(names)] if command.name == "require" => names.as_slice(),
+                _ => {
+                    pos += 1;
+                    continue;
+                }
+            };
+
+            for name in names {
+                let name = Capability::parse(&String::from_utf8_lossy(name))
+                    .to_string()
+                    .into_bytes();
+                if !capabilities.contains(&name) {
+                    capabilities.push(name);
+                }
+            }
+
+            if first_pos.is_none() {
+                first_pos = Some(pos);
+                pos += 1;
+            } else {
+                let command = self.commands.remove(pos);
+                comments.extend(command.comments);
+                comments.extend(command.trailing_comment);
+            }
+        }
+
+        if let Some(pos) = first_pos {
+            let command = &mut self.commands[pos];
+            command.comments.extend(comments);
+            command.arguments = vec![Argument::StringList(capabilities)];
+        }
+    }
+
+    fn write(&self, out: &mut String, indent: usize) {
+        let mut last_command: Option<&Command> = None;
+
+        for command in &self.commands {
+            let start_line_num = command
+                .comments
+                .first()
+                .map_or(command.line_num, |comment| comment.line_num);
+
+            match last_command {
+                Some(last_command)
+                    if matches!(command.name.as_str(), "elsif" | "else")
+                        && command.comments.is_empty()
+                        && last_command.block.is_some()
+                        && last_command.trailing_comment.is_none() =>
+                {
+                    out.push(' ');
+                }
+                Some(last_command) => {
+                    out.push('\n');
+                    if start_line_num > last_command.end_line_num + 1 {
+                        out.push('\n');
+                    }
+                    write_indent(out, indent);
+                }
+                None => {
+                    write_indent(out, indent);
+                }
+            }
+
+            for comment in &command.comments {
+                comment.write(out);
+                out.push('\n');
+                write_indent(out, indent);
+            }
+
+            command.write(out, indent);
+            last_command = Some(command);
+        }
+
+        if last_command.is_some() {
+            out.push('\n');
+        }
+
+        for comment in &self.comments {
+            write_indent(out, indent);
+            comment.write(out);
+            out.push('\n');
+        }
+    }
+}
+
+impl Command {
+    fn write(&self, out: &mut String, indent: usize) {
+        out.push_str(&self.name);
+        write_arguments(out, &self.arguments);
+
+        if let Some(block) = &self.block {
+            out.push_str(" {\n");
+            block.write(out, indent + 1);
+            write_indent(out, indent);
+            out.push('}');
+        } else {
+            out.push(';');
+        }
+
+        if let Some(comment) = &self.trailing_comment {
+            out.push(' ');
+            comment.write(out);
+        }
+    }
+}
+
+impl Test {
+    fn write(&self, out: &mut String) {
+        out.push_str(&self.name);
+        write_arguments(out, &self.arguments);
+    }
+}
+
+impl Comment {
+    fn write(&self, out: &mut String) {
+        let text = String::from_utf8_lossy(&self.text);
+        if self.is_bracket {
+            let _ = write!(out, "/*{text}*/");
+        } else {
+            let _ = write!(out, "#{}", text.trim_end());
+        }
+    }
+}
+
+fn write_arguments(out: &mut String, arguments: &[Argument]) {
+    for argument in arguments {
+        if !out.ends_with('\n') {
+            out.push(' ');
+        }
+
+        match argument {
+            Argument::Tag(tag) => out.push_str(tag),
+            Argument::Number(number) => write_number(out, *number),
+            Argument::String(string) => write_string(out, string),
+            Argument::StringList(strings) => {
+                if let [string] = strings.as_slice() {
+                    write_string(out, string);
+                } else {
+                    out.push('[');
+                    for (pos, string) in strings.iter().enumerate() {
+                        if pos > 0 {
+                            out.push_str(", ");
+                        }
+                        write_string(out, string);
+                    }
+                    out.push(']');
+                }
+            }
+            Argument::Test(test) => test.write(out),
+            Argument::TestList(tests) => {
+                out.push('(');
+                let column = out.len() - out.rfind('\n').map_or(0, |pos| pos + 1);
+                let length = tests
+                    .iter()
+                    .map(|test| {
+                        let mut test_out = String::new();
+                        test.write(&mut test_out);
+                        test_out.len() + 2
+                    })
+                    .sum::<usize>();
+                let is_multiline = tests.len() > 1 && column + length > MAX_LINE_LENGTH;
+
+                for (pos, test) in tests.iter().enumerate() {
+                    if pos > 0 {
+                        if is_multiline {
+                            out.push_str(",\n");
+                            out.extend(std::iter::repeat_n(' ', column));
+                        } else {
+                            out.push_str(", ");
+                        }
+                    }
+                    test.write(out);
+                }
+                out.push(')');
+            }
+        }
+    }
+}
+
+pub(crate) fn write_number(out: &mut String, number: usize) {
+    let _ = match number {
+        0 => write!(out, "0"),
+        _ if number.is_multiple_of(1073741824) => write!(out, "{}G", number / 1073741824),
+        _ if number.is_multiple_of(1048576) => write!(out, "{}M", number / 1048576),
+        _ if number.is_multiple_of(1024) => write!(out, "{}K", number / 1024),
+        _ => write!(out, "{number}"),
+    };
+}
+
+pub(crate) fn write_string(out: &mut String, string: &[u8]) {
+    let string = String::from_utf8_lossy(string);
+
+    if string.ends_with('\n') && string[..string.len() - 1].contains('\n') {
+        out.push_str("text:\n");
+        for line in string.split_inclusive('\n') {
+            if line.starts_with('.') {
+                out.push('.');
+            }
+            out.push_str(line);
+        }
+        out.push_str(".\n");
+    } else {
+        out.push('"');
+        for ch in string.chars() {
+            if matches!(ch, '"' | '\\') {
+                out.push('\\');
+            }
+            out.push(ch);
+        }
+        out.push('"');
+    }
+}
+
+fn write_indent(out: &mut String, indent: usize) {
+    for _ in 0..indent {
+        out.push_str(INDENT);
+    }
+}
@@ -89,7 +89,9 @@ impl CompilerState<'_> {
                     special_use = self.parse_string()?.into();
                 }
                 _ => {
+                    self.begin_mailbox_refs();
                     folder = self.parse_string_token(token_info)?;
+                    self.end_mailbox_refs();
                     break;
                 }
             }
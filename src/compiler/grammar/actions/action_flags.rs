@@ -4,8 +4,6 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-
-
 use crate::compiler::{
     grammar::instruction::{CompilerState, Instruction},
     lexer::{word::Word, Token},
@@ -62,11 +60,15 @@ impl CompilerState<'_> {
                     Some(Ok(
                         Token::StringConstant(_) | Token::StringVariable(_) | Token::BracketOpen,
                     )),
-                ) => EditFlags {
-                    name: self.parse_variable_name(token_info, false)?.into(),
-                    flags: self.parse_strings(false)?,
-                    action,
-                },
+                ) => {
+                    let name = self.parse_variable_name(token_info, false)?;
+                    self.register_var_read(&name);
+                    EditFlags {
+                        name: name.into(),
+                        flags: self.parse_strings(false)?,
+                        action,
+                    }
+                }
                 (Token::BracketOpen, _)
                 | (
                     Token::StringConstant(_) | Token::StringVariable(_),
@@ -4,8 +4,6 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-
-
 use crate::{
     compiler::{
         grammar::{
@@ -119,7 +117,9 @@ impl CompilerState<'_> {
                         token_info.line_num,
                         token_info.line_pos,
                     )?;
+                    self.begin_mailbox_refs();
                     fcc = self.parse_string()?.into();
+                    self.end_mailbox_refs();
                 }
                 Token::Tag(Word::Flags) => {
                     self.validate_argument(
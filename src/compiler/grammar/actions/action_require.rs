@@ -14,8 +14,16 @@ use crate::compiler::{
 };
 
 impl CompilerState<'_> {
-    fn add_capability(&mut self, capabilities: &mut Vec<Capability>, capability: Capability) {
+    fn add_capability(
+        &mut self,
+        capabilities: &mut Vec<Capability>,
+        capability: Capability,
+        line_num: usize,
+        line_pos: usize,
+    ) {
         if !self.has_capability(&capability) {
+            self.capabilities_required
+                .push((capability.clone(), line_num, line_pos));
             let parent_capability = if matches!(&capability, Capability::SpamTestPlus) {
                 Some(Capability::SpamTest)
             } else {
@@ -45,6 +53,8 @@ impl CompilerState<'_> {
                         self.add_capability(
                             &mut capabilities,
                             Capability::parse(value.to_string().as_ref()),
+                            token_info.line_num,
+                            token_info.line_pos,
                         );
                         let token_info = self.tokens.unwrap_next()?;
                         match token_info.token {
@@ -64,6 +74,8 @@ impl CompilerState<'_> {
                 self.add_capability(
                     &mut capabilities,
                     Capability::parse(value.to_string().as_ref()),
+                    token_info.line_num,
+                    token_info.line_pos,
                 );
             }
             _ => {
@@ -155,13 +155,17 @@ impl CompilerState<'_> {
         register_as_local: bool,
     ) -> Result<VariableType, CompileError> {
         match token_info.token {
-            Token::StringConstant(value) => self
-                .register_variable(value.into_string(), register_as_local)
-                .map_err(|error_type| CompileError {
-                    line_num: token_info.line_num,
-                    line_pos: token_info.line_pos,
-                    error_type,
-                }),
+            Token::StringConstant(value) => {
+                let var = self
+                    .register_variable(value.into_string(), register_as_local)
+                    .map_err(|error_type| CompileError {
+                        line_num: token_info.line_num,
+                        line_pos: token_info.line_pos,
+                        error_type,
+                    })?;
+                self.register_var_write(&var, token_info.line_num, token_info.line_pos);
+                Ok(var)
+            }
             _ => Err(token_info.custom(ErrorType::ExpectedConstantString)),
         }
     }
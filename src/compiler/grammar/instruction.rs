@@ -8,9 +8,14 @@ use ahash::{AHashMap, AHashSet};
 
 use crate::{
     compiler::{
+        binary,
         grammar::{test::Test, MatchType},
-        lexer::{tokenizer::Tokenizer, word::Word, Token},
-        CompileError, ErrorType, Value, VariableType,
+        lexer::{
+            tokenizer::{TokenInfo, Tokenizer},
+            word::Word,
+            Token,
+        },
+        CompileError, CompileWarning, Diagnostics, ErrorType, Value, VariableType, WarningType,
     },
     Compiler, Sieve,
 };
@@ -31,7 +36,7 @@ use super::{
         action_vacation::Vacation,
     },
     expr::Expression,
-    Capability, Clear, Invalid, While,
+    Capability, Clear, Invalid, SourcePosition, StringRef, While,
 };
 
 use super::tests::test_ihave::Error;
@@ -124,12 +129,14 @@ pub(crate) struct Block {
     pub(crate) vars_local: AHashMap<String, usize>,
     pub(crate) capabilities: AHashSet<Capability>,
     pub(crate) require_pos: usize,
+    pub(crate) is_stopped: bool,
 }
 
 pub(crate) struct CompilerState<'x> {
     pub(crate) compiler: &'x Compiler,
     pub(crate) tokens: Tokenizer<'x>,
     pub(crate) instructions: Vec<Instruction>,
+    pub(crate) positions: Vec<SourcePosition>,
     pub(crate) block_stack: Vec<Block>,
     pub(crate) block: Block,
     pub(crate) last_block_type: Word,
@@ -140,11 +147,84 @@ pub(crate) struct CompilerState<'x> {
     pub(crate) vars_local: usize,
     pub(crate) param_check: [bool; MAX_PARAMS],
     pub(crate) includes_num: usize,
+    pub(crate) vars_written: AHashMap<String, (usize, usize)>,
+    pub(crate) vars_read: AHashSet<String>,
+    pub(crate) capabilities_required: Vec<(Capability, usize, usize)>,
+    pub(crate) capabilities_used: AHashSet<Capability>,
+    pub(crate) warnings: Vec<CompileWarning>,
+    pub(crate) string_refs: Option<Vec<StringRef>>,
+    pub(crate) mailbox_refs: Vec<StringRef>,
+    pub(crate) source_hash: u64,
+    pub(crate) is_lenient: bool,
 }
 
 impl Compiler {
     pub fn compile(&self, script: &[u8]) -> Result<Sieve, CompileError> {
-        if script.len() > self.max_script_size {
+        let mut state = CompilerState::new(self, script)?;
+        state.parse_script()?;
+        Ok(state.into_sieve())
+    }
+
+    pub fn compile_with_warnings(
+        &self,
+        script: &[u8],
+    ) -> Result<(Sieve, Vec<CompileWarning>), CompileError> {
+        let mut state = CompilerState::new(self, script)?;
+        state.is_lenient = true;
+        state.parse_script()?;
+        let warnings = state.take_warnings();
+        Ok((state.into_sieve(), warnings))
+    }
+
+    pub fn compile_with_diagnostics(&self, script: &[u8]) -> Diagnostics {
+        let mut state = match CompilerState::new(self, script) {
+            Ok(state) => state,
+            Err(err) => {
+                return Diagnostics {
+                    sieve: None,
+                    errors: vec![err],
+                    warnings: vec![],
+                }
+            }
+        };
+        state.is_lenient = true;
+        let mut errors = Vec::new();
+
+        while let Some(token_info) = state.tokens.next() {
+            let start_pos = state.instructions.len();
+            if let Err(err) = token_info.and_then(|token_info| state.parse_instruction(token_info))
+            {
+                errors.push(err);
+                state.recover(start_pos, &mut errors);
+            }
+        }
+
+        if !state.block_stack.is_empty() {
+            errors.push(CompileError {
+                line_num: state.block.line_num,
+                line_pos: state.block.line_pos,
+                error_type: ErrorType::UnterminatedBlock,
+            });
+            while !state.block_stack.is_empty() {
+                state.close_block();
+            }
+            state.add_positions(
+                state.tokens.line_num,
+                state.tokens.pos - state.tokens.line_start,
+            );
+        }
+
+        Diagnostics {
+            warnings: state.take_warnings(),
+            sieve: Some(state.into_sieve()),
+            errors,
+        }
+    }
+}
+
+impl<'x> CompilerState<'x> {
+    pub(crate) fn new(compiler: &'x Compiler, script: &'x [u8]) -> Result<Self, CompileError> {
+        if script.len() > compiler.max_script_size {
             return Err(CompileError {
                 line_num: 0,
                 line_pos: 0,
@@ -152,10 +232,11 @@ impl Compiler {
             });
         }
 
-        let mut state = CompilerState {
-            compiler: self,
-            tokens: Tokenizer::new(self, script),
+        Ok(CompilerState {
+            compiler,
+            tokens: Tokenizer::new(compiler, script),
             instructions: Vec::new(),
+            positions: Vec::new(),
             block_stack: Vec::new(),
             block: Block::new(Word::Not),
             last_block_type: Word::Not,
@@ -166,665 +247,806 @@ impl Compiler {
             vars_local: 0,
             param_check: [false; MAX_PARAMS],
             includes_num: 0,
-        };
+            vars_written: AHashMap::new(),
+            vars_read: AHashSet::new(),
+            capabilities_required: Vec::new(),
+            capabilities_used: AHashSet::new(),
+            warnings: Vec::new(),
+            string_refs: None,
+            mailbox_refs: Vec::new(),
+            source_hash: binary::source_hash(script),
+            is_lenient: false,
+        })
+    }
 
-        while let Some(token_info) = state.tokens.next() {
-            let token_info = token_info?;
-            state.reset_param_check();
+    pub(crate) fn parse_script(&mut self) -> Result<(), CompileError> {
+        while let Some(token_info) = self.tokens.next() {
+            self.parse_instruction(token_info?)?;
+        }
 
-            match token_info.token {
-                Token::Identifier(instruction) => {
-                    let mut is_new_block = None;
+        if !self.block_stack.is_empty() {
+            return Err(CompileError {
+                line_num: self.block.line_num,
+                line_pos: self.block.line_pos,
+                error_type: ErrorType::UnterminatedBlock,
+            });
+        }
+
+        Ok(())
+    }
 
-                    match instruction {
-                        Word::Require => {
-                            state.parse_require()?;
+    pub(crate) fn parse_instruction(&mut self, token_info: TokenInfo) -> Result<(), CompileError> {
+        let line_num = token_info.line_num;
+        let line_pos = token_info.line_pos;
+        self.parse_command(token_info)?;
+        self.add_positions(line_num, line_pos);
+        Ok(())
+    }
+
+    fn parse_command(&mut self, token_info: TokenInfo) -> Result<(), CompileError> {
+        self.reset_param_check();
+
+        if self.block.is_stopped && !matches!(token_info.token, Token::CurlyClose) {
+            self.block.is_stopped = false;
+            self.warnings.push(CompileWarning {
+                line_num: token_info.line_num,
+                line_pos: token_info.line_pos,
+                warning_type: WarningType::UnreachableCode,
+            });
+        }
+
+        match token_info.token {
+            Token::Identifier(instruction) => {
+                let mut is_new_block = None;
+
+                match instruction {
+                    Word::Require => {
+                        self.parse_require()?;
+                    }
+                    Word::If => {
+                        self.parse_test()?;
+                        self.block.if_jmps.clear();
+                        is_new_block = Block::new(Word::If).into();
+                    }
+                    Word::ElsIf => {
+                        if let Word::If | Word::ElsIf = &self.last_block_type {
+                            self.parse_test()?;
+                            is_new_block = Block::new(Word::ElsIf).into();
+                        } else {
+                            return Err(token_info.expected("'if' before 'elsif'"));
                         }
-                        Word::If => {
-                            state.parse_test()?;
-                            state.block.if_jmps.clear();
-                            is_new_block = Block::new(Word::If).into();
+                    }
+                    Word::Else => {
+                        if let Word::If | Word::ElsIf = &self.last_block_type {
+                            is_new_block = Block::new(Word::Else).into();
+                        } else {
+                            return Err(token_info.expected("'if' or 'elsif' before 'else'"));
                         }
-                        Word::ElsIf => {
-                            if let Word::If | Word::ElsIf = &state.last_block_type {
-                                state.parse_test()?;
-                                is_new_block = Block::new(Word::ElsIf).into();
-                            } else {
-                                return Err(token_info.expected("'if' before 'elsif'"));
-                            }
+                    }
+                    Word::Keep => {
+                        self.parse_keep()?;
+                    }
+                    Word::FileInto => {
+                        self.validate_argument(
+                            0,
+                            Capability::FileInto.into(),
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
+                        self.parse_fileinto()?;
+                    }
+                    Word::Redirect => {
+                        self.parse_redirect()?;
+                    }
+                    Word::Discard => {
+                        self.instructions.push(Instruction::Discard);
+                    }
+                    Word::Stop => {
+                        self.instructions.push(Instruction::Stop);
+                        self.block.is_stopped = true;
+                    }
+
+                    // RFC 5703
+                    Word::ForEveryPart => {
+                        self.validate_argument(
+                            0,
+                            Capability::ForEveryPart.into(),
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
+
+                        if self
+                            .block_stack
+                            .iter()
+                            .filter(|b| matches!(&b.btype, Word::ForEveryPart))
+                            .count()
+                            == self.compiler.max_nested_foreverypart
+                        {
+                            return Err(token_info.custom(ErrorType::TooManyNestedForEveryParts));
                         }
-                        Word::Else => {
-                            if let Word::If | Word::ElsIf = &state.last_block_type {
-                                is_new_block = Block::new(Word::Else).into();
-                            } else {
-                                return Err(token_info.expected("'if' or 'elsif' before 'else'"));
+
+                        is_new_block = if let Some(Ok(Token::Tag(Word::Name))) =
+                            self.tokens.peek().map(|r| r.map(|t| &t.token))
+                        {
+                            let tag = self.tokens.next().unwrap().unwrap();
+                            let label = self.tokens.expect_static_string()?;
+                            for block in &self.block_stack {
+                                if block.label.as_ref().is_some_and(|n| n.eq(&label)) {
+                                    return Err(tag.custom(ErrorType::LabelAlreadyDefined(label)));
+                                }
                             }
+                            Block::new(Word::ForEveryPart).with_label(label)
+                        } else {
+                            Block::new(Word::ForEveryPart)
                         }
-                        Word::Keep => {
-                            state.parse_keep()?;
-                        }
-                        Word::FileInto => {
-                            state.validate_argument(
-                                0,
-                                Capability::FileInto.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
-                            state.parse_fileinto()?;
-                        }
-                        Word::Redirect => {
-                            state.parse_redirect()?;
-                        }
-                        Word::Discard => {
-                            state.instructions.push(Instruction::Discard);
-                        }
-                        Word::Stop => {
-                            state.instructions.push(Instruction::Stop);
-                        }
+                        .into();
 
-                        // RFC 5703
-                        Word::ForEveryPart => {
-                            state.validate_argument(
+                        self.instructions.push(Instruction::ForEveryPartPush);
+                        self.instructions
+                            .push(Instruction::ForEveryPart(ForEveryPart {
+                                jz_pos: usize::MAX,
+                            }));
+                    }
+                    Word::Break => {
+                        if let Some(Ok(Token::Tag(Word::Name))) =
+                            self.tokens.peek().map(|r| r.map(|t| &t.token))
+                        {
+                            self.validate_argument(
                                 0,
                                 Capability::ForEveryPart.into(),
                                 token_info.line_num,
                                 token_info.line_pos,
                             )?;
 
-                            if state
-                                .block_stack
-                                .iter()
-                                .filter(|b| matches!(&b.btype, Word::ForEveryPart))
-                                .count()
-                                == self.max_nested_foreverypart
-                            {
-                                return Err(
-                                    token_info.custom(ErrorType::TooManyNestedForEveryParts)
-                                );
-                            }
+                            let tag = self.tokens.next().unwrap().unwrap();
+                            let label = self.tokens.expect_static_string()?;
+                            let mut label_found = false;
+                            let mut num_pops = 0;
 
-                            is_new_block = if let Some(Ok(Token::Tag(Word::Name))) =
-                                state.tokens.peek().map(|r| r.map(|t| &t.token))
+                            for block in [&mut self.block]
+                                .into_iter()
+                                .chain(self.block_stack.iter_mut().rev())
                             {
-                                let tag = state.tokens.next().unwrap().unwrap();
-                                let label = state.tokens.expect_static_string()?;
-                                for block in &state.block_stack {
+                                if let Word::ForEveryPart = &block.btype {
+                                    num_pops += 1;
                                     if block.label.as_ref().is_some_and(|n| n.eq(&label)) {
-                                        return Err(
-                                            tag.custom(ErrorType::LabelAlreadyDefined(label))
-                                        );
+                                        self.instructions
+                                            .push(Instruction::ForEveryPartPop(num_pops));
+                                        block.break_jmps.push(self.instructions.len());
+                                        label_found = true;
+                                        break;
                                     }
                                 }
-                                Block::new(Word::ForEveryPart).with_label(label)
-                            } else {
-                                Block::new(Word::ForEveryPart)
                             }
-                            .into();
-
-                            state.instructions.push(Instruction::ForEveryPartPush);
-                            state
-                                .instructions
-                                .push(Instruction::ForEveryPart(ForEveryPart {
-                                    jz_pos: usize::MAX,
-                                }));
-                        }
-                        Word::Break => {
-                            if let Some(Ok(Token::Tag(Word::Name))) =
-                                state.tokens.peek().map(|r| r.map(|t| &t.token))
-                            {
-                                state.validate_argument(
-                                    0,
-                                    Capability::ForEveryPart.into(),
-                                    token_info.line_num,
-                                    token_info.line_pos,
-                                )?;
-
-                                let tag = state.tokens.next().unwrap().unwrap();
-                                let label = state.tokens.expect_static_string()?;
-                                let mut label_found = false;
-                                let mut num_pops = 0;
-
-                                for block in [&mut state.block]
-                                    .into_iter()
-                                    .chain(state.block_stack.iter_mut().rev())
-                                {
-                                    if let Word::ForEveryPart = &block.btype {
-                                        num_pops += 1;
-                                        if block.label.as_ref().is_some_and(|n| n.eq(&label)) {
-                                            state
-                                                .instructions
-                                                .push(Instruction::ForEveryPartPop(num_pops));
-                                            block.break_jmps.push(state.instructions.len());
-                                            label_found = true;
-                                            break;
-                                        }
-                                    }
-                                }
 
-                                if !label_found {
-                                    return Err(tag.custom(ErrorType::LabelUndefined(label)));
-                                }
+                            if !label_found {
+                                return Err(tag.custom(ErrorType::LabelUndefined(label)));
+                            }
+                        } else {
+                            let mut block_found = None;
+                            if matches!(&self.block.btype, Word::ForEveryPart | Word::While) {
+                                block_found = Some(&mut self.block);
                             } else {
-                                let mut block_found = None;
-                                if matches!(&state.block.btype, Word::ForEveryPart | Word::While) {
-                                    block_found = Some(&mut state.block);
-                                } else {
-                                    for block in state.block_stack.iter_mut().rev() {
-                                        if matches!(&block.btype, Word::ForEveryPart | Word::While)
-                                        {
-                                            block_found = Some(block);
-                                            break;
-                                        }
+                                for block in self.block_stack.iter_mut().rev() {
+                                    if matches!(&block.btype, Word::ForEveryPart | Word::While) {
+                                        block_found = Some(block);
+                                        break;
                                     }
                                 }
+                            }
 
-                                let block = block_found.ok_or_else(|| {
-                                    token_info.custom(ErrorType::BreakOutsideLoop)
-                                })?;
-                                if matches!(block.btype, Word::ForEveryPart) {
-                                    state.instructions.push(Instruction::ForEveryPartPop(1));
-                                }
-
-                                block.break_jmps.push(state.instructions.len());
+                            let block = block_found
+                                .ok_or_else(|| token_info.custom(ErrorType::BreakOutsideLoop))?;
+                            if matches!(block.btype, Word::ForEveryPart) {
+                                self.instructions.push(Instruction::ForEveryPartPop(1));
                             }
 
-                            state.instructions.push(Instruction::Jmp(usize::MAX));
-                        }
-                        Word::Replace => {
-                            state.validate_argument(
-                                0,
-                                Capability::Replace.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
-                            state.parse_replace()?;
-                        }
-                        Word::Enclose => {
-                            state.validate_argument(
-                                0,
-                                Capability::Enclose.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
-                            state.parse_enclose()?;
-                        }
-                        Word::ExtractText => {
-                            state.validate_argument(
-                                0,
-                                Capability::ExtractText.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
-                            state.parse_extracttext()?;
+                            block.break_jmps.push(self.instructions.len());
                         }
 
-                        // RFC 6558
-                        Word::Convert => {
-                            state.validate_argument(
-                                0,
-                                Capability::Convert.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
-                            state.parse_convert()?;
-                        }
+                        self.instructions.push(Instruction::Jmp(usize::MAX));
+                    }
+                    Word::Replace => {
+                        self.validate_argument(
+                            0,
+                            Capability::Replace.into(),
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
+                        self.parse_replace()?;
+                    }
+                    Word::Enclose => {
+                        self.validate_argument(
+                            0,
+                            Capability::Enclose.into(),
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
+                        self.parse_enclose()?;
+                    }
+                    Word::ExtractText => {
+                        self.validate_argument(
+                            0,
+                            Capability::ExtractText.into(),
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
+                        self.parse_extracttext()?;
+                    }
 
-                        // RFC 5293
-                        Word::AddHeader => {
-                            state.validate_argument(
-                                0,
-                                Capability::EditHeader.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
-                            state.parse_addheader()?;
-                        }
-                        Word::DeleteHeader => {
-                            state.validate_argument(
-                                0,
-                                Capability::EditHeader.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
-                            state.parse_deleteheader()?;
-                        }
+                    // RFC 6558
+                    Word::Convert => {
+                        self.validate_argument(
+                            0,
+                            Capability::Convert.into(),
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
+                        self.parse_convert()?;
+                    }
 
-                        // RFC 5229
-                        Word::Set => {
-                            state.validate_argument(
-                                0,
-                                Capability::Variables.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
-                            state.parse_set()?;
-                        }
+                    // RFC 5293
+                    Word::AddHeader => {
+                        self.validate_argument(
+                            0,
+                            Capability::EditHeader.into(),
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
+                        self.parse_addheader()?;
+                    }
+                    Word::DeleteHeader => {
+                        self.validate_argument(
+                            0,
+                            Capability::EditHeader.into(),
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
+                        self.parse_deleteheader()?;
+                    }
 
-                        // RFC 5435
-                        Word::Notify => {
-                            state.validate_argument(
-                                0,
-                                Capability::Enotify.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
-                            state.parse_notify()?;
-                        }
+                    // RFC 5229
+                    Word::Set => {
+                        self.validate_argument(
+                            0,
+                            Capability::Variables.into(),
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
+                        self.parse_set()?;
+                    }
 
-                        // RFC 5429
-                        Word::Reject => {
-                            state.validate_argument(
-                                0,
-                                Capability::Reject.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
-                            state.parse_reject(false)?;
-                        }
-                        Word::Ereject => {
-                            state.validate_argument(
-                                0,
-                                Capability::Ereject.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
-                            state.parse_reject(true)?;
-                        }
+                    // RFC 5435
+                    Word::Notify => {
+                        self.validate_argument(
+                            0,
+                            Capability::Enotify.into(),
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
+                        self.parse_notify()?;
+                    }
 
-                        // RFC 5230
-                        Word::Vacation => {
-                            state.validate_argument(
-                                0,
-                                Capability::Vacation.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
-                            state.parse_vacation()?;
-                        }
+                    // RFC 5429
+                    Word::Reject => {
+                        self.validate_argument(
+                            0,
+                            Capability::Reject.into(),
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
+                        self.parse_reject(false)?;
+                    }
+                    Word::Ereject => {
+                        self.validate_argument(
+                            0,
+                            Capability::Ereject.into(),
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
+                        self.parse_reject(true)?;
+                    }
 
-                        // RFC 5463
-                        Word::Error => {
-                            state.validate_argument(
-                                0,
-                                Capability::Ihave.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
-                            state.parse_error()?;
-                        }
+                    // RFC 5230
+                    Word::Vacation => {
+                        self.validate_argument(
+                            0,
+                            Capability::Vacation.into(),
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
+                        self.parse_vacation()?;
+                    }
 
-                        // RFC 5232
-                        Word::SetFlag | Word::AddFlag | Word::RemoveFlag => {
-                            state.validate_argument(
-                                0,
-                                Capability::Imap4Flags.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
-                            state.parse_flag_action(instruction)?;
-                        }
+                    // RFC 5463
+                    Word::Error => {
+                        self.validate_argument(
+                            0,
+                            Capability::Ihave.into(),
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
+                        self.parse_error()?;
+                    }
 
-                        // RFC 6609
-                        Word::Include => {
-                            if state.includes_num < self.max_includes {
-                                state.validate_argument(
-                                    0,
-                                    Capability::Include.into(),
-                                    token_info.line_num,
-                                    token_info.line_pos,
-                                )?;
-                                state.parse_include()?;
-                                state.includes_num += 1;
-                            } else {
-                                return Err(token_info.custom(ErrorType::TooManyIncludes));
-                            }
-                        }
-                        Word::Return => {
-                            state.validate_argument(
+                    // RFC 5232
+                    Word::SetFlag | Word::AddFlag | Word::RemoveFlag => {
+                        self.validate_argument(
+                            0,
+                            Capability::Imap4Flags.into(),
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
+                        self.parse_flag_action(instruction)?;
+                    }
+
+                    // RFC 6609
+                    Word::Include => {
+                        if self.includes_num < self.compiler.max_includes {
+                            self.validate_argument(
                                 0,
                                 Capability::Include.into(),
                                 token_info.line_num,
                                 token_info.line_pos,
                             )?;
-                            let mut num_pops = 0;
-
-                            for block in [&state.block]
-                                .into_iter()
-                                .chain(state.block_stack.iter().rev())
-                            {
-                                if let Word::ForEveryPart = &block.btype {
-                                    num_pops += 1;
-                                }
-                            }
-
-                            if num_pops > 0 {
-                                state
-                                    .instructions
-                                    .push(Instruction::ForEveryPartPop(num_pops));
+                            self.parse_include()?;
+                            self.includes_num += 1;
+                        } else {
+                            return Err(token_info.custom(ErrorType::TooManyIncludes));
+                        }
+                    }
+                    Word::Return => {
+                        self.validate_argument(
+                            0,
+                            Capability::Include.into(),
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
+                        let mut num_pops = 0;
+
+                        for block in [&self.block]
+                            .into_iter()
+                            .chain(self.block_stack.iter().rev())
+                        {
+                            if let Word::ForEveryPart = &block.btype {
+                                num_pops += 1;
                             }
+                        }
 
-                            state.instructions.push(Instruction::Return);
+                        if num_pops > 0 {
+                            self.instructions
+                                .push(Instruction::ForEveryPartPop(num_pops));
                         }
-                        Word::Global => {
-                            state.validate_argument(
-                                0,
-                                Capability::Include.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
-                            state.validate_argument(
-                                0,
-                                Capability::Variables.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
-                            for global in state.parse_static_strings()? {
-                                if !state.is_var_local(&global) {
-                                    if global.len() < self.max_variable_name_size {
-                                        state.register_global_var(&global);
-                                    } else {
-                                        return Err(state
-                                            .tokens
-                                            .unwrap_next()?
-                                            .custom(ErrorType::VariableTooLong));
-                                    }
+
+                        self.instructions.push(Instruction::Return);
+                    }
+                    Word::Global => {
+                        self.validate_argument(
+                            0,
+                            Capability::Include.into(),
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
+                        self.validate_argument(
+                            0,
+                            Capability::Variables.into(),
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
+                        for global in self.parse_static_strings()? {
+                            if !self.is_var_local(&global) {
+                                if global.len() < self.compiler.max_variable_name_size {
+                                    self.register_global_var(&global);
                                 } else {
-                                    return Err(state
+                                    return Err(self
                                         .tokens
                                         .unwrap_next()?
-                                        .custom(ErrorType::VariableIsLocal(global)));
+                                        .custom(ErrorType::VariableTooLong));
                                 }
+                            } else {
+                                return Err(self
+                                    .tokens
+                                    .unwrap_next()?
+                                    .custom(ErrorType::VariableIsLocal(global)));
                             }
                         }
+                    }
 
-                        // Expressions extension
-                        Word::Let => {
-                            state.validate_argument(
-                                0,
-                                Capability::Expressions.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
-                            state.parse_let()?;
-                        }
-                        Word::Eval => {
-                            state.validate_argument(
-                                0,
-                                Capability::Expressions.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
-                            let expr = state.parse_expr()?;
-                            state.instructions.push(Instruction::Eval(expr));
-                        }
-
-                        // While extension
-                        Word::While => {
-                            state.validate_argument(
-                                0,
-                                Capability::While.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
-
-                            is_new_block = Block::new(Word::While).into();
+                    // Expressions extension
+                    Word::Let => {
+                        self.validate_argument(
+                            0,
+                            Capability::Expressions.into(),
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
+                        self.parse_let()?;
+                    }
+                    Word::Eval => {
+                        self.validate_argument(
+                            0,
+                            Capability::Expressions.into(),
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
+                        let expr = self.parse_expr()?;
+                        self.instructions.push(Instruction::Eval(expr));
+                    }
 
-                            let expr = state.parse_expr()?;
-                            state.instructions.push(Instruction::While(While {
-                                expr,
-                                jz_pos: usize::MAX,
-                            }));
-                        }
-                        Word::Continue => {
-                            state.validate_argument(
-                                0,
-                                Capability::While.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
-                            let mut found_while = 0;
-                            for block in [&state.block]
-                                .into_iter()
-                                .chain(state.block_stack.iter().rev())
-                            {
-                                if let Word::While = &block.btype {
-                                    found_while += 1;
-                                } else if found_while == 1 {
-                                    state
-                                        .instructions
-                                        .push(Instruction::Jmp(block.last_block_start));
-                                    found_while += 1;
-                                    break;
-                                }
-                            }
-                            if found_while != 2 {
-                                return Err(token_info.custom(ErrorType::ContinueOutsideLoop));
+                    // While extension
+                    Word::While => {
+                        self.validate_argument(
+                            0,
+                            Capability::While.into(),
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
+
+                        is_new_block = Block::new(Word::While).into();
+
+                        let expr = self.parse_expr()?;
+                        self.instructions.push(Instruction::While(While {
+                            expr,
+                            jz_pos: usize::MAX,
+                        }));
+                    }
+                    Word::Continue => {
+                        self.validate_argument(
+                            0,
+                            Capability::While.into(),
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
+                        let mut found_while = 0;
+                        for block in [&self.block]
+                            .into_iter()
+                            .chain(self.block_stack.iter().rev())
+                        {
+                            if let Word::While = &block.btype {
+                                found_while += 1;
+                            } else if found_while == 1 {
+                                self.instructions
+                                    .push(Instruction::Jmp(block.last_block_start));
+                                found_while += 1;
+                                break;
                             }
                         }
-
-                        _ => {
-                            if state.has_capability(&Capability::Ihave) {
-                                state.ignore_instruction()?;
-                                state.instructions.push(Instruction::Invalid(Invalid {
-                                    name: instruction.to_string(),
-                                    line_num: token_info.line_num,
-                                    line_pos: token_info.line_pos,
-                                }));
-                                continue;
-                            } else {
-                                return Err(CompileError {
-                                    line_num: state.block.line_num,
-                                    line_pos: state.block.line_pos,
-                                    error_type: ErrorType::UnexpectedToken {
-                                        expected: "command".into(),
-                                        found: instruction.to_string(),
-                                    },
-                                });
-                            }
+                        if found_while != 2 {
+                            return Err(token_info.custom(ErrorType::ContinueOutsideLoop));
                         }
                     }
 
-                    if let Some(mut new_block) = is_new_block {
-                        new_block.line_num = state.tokens.line_num;
-                        new_block.line_pos = state.tokens.pos - state.tokens.line_start;
-
-                        state.tokens.expect_token(Token::CurlyOpen)?;
-                        if state.block_stack.len() < self.max_nested_blocks {
-                            state.block.last_block_start = state.instructions.len() - 1;
-                            state.block_stack.push(state.block);
-                            state.block = new_block;
+                    _ => {
+                        if self.has_capability(&Capability::Ihave) {
+                            self.ignore_instruction()?;
+                            self.instructions.push(Instruction::Invalid(Invalid {
+                                name: instruction.to_string(),
+                                line_num: token_info.line_num,
+                                line_pos: token_info.line_pos,
+                            }));
+                            return Ok(());
                         } else {
                             return Err(CompileError {
-                                line_num: state.block.line_num,
-                                line_pos: state.block.line_pos,
-                                error_type: ErrorType::TooManyNestedBlocks,
+                                line_num: self.block.line_num,
+                                line_pos: self.block.line_pos,
+                                error_type: ErrorType::UnexpectedToken {
+                                    expected: "command".into(),
+                                    found: instruction.to_string(),
+                                },
                             });
                         }
+                    }
+                }
+
+                if let Some(mut new_block) = is_new_block {
+                    new_block.line_num = self.tokens.line_num;
+                    new_block.line_pos = self.tokens.pos - self.tokens.line_start;
+
+                    self.tokens.expect_token(Token::CurlyOpen)?;
+                    if self.block_stack.len() < self.compiler.max_nested_blocks {
+                        self.block.last_block_start = self.instructions.len() - 1;
+                        let prev_block = std::mem::replace(&mut self.block, new_block);
+                        self.block_stack.push(prev_block);
                     } else {
-                        state.expect_instruction_end()?;
+                        return Err(CompileError {
+                            line_num: self.block.line_num,
+                            line_pos: self.block.line_pos,
+                            error_type: ErrorType::TooManyNestedBlocks,
+                        });
                     }
+                } else {
+                    self.expect_instruction_end()?;
                 }
-                Token::CurlyClose if !state.block_stack.is_empty() => {
-                    state.block_end();
-                    let mut prev_block = state.block_stack.pop().unwrap();
-                    match &state.block.btype {
-                        Word::ForEveryPart => {
-                            state
-                                .instructions
-                                .push(Instruction::Jmp(prev_block.last_block_start));
-                            let cur_pos = state.instructions.len();
-                            if let Instruction::ForEveryPart(fep) =
-                                &mut state.instructions[prev_block.last_block_start]
-                            {
-                                fep.jz_pos = cur_pos;
-                            } else {
-                                debug_assert!(false, "This should not have happened.");
-                            }
-                            for pos in state.block.break_jmps {
-                                if let Instruction::Jmp(jmp_pos) = &mut state.instructions[pos] {
-                                    *jmp_pos = cur_pos;
-                                } else {
-                                    debug_assert!(false, "This should not have happened.");
-                                }
-                            }
-                            state.last_block_type = Word::Not;
-                        }
-                        Word::If | Word::ElsIf => {
-                            let next_is_block = matches!(
-                                state.tokens.peek().map(|r| r.map(|t| &t.token)),
-                                Some(Ok(Token::Identifier(Word::ElsIf | Word::Else)))
-                            );
-                            if next_is_block {
-                                prev_block.if_jmps.push(state.instructions.len());
-                                state.instructions.push(Instruction::Jmp(usize::MAX));
-                            }
-                            let cur_pos = state.instructions.len();
-                            if let Instruction::Jz(jmp_pos) =
-                                &mut state.instructions[prev_block.last_block_start]
-                            {
-                                *jmp_pos = cur_pos;
-                            } else {
-                                debug_assert!(false, "This should not have happened.");
-                            }
-                            if !next_is_block {
-                                for pos in prev_block.if_jmps.drain(..) {
-                                    if let Instruction::Jmp(jmp_pos) = &mut state.instructions[pos]
-                                    {
-                                        *jmp_pos = cur_pos;
-                                    } else {
-                                        debug_assert!(false, "This should not have happened.");
+            }
+            Token::CurlyClose if !self.block_stack.is_empty() => {
+                self.close_block();
+            }
+
+            #[cfg(test)]
+            Token::Unknown(instruction) if instruction.contains("test") => {
+                let has_arguments = instruction != "test";
+                let mut arguments = vec![Value::Text(instruction.into())];
+
+                if !has_arguments {
+                    arguments.push(self.parse_string()?);
+                    self.instructions.push(Instruction::TestCmd(arguments));
+                    let mut new_block = Block::new(Word::Else);
+                    new_block.line_num = self.tokens.line_num;
+                    new_block.line_pos = self.tokens.pos - self.tokens.line_start;
+                    self.tokens.expect_token(Token::CurlyOpen)?;
+                    self.block.last_block_start = self.instructions.len() - 1;
+                    let prev_block = std::mem::replace(&mut self.block, new_block);
+                    self.block_stack.push(prev_block);
+                } else {
+                    loop {
+                        arguments.push(match self.tokens.unwrap_next()?.token {
+                            Token::StringConstant(s) => Value::from(s),
+                            Token::StringVariable(s) => {
+                                self.tokenize_string(&s, true).map_err(|error_type| {
+                                    CompileError {
+                                        line_num: 0,
+                                        line_pos: 0,
+                                        error_type,
                                     }
-                                }
-                                state.last_block_type = Word::Not;
-                            } else {
-                                state.last_block_type = state.block.btype;
+                                })?
                             }
-                        }
-                        Word::Else => {
-                            let cur_pos = state.instructions.len();
-                            for pos in prev_block.if_jmps.drain(..) {
-                                if let Instruction::Jmp(jmp_pos) = &mut state.instructions[pos] {
-                                    *jmp_pos = cur_pos;
-                                } else {
-                                    debug_assert!(false, "This should not have happened.");
-                                }
+                            Token::Number(n) => {
+                                Value::Number(crate::compiler::Number::Integer(n as i64))
                             }
-                            state.last_block_type = Word::Else;
-                        }
-                        Word::While => {
-                            state
-                                .instructions
-                                .push(Instruction::Jmp(prev_block.last_block_start));
-                            let cur_pos = state.instructions.len();
-                            if let Instruction::While(fep) =
-                                &mut state.instructions[prev_block.last_block_start]
-                            {
-                                fep.jz_pos = cur_pos;
-                            } else {
-                                debug_assert!(false, "This should not have happened.");
-                            }
-                            for pos in state.block.break_jmps {
-                                if let Instruction::Jmp(jmp_pos) = &mut state.instructions[pos] {
-                                    *jmp_pos = cur_pos;
-                                } else {
-                                    debug_assert!(false, "This should not have happened.");
-                                }
-                            }
-                            state.last_block_type = Word::Not;
-                        }
-                        _ => {
-                            debug_assert!(false, "This should not have happened.");
-                        }
+                            Token::Identifier(s) => Value::Text(s.to_string().into()),
+                            Token::Tag(s) => Value::Text(format!(":{s}").into()),
+                            Token::Unknown(s) => Value::Text(s.into()),
+                            Token::Semicolon => break,
+                            other => panic!("Invalid test param {other:?}"),
+                        });
                     }
+                    self.instructions.push(Instruction::TestCmd(arguments));
+                }
+            }
 
-                    state.block = prev_block;
+            Token::Unknown(instruction) => {
+                if self.has_capability(&Capability::Ihave) {
+                    self.ignore_instruction()?;
+                    self.instructions.push(Instruction::Invalid(Invalid {
+                        name: instruction,
+                        line_num: token_info.line_num,
+                        line_pos: token_info.line_pos,
+                    }));
+                } else {
+                    return Err(CompileError {
+                        line_num: self.block.line_num,
+                        line_pos: self.block.line_pos,
+                        error_type: ErrorType::UnexpectedToken {
+                            expected: "command".into(),
+                            found: instruction,
+                        },
+                    });
                 }
+            }
+            _ => {
+                return Err(token_info.expected("instruction"));
+            }
+        }
 
-                #[cfg(test)]
-                Token::Unknown(instruction) if instruction.contains("test") => {
-                    let has_arguments = instruction != "test";
-                    let mut arguments = vec![Value::Text(instruction.into())];
-
-                    if !has_arguments {
-                        arguments.push(state.parse_string()?);
-                        state.instructions.push(Instruction::TestCmd(arguments));
-                        let mut new_block = Block::new(Word::Else);
-                        new_block.line_num = state.tokens.line_num;
-                        new_block.line_pos = state.tokens.pos - state.tokens.line_start;
-                        state.tokens.expect_token(Token::CurlyOpen)?;
-                        state.block.last_block_start = state.instructions.len() - 1;
-                        state.block_stack.push(state.block);
-                        state.block = new_block;
+        Ok(())
+    }
+
+    pub(crate) fn close_block(&mut self) {
+        self.block_end();
+        let mut prev_block = self.block_stack.pop().unwrap();
+        match &self.block.btype {
+            Word::ForEveryPart => {
+                self.instructions
+                    .push(Instruction::Jmp(prev_block.last_block_start));
+                let cur_pos = self.instructions.len();
+                if let Instruction::ForEveryPart(fep) =
+                    &mut self.instructions[prev_block.last_block_start]
+                {
+                    fep.jz_pos = cur_pos;
+                } else {
+                    debug_assert!(false, "This should not have happened.");
+                }
+                for pos in self.block.break_jmps.drain(..) {
+                    if let Instruction::Jmp(jmp_pos) = &mut self.instructions[pos] {
+                        *jmp_pos = cur_pos;
                     } else {
-                        loop {
-                            arguments.push(match state.tokens.unwrap_next()?.token {
-                                Token::StringConstant(s) => Value::from(s),
-                                Token::StringVariable(s) => state
-                                    .tokenize_string(&s, true)
-                                    .map_err(|error_type| CompileError {
-                                        line_num: 0,
-                                        line_pos: 0,
-                                        error_type,
-                                    })?,
-                                Token::Number(n) => {
-                                    Value::Number(crate::compiler::Number::Integer(n as i64))
-                                }
-                                Token::Identifier(s) => Value::Text(s.to_string().into()),
-                                Token::Tag(s) => Value::Text(format!(":{s}").into()),
-                                Token::Unknown(s) => Value::Text(s.into()),
-                                Token::Semicolon => break,
-                                other => panic!("Invalid test param {other:?}"),
-                            });
+                        debug_assert!(false, "This should not have happened.");
+                    }
+                }
+                self.last_block_type = Word::Not;
+            }
+            Word::If | Word::ElsIf => {
+                let next_is_block = matches!(
+                    self.tokens.peek().map(|r| r.map(|t| &t.token)),
+                    Some(Ok(Token::Identifier(Word::ElsIf | Word::Else)))
+                );
+                if next_is_block {
+                    prev_block.if_jmps.push(self.instructions.len());
+                    self.instructions.push(Instruction::Jmp(usize::MAX));
+                }
+                let cur_pos = self.instructions.len();
+                if let Instruction::Jz(jmp_pos) =
+                    &mut self.instructions[prev_block.last_block_start]
+                {
+                    *jmp_pos = cur_pos;
+                } else {
+                    debug_assert!(false, "This should not have happened.");
+                }
+                if !next_is_block {
+                    for pos in prev_block.if_jmps.drain(..) {
+                        if let Instruction::Jmp(jmp_pos) = &mut self.instructions[pos] {
+                            *jmp_pos = cur_pos;
+                        } else {
+                            debug_assert!(false, "This should not have happened.");
                         }
-                        state.instructions.push(Instruction::TestCmd(arguments));
                     }
+                    self.last_block_type = Word::Not;
+                } else {
+                    self.last_block_type = self.block.btype;
                 }
-
-                Token::Unknown(instruction) => {
-                    if state.has_capability(&Capability::Ihave) {
-                        state.ignore_instruction()?;
-                        state.instructions.push(Instruction::Invalid(Invalid {
-                            name: instruction,
-                            line_num: token_info.line_num,
-                            line_pos: token_info.line_pos,
-                        }));
+            }
+            Word::Else => {
+                let cur_pos = self.instructions.len();
+                for pos in prev_block.if_jmps.drain(..) {
+                    if let Instruction::Jmp(jmp_pos) = &mut self.instructions[pos] {
+                        *jmp_pos = cur_pos;
                     } else {
-                        return Err(CompileError {
-                            line_num: state.block.line_num,
-                            line_pos: state.block.line_pos,
-                            error_type: ErrorType::UnexpectedToken {
-                                expected: "command".into(),
-                                found: instruction,
-                            },
-                        });
+                        debug_assert!(false, "This should not have happened.");
                     }
                 }
-                _ => {
-                    return Err(token_info.expected("instruction"));
+                self.last_block_type = Word::Else;
+            }
+            Word::While => {
+                self.instructions
+                    .push(Instruction::Jmp(prev_block.last_block_start));
+                let cur_pos = self.instructions.len();
+                if let Instruction::While(fep) = &mut self.instructions[prev_block.last_block_start]
+                {
+                    fep.jz_pos = cur_pos;
+                } else {
+                    debug_assert!(false, "This should not have happened.");
+                }
+                for pos in self.block.break_jmps.drain(..) {
+                    if let Instruction::Jmp(jmp_pos) = &mut self.instructions[pos] {
+                        *jmp_pos = cur_pos;
+                    } else {
+                        debug_assert!(false, "This should not have happened.");
+                    }
                 }
+                self.last_block_type = Word::Not;
+            }
+            _ => {
+                debug_assert!(false, "This should not have happened.");
             }
         }
 
-        if !state.block_stack.is_empty() {
-            return Err(CompileError {
-                line_num: state.block.line_num,
-                line_pos: state.block.line_pos,
-                error_type: ErrorType::UnterminatedBlock,
-            });
+        self.block = prev_block;
+    }
+
+    pub(crate) fn add_positions(&mut self, line_num: usize, line_pos: usize) {
+        if self.compiler.source_positions {
+            self.positions.resize(
+                self.instructions.len(),
+                SourcePosition { line_num, line_pos },
+            );
+        }
+    }
+
+    pub(crate) fn recover(&mut self, start_pos: usize, errors: &mut Vec<CompileError>) {
+        // Discard any instructions emitted by the failed command
+        self.instructions.truncate(start_pos);
+        self.positions.truncate(start_pos);
+        for block in [&mut self.block]
+            .into_iter()
+            .chain(self.block_stack.iter_mut())
+        {
+            block.match_test_pos.retain(|pos| *pos < start_pos);
+            block.break_jmps.retain(|pos| *pos < start_pos);
         }
 
+        // An 'elsif' or 'else' failed to compile, terminate the 'if' chain
+        for pos in self.block.if_jmps.drain(..) {
+            if let Some(Instruction::Jmp(jmp_pos)) = self.instructions.get_mut(pos) {
+                *jmp_pos = start_pos;
+            }
+        }
+        self.last_block_type = Word::Not;
+
+        // Skip to the end of the failed command
+        let mut curly_count = match self.tokens.last_delimiter.take() {
+            Some(token_info) => match token_info.token {
+                Token::CurlyOpen => 1,
+                Token::CurlyClose if !self.block_stack.is_empty() => {
+                    self.tokens.next_token.push(token_info);
+                    return;
+                }
+                _ => return,
+            },
+            None => 0,
+        };
+        while let Some(token_info) = self.tokens.next() {
+            let token_info = match token_info {
+                Ok(token_info) => token_info,
+                Err(err) => {
+                    errors.push(err);
+                    continue;
+                }
+            };
+            match token_info.token {
+                Token::Semicolon if curly_count == 0 => break,
+                Token::CurlyOpen => {
+                    curly_count += 1;
+                }
+                Token::CurlyClose => {
+                    if curly_count == 0 {
+                        if !self.block_stack.is_empty() {
+                            self.tokens.next_token.push(token_info);
+                        }
+                        break;
+                    }
+                    curly_count -= 1;
+                    if curly_count == 0 {
+                        break;
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn take_warnings(&mut self) -> Vec<CompileWarning> {
+        let mut warnings = std::mem::take(&mut self.warnings);
+
+        for (name, (line_num, line_pos)) in &self.vars_written {
+            if !self.vars_read.contains(name) {
+                warnings.push(CompileWarning {
+                    line_num: *line_num,
+                    line_pos: *line_pos,
+                    warning_type: WarningType::UnusedVariable(name.clone()),
+                });
+            }
+        }
+
+        for (capability, line_num, line_pos) in &self.capabilities_required {
+            if !self.capabilities_used.contains(capability)
+                && !matches!(
+                    capability,
+                    Capability::Comparator(_) | Capability::Other(_) | Capability::ImapSieve
+                )
+            {
+                warnings.push(CompileWarning {
+                    line_num: *line_num,
+                    line_pos: *line_pos,
+                    warning_type: WarningType::UnusedCapability(capability.clone()),
+                });
+            }
+        }
+
+        warnings.sort_by_key(|w| (w.line_num, w.line_pos));
+        warnings
+    }
+
+    fn into_sieve(mut self) -> Sieve {
         // Map local variables
-        let mut num_vars = std::cmp::max(state.vars_num_max, state.vars_num);
-        if state.vars_local > 0 {
-            state.map_local_vars(num_vars);
-            num_vars += state.vars_local;
+        let mut num_vars = std::cmp::max(self.vars_num_max, self.vars_num);
+        if self.vars_local > 0 {
+            self.map_local_vars(num_vars);
+            num_vars += self.vars_local;
         }
 
-        Ok(Sieve {
-            instructions: state.instructions,
+        let mut sieve = Sieve {
+            instructions: self.instructions,
+            positions: self.positions,
             num_vars: num_vars as u32,
-            num_match_vars: state.vars_match_max as u32,
-        })
+            num_match_vars: self.vars_match_max as u32,
+            posix_regex: self.compiler.posix_regex,
+            source_hash: self.source_hash,
+            capabilities: self.compiler.capability_fingerprint(),
+        };
+        if self.compiler.optimize {
+            sieve.optimize();
+        }
+        sieve
     }
 }
 
@@ -868,6 +1090,52 @@ impl CompilerState<'_> {
         }
     }
 
+    fn local_var_name(&self, var_id: usize) -> Option<String> {
+        [&self.block]
+            .into_iter()
+            .chain(self.block_stack.iter().rev())
+            .find_map(|b| {
+                b.vars_local
+                    .iter()
+                    .find_map(|(name, id)| (*id == var_id).then(|| name.clone()))
+            })
+    }
+
+    pub(crate) fn register_var_write(
+        &mut self,
+        var: &VariableType,
+        line_num: usize,
+        line_pos: usize,
+    ) {
+        match var {
+            VariableType::Local(var_id) => {
+                if let Some(name) = self.local_var_name(*var_id) {
+                    self.vars_written
+                        .entry(name)
+                        .or_insert((line_num, line_pos));
+                }
+            }
+            VariableType::Global(_) => {
+                self.register_capability_use(Capability::Include);
+            }
+            _ => (),
+        }
+    }
+
+    pub(crate) fn register_var_read(&mut self, var: &VariableType) {
+        match var {
+            VariableType::Local(var_id) => {
+                if let Some(name) = self.local_var_name(*var_id) {
+                    self.vars_read.insert(name);
+                }
+            }
+            VariableType::Global(_) => {
+                self.register_capability_use(Capability::Include);
+            }
+            _ => (),
+        }
+    }
+
     pub(crate) fn register_global_var(&mut self, name: &str) {
         self.vars_global.insert(name.to_ascii_lowercase());
     }
@@ -1043,6 +1311,10 @@ impl CompilerState<'_> {
                 Instruction::Include(v) => {
                     v.value.map_local_vars(last_id);
                 }
+                #[cfg(test)]
+                Instruction::TestCmd(v) => {
+                    v.map_local_vars(last_id);
+                }
                 _ => {}
             }
         }
@@ -1211,6 +1483,7 @@ impl Block {
             vars_local: AHashMap::new(),
             capabilities: AHashSet::new(),
             require_pos: usize::MAX,
+            is_stopped: false,
         }
     }
 
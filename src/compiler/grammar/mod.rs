@@ -6,11 +6,14 @@
 
 use std::fmt::Display;
 
+use mail_parser::HeaderName;
+
 use self::{expr::Expression, instruction::CompilerState};
+use crate::runtime::tests::glob::GlobPattern;
 
 use super::{
     lexer::{tokenizer::TokenInfo, word::Word, Token},
-    CompileError, ErrorType, Regex, Value,
+    CompileError, CompileWarning, ErrorType, Glob, Regex, Value, WarningType,
 };
 
 pub mod actions;
@@ -149,6 +152,7 @@ pub enum Comparator {
     Octet,
     AsciiCaseMap,
     AsciiNumeric,
+    UnicodeCaseMap,
     Other(String),
 }
 
@@ -182,6 +186,26 @@ pub struct Invalid {
     pub(crate) line_pos: usize,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    any(test, feature = "serde"),
+    derive(serde::Serialize, serde::Deserialize)
+)]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Serialize, rkyv::Deserialize, rkyv::Archive)
+)]
+pub struct SourcePosition {
+    pub(crate) line_num: usize,
+    pub(crate) line_pos: usize,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct StringRef {
+    pub(crate) position: SourcePosition,
+    pub(crate) value: Value,
+}
+
 #[derive(Debug, Eq, PartialEq, Clone)]
 #[cfg_attr(
     any(test, feature = "serde"),
@@ -244,10 +268,8 @@ impl CompilerState<'_> {
                         d_count -= 1;
                     }
                 }
-                Token::Comma => {
-                    if d_count == 0 {
-                        break;
-                    }
+                Token::Comma if d_count == 0 => {
+                    break;
                 }
                 Token::CurlyOpen => {
                     break;
@@ -324,15 +346,17 @@ impl CompilerState<'_> {
     pub fn parse_string(&mut self) -> Result<Value, CompileError> {
         let next_token = self.tokens.unwrap_next()?;
         match next_token.token {
-            Token::StringConstant(s) => Ok(Value::from(s)),
-            Token::StringVariable(s) => {
-                self.tokenize_string(&s, true)
-                    .map_err(|error_type| CompileError {
-                        line_num: next_token.line_num,
-                        line_pos: next_token.line_pos,
-                        error_type,
-                    })
+            Token::StringConstant(s) => {
+                Ok(self.track_string(next_token.line_num, next_token.line_pos, Value::from(s)))
             }
+            Token::StringVariable(s) => self
+                .tokenize_string(&s, true)
+                .map(|value| self.track_string(next_token.line_num, next_token.line_pos, value))
+                .map_err(|error_type| CompileError {
+                    line_num: next_token.line_num,
+                    line_pos: next_token.line_pos,
+                    error_type,
+                }),
             Token::BracketOpen => {
                 let mut items = self.parse_string_list(false)?;
                 match items.pop() {
@@ -348,16 +372,19 @@ impl CompilerState<'_> {
         let token_info = self.tokens.unwrap_next()?;
         match token_info.token {
             Token::BracketOpen => self.parse_string_list(allow_empty),
-            Token::StringConstant(s) => Ok(vec![Value::from(s)]),
-            Token::StringVariable(s) => {
-                self.tokenize_string(&s, true)
-                    .map(|s| vec![s])
-                    .map_err(|error_type| CompileError {
-                        line_num: token_info.line_num,
-                        line_pos: token_info.line_pos,
-                        error_type,
-                    })
-            }
+            Token::StringConstant(s) => Ok(vec![self.track_string(
+                token_info.line_num,
+                token_info.line_pos,
+                Value::from(s),
+            )]),
+            Token::StringVariable(s) => self
+                .tokenize_string(&s, true)
+                .map(|s| vec![self.track_string(token_info.line_num, token_info.line_pos, s)])
+                .map_err(|error_type| CompileError {
+                    line_num: token_info.line_num,
+                    line_pos: token_info.line_pos,
+                    error_type,
+                }),
             _ => Err(token_info.expected("'[' or string")),
         }
     }
@@ -367,15 +394,17 @@ impl CompilerState<'_> {
         token_info: TokenInfo,
     ) -> Result<Value, CompileError> {
         match token_info.token {
-            Token::StringConstant(s) => Ok(Value::from(s)),
-            Token::StringVariable(s) => {
-                self.tokenize_string(&s, true)
-                    .map_err(|error_type| CompileError {
-                        line_num: token_info.line_num,
-                        line_pos: token_info.line_pos,
-                        error_type,
-                    })
+            Token::StringConstant(s) => {
+                Ok(self.track_string(token_info.line_num, token_info.line_pos, Value::from(s)))
             }
+            Token::StringVariable(s) => self
+                .tokenize_string(&s, true)
+                .map(|value| self.track_string(token_info.line_num, token_info.line_pos, value))
+                .map_err(|error_type| CompileError {
+                    line_num: token_info.line_num,
+                    line_pos: token_info.line_pos,
+                    error_type,
+                }),
             _ => Err(token_info.expected("string")),
         }
     }
@@ -385,16 +414,19 @@ impl CompilerState<'_> {
         token_info: TokenInfo,
     ) -> Result<Vec<Value>, CompileError> {
         match token_info.token {
-            Token::StringConstant(s) => Ok(vec![Value::from(s)]),
-            Token::StringVariable(s) => {
-                self.tokenize_string(&s, true)
-                    .map(|s| vec![s])
-                    .map_err(|error_type| CompileError {
-                        line_num: token_info.line_num,
-                        line_pos: token_info.line_pos,
-                        error_type,
-                    })
-            }
+            Token::StringConstant(s) => Ok(vec![self.track_string(
+                token_info.line_num,
+                token_info.line_pos,
+                Value::from(s),
+            )]),
+            Token::StringVariable(s) => self
+                .tokenize_string(&s, true)
+                .map(|s| vec![self.track_string(token_info.line_num, token_info.line_pos, s)])
+                .map_err(|error_type| CompileError {
+                    line_num: token_info.line_num,
+                    line_pos: token_info.line_pos,
+                    error_type,
+                }),
             Token::BracketOpen => self.parse_string_list(false),
             _ => Err(token_info.expected("string")),
         }
@@ -409,16 +441,25 @@ impl CompilerState<'_> {
             let token_info = self.tokens.unwrap_next()?;
             match token_info.token {
                 Token::StringConstant(s) => {
-                    strings.push(Value::from(s));
+                    strings.push(self.track_string(
+                        token_info.line_num,
+                        token_info.line_pos,
+                        Value::from(s),
+                    ));
                 }
                 Token::StringVariable(s) => {
-                    strings.push(self.tokenize_string(&s, true).map_err(|error_type| {
-                        CompileError {
-                            line_num: token_info.line_num,
-                            line_pos: token_info.line_pos,
-                            error_type,
-                        }
-                    })?);
+                    let value =
+                        self.tokenize_string(&s, true)
+                            .map_err(|error_type| CompileError {
+                                line_num: token_info.line_num,
+                                line_pos: token_info.line_pos,
+                                error_type,
+                            })?;
+                    strings.push(self.track_string(
+                        token_info.line_num,
+                        token_info.line_pos,
+                        value,
+                    ));
                 }
                 Token::Comma => (),
                 Token::BracketClose if !strings.is_empty() || allow_empty => break,
@@ -428,6 +469,29 @@ impl CompilerState<'_> {
         Ok(strings)
     }
 
+    #[inline(always)]
+    fn track_string(&mut self, line_num: usize, line_pos: usize, value: Value) -> Value {
+        if let Some(string_refs) = &mut self.string_refs {
+            string_refs.push(StringRef {
+                position: SourcePosition { line_num, line_pos },
+                value: value.clone(),
+            });
+        }
+        value
+    }
+
+    pub(crate) fn begin_mailbox_refs(&mut self) {
+        if let Some(string_refs) = &mut self.string_refs {
+            string_refs.clear();
+        }
+    }
+
+    pub(crate) fn end_mailbox_refs(&mut self) {
+        if let Some(string_refs) = &mut self.string_refs {
+            self.mailbox_refs.append(string_refs);
+        }
+    }
+
     #[inline(always)]
     pub(crate) fn has_capability(&self, capability: &Capability) -> bool {
         [&self.block]
@@ -437,6 +501,55 @@ impl CompilerState<'_> {
             || (capability != &Capability::Ihave && self.compiler.no_capability_check)
     }
 
+    pub(crate) fn register_capability_use(&mut self, capability: Capability) {
+        if capability == Capability::SpamTest {
+            self.capabilities_used.insert(Capability::SpamTestPlus);
+        }
+        self.capabilities_used.insert(capability);
+    }
+
+    /// Invalid header names are errors, unless diagnostics are being
+    /// collected, in which case they are reported as warnings.
+    pub(crate) fn validate_header_names(
+        &mut self,
+        headers: &[Value],
+        line_num: usize,
+        line_pos: usize,
+    ) -> Result<(), CompileError> {
+        if !self.is_lenient
+            && headers.iter().any(|header| {
+                matches!(header, Value::Text(header_name) if HeaderName::parse(header_name.as_ref()).is_none())
+            })
+        {
+            return Err(self
+                .tokens
+                .unwrap_next()?
+                .custom(ErrorType::InvalidHeaderName));
+        }
+
+        self.warn_header_names(headers, line_num, line_pos);
+        Ok(())
+    }
+
+    pub(crate) fn warn_header_names(
+        &mut self,
+        headers: &[Value],
+        line_num: usize,
+        line_pos: usize,
+    ) {
+        for header in headers {
+            if let Value::Text(header_name) = header {
+                if HeaderName::parse(header_name.as_ref()).is_none() {
+                    self.warnings.push(CompileWarning {
+                        line_num,
+                        line_pos,
+                        warning_type: WarningType::InvalidHeaderName(header_name.to_string()),
+                    });
+                }
+            }
+        }
+    }
+
     #[inline(always)]
     pub(crate) fn reset_param_check(&mut self) {
         self.param_check.fill(false);
@@ -474,6 +587,7 @@ impl CompilerState<'_> {
                     error_type: ErrorType::UndeclaredCapability(capability),
                 });
             }
+            self.register_capability_use(capability);
         }
 
         Ok(())
@@ -482,12 +596,28 @@ impl CompilerState<'_> {
     pub(crate) fn validate_match(
         &mut self,
         match_type: &MatchType,
+        comparator: &Comparator,
         key_list: &mut [Value],
     ) -> Result<(), CompileError> {
-        if matches!(match_type, MatchType::Regex(_)) {
+        if let (MatchType::Matches(_), Some(case_fold)) = (match_type, comparator.case_fold()) {
             for key in key_list {
                 if let Value::Text(expr) = key {
-                    match fancy_regex::Regex::new(expr) {
+                    *key = Value::Glob(Glob {
+                        pattern: GlobPattern::compile(expr, case_fold),
+                        expr: expr.to_string(),
+                    });
+                }
+            }
+        } else if matches!(match_type, MatchType::Regex(_)) {
+            for key in key_list {
+                if let Value::Text(expr) = key {
+                    match Regex::build(
+                        expr,
+                        self.compiler.max_regex_size,
+                        self.compiler.regex_backtrack_limit,
+                        self.compiler.linear_regex,
+                        self.compiler.posix_regex,
+                    ) {
                         Ok(regex) => {
                             *key = Value::Regex(Regex::new(expr.to_string(), regex));
                         }
@@ -526,6 +656,7 @@ impl Capability {
             Capability::Comparator(Comparator::Elbonia),
             Capability::Comparator(Comparator::AsciiCaseMap),
             Capability::Comparator(Comparator::AsciiNumeric),
+            Capability::Comparator(Comparator::UnicodeCaseMap),
             Capability::Comparator(Comparator::Octet),
             Capability::Body,
             Capability::Convert,
@@ -587,6 +718,7 @@ fn lookup_comparator(input: &str) -> Option<Comparator> {
         "i;octet" => Comparator::Octet,
         "i;ascii-casemap" => Comparator::AsciiCaseMap,
         "i;ascii-numeric" => Comparator::AsciiNumeric,
+        "i;unicode-casemap" => Comparator::UnicodeCaseMap,
     )
 }
 
@@ -604,6 +736,16 @@ impl Invalid {
     }
 }
 
+impl SourcePosition {
+    pub fn line_num(&self) -> usize {
+        self.line_num
+    }
+
+    pub fn line_pos(&self) -> usize {
+        self.line_pos
+    }
+}
+
 impl From<&str> for Capability {
     fn from(value: &str) -> Self {
         Capability::parse(value)
@@ -632,7 +774,12 @@ impl Display for Capability {
             Capability::Comparator(Comparator::AsciiNumeric) => {
                 f.write_str("comparator-i;ascii-numeric")
             }
-            Capability::Comparator(Comparator::Other(comparator)) => f.write_str(comparator),
+            Capability::Comparator(Comparator::UnicodeCaseMap) => {
+                f.write_str("comparator-i;unicode-casemap")
+            }
+            Capability::Comparator(Comparator::Other(comparator)) => {
+                write!(f, "comparator-{comparator}")
+            }
             Capability::Body => f.write_str("body"),
             Capability::Convert => f.write_str("convert"),
             Capability::Copy => f.write_str("copy"),
@@ -690,6 +837,7 @@ fn lookup_capabilities(input: &str) -> Option<Capability> {
         "comparator-i;octet" => Capability::Comparator(Comparator::Octet),
         "comparator-i;ascii-casemap" => Capability::Comparator(Comparator::AsciiCaseMap),
         "comparator-i;ascii-numeric" => Capability::Comparator(Comparator::AsciiNumeric),
+        "comparator-i;unicode-casemap" => Capability::Comparator(Comparator::UnicodeCaseMap),
         "body" => Capability::Body,
         "convert" => Capability::Convert,
         "copy" => Capability::Copy,
@@ -571,13 +571,22 @@ impl CompilerState<'_> {
             _ => return Err(next_token.expected("string")),
         };
 
-        match ExpressionParser::from_tokenizer(Tokenizer::from_iter(
+        let result = ExpressionParser::from_tokenizer(Tokenizer::from_iter(
             expr.iter().enumerate().peekable(),
             |var_name, maybe_namespace| self.parse_expr_fnc_or_var(var_name, maybe_namespace),
         ))
         .parse()
-        {
-            Ok(parser) => Ok(parser.output),
+        .map(|parser| parser.output);
+
+        match result {
+            Ok(output) => {
+                for expr in &output {
+                    if let Expression::Variable(var) = expr {
+                        self.register_var_read(var);
+                    }
+                }
+                Ok(output)
+            }
             Err(err) => {
                 let err = ErrorType::InvalidExpression(format!(
                     "{}: {}",
@@ -4,8 +4,6 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-
-
 use crate::compiler::{
     grammar::{instruction::CompilerState, test::Test, Capability, Comparator},
     lexer::{word::Word, Token},
@@ -13,6 +11,7 @@ use crate::compiler::{
 };
 
 use crate::compiler::grammar::{AddressPart, MatchType};
+use crate::runtime::tests::key_set::KeySet;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[cfg_attr(
@@ -26,6 +25,11 @@ use crate::compiler::grammar::{AddressPart, MatchType};
 pub(crate) struct TestAddress {
     pub header_list: Vec<Value>,
     pub key_list: Vec<Value>,
+    #[cfg_attr(
+        any(test, feature = "serde"),
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub key_set: Option<KeySet>,
     pub address_part: AddressPart,
     pub match_type: MatchType,
     pub comparator: Comparator,
@@ -135,7 +139,10 @@ impl CompilerState<'_> {
                 }
                 _ => {
                     if header_list.is_none() {
-                        header_list = self.parse_strings_token(token_info)?.into();
+                        let (line_num, line_pos) = (token_info.line_num, token_info.line_pos);
+                        let headers = self.parse_strings_token(token_info)?;
+                        self.warn_header_names(&headers, line_num, line_pos);
+                        header_list = headers.into();
                     } else {
                         key_list = self.parse_strings_token(token_info)?;
                         break;
@@ -147,11 +154,13 @@ impl CompilerState<'_> {
         if !mime && mime_anychild {
             return Err(self.tokens.unwrap_next()?.missing_tag(":mime"));
         }
-        self.validate_match(&match_type, &mut key_list)?;
+        self.validate_match(&match_type, &comparator, &mut key_list)?;
+        let key_set = KeySet::new(&match_type, &comparator, &key_list);
 
         Ok(Test::Address(TestAddress {
             header_list: header_list.unwrap(),
             key_list,
+            key_set,
             address_part,
             match_type,
             comparator,
@@ -4,8 +4,6 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-
-
 use crate::compiler::{
     grammar::{instruction::CompilerState, Capability, Comparator},
     lexer::{word::Word, Token},
@@ -13,6 +11,7 @@ use crate::compiler::{
 };
 
 use crate::compiler::grammar::{test::Test, MatchType};
+use crate::runtime::tests::key_set::KeySet;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[cfg_attr(
@@ -25,6 +24,11 @@ use crate::compiler::grammar::{test::Test, MatchType};
 )]
 pub(crate) struct TestBody {
     pub key_list: Vec<Value>,
+    #[cfg_attr(
+        any(test, feature = "serde"),
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub key_set: Option<KeySet>,
     pub body_transform: BodyTransform,
     pub match_type: MatchType,
     pub comparator: Comparator,
@@ -106,10 +110,12 @@ impl CompilerState<'_> {
                 }
             }
         }
-        self.validate_match(&match_type, &mut key_list)?;
+        self.validate_match(&match_type, &comparator, &mut key_list)?;
+        let key_set = KeySet::new(&match_type, &comparator, &key_list);
 
         Ok(Test::Body(TestBody {
             key_list,
+            key_set,
             body_transform,
             match_type,
             comparator,
@@ -4,12 +4,10 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use mail_parser::HeaderName;
-
 use crate::compiler::{
     grammar::{instruction::CompilerState, Capability, Comparator},
     lexer::{word::Word, StringConstant, Token},
-    CompileError, ErrorType, Number, Value,
+    CompileError, Number, Value,
 };
 
 use crate::compiler::grammar::{test::Test, MatchType};
@@ -45,7 +43,7 @@ pub(crate) struct TestDate {
     derive(rkyv::Serialize, rkyv::Deserialize, rkyv::Archive)
 )]
 pub(crate) struct TestCurrentDate {
-    pub zone: Option<i64>,
+    pub zone: Option<Zone>,
     pub match_type: MatchType,
     pub comparator: Comparator,
     pub date_part: DatePart,
@@ -53,7 +51,7 @@ pub(crate) struct TestCurrentDate {
     pub is_not: bool,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 #[cfg_attr(
     any(test, feature = "serde"),
     derive(serde::Serialize, serde::Deserialize)
@@ -64,6 +62,7 @@ pub(crate) struct TestCurrentDate {
 )]
 pub(crate) enum Zone {
     Time(i64),
+    Named(String),
     Original,
     Local,
 }
@@ -179,19 +178,17 @@ impl CompilerState<'_> {
                 }
                 Token::Tag(Word::Zone) => {
                     self.validate_argument(7, None, token_info.line_num, token_info.line_pos)?;
-                    zone = Zone::Time(self.parse_timezone()?);
+                    zone = self.parse_timezone()?;
                 }
                 _ => {
                     if header_name.is_none() {
+                        let (line_num, line_pos) = (token_info.line_num, token_info.line_pos);
                         let header = self.parse_string_token(token_info)?;
-                        if let Value::Text(header_name) = &header {
-                            if HeaderName::parse(header_name.as_ref()).is_none() {
-                                return Err(self
-                                    .tokens
-                                    .unwrap_next()?
-                                    .custom(ErrorType::InvalidHeaderName));
-                            }
-                        }
+                        self.validate_header_names(
+                            std::slice::from_ref(&header),
+                            line_num,
+                            line_pos,
+                        )?;
                         header_name = header.into();
                     } else if date_part.is_none() {
                         if let Token::StringConstant(string) = &token_info.token {
@@ -214,7 +211,7 @@ impl CompilerState<'_> {
         if !mime && mime_anychild {
             return Err(self.tokens.unwrap_next()?.missing_tag(":mime"));
         }
-        self.validate_match(&match_type, &mut key_list)?;
+        self.validate_match(&match_type, &comparator, &mut key_list)?;
 
         Ok(Test::Date(TestDate {
             header_name: header_name.unwrap(),
@@ -288,7 +285,7 @@ impl CompilerState<'_> {
                 }
             }
         }
-        self.validate_match(&match_type, &mut key_list)?;
+        self.validate_match(&match_type, &comparator, &mut key_list)?;
 
         Ok(Test::CurrentDate(TestCurrentDate {
             key_list,
@@ -300,25 +297,43 @@ impl CompilerState<'_> {
         }))
     }
 
-    pub(crate) fn parse_timezone(&mut self) -> Result<i64, CompileError> {
+    pub(crate) fn parse_timezone(&mut self) -> Result<Zone, CompileError> {
         let token_info = self.tokens.unwrap_next()?;
         if let Token::StringConstant(value) = &token_info.token {
-            let timezone = match value {
-                StringConstant::String(value) => value.parse::<i64>().unwrap_or(i64::MAX),
-                StringConstant::Number(Number::Integer(n)) => *n,
-                StringConstant::Number(Number::Float(n)) => *n as i64,
+            let zone = match value {
+                StringConstant::String(value) => Zone::parse(value),
+                StringConstant::Number(Number::Integer(n)) => Zone::from_offset(*n),
+                StringConstant::Number(Number::Float(n)) => Zone::from_offset(*n as i64),
             };
 
-            return match timezone {
-                0..=1400 => Ok((timezone / 100 * 3600) + (timezone % 100 * 60)),
-                -1200..=-1 => Ok((timezone / 100 * 3600) - (-timezone % 100 * 60)),
-                _ => Err(token_info.expected("invalid timezone")),
-            };
+            return zone.ok_or_else(|| token_info.expected("invalid timezone"));
         }
         Err(token_info.expected("string containing time zone"))
     }
 }
 
+impl Zone {
+    /// Parses an offset in "+hhmm" or "-hhmm" format or an IANA time zone
+    /// name such as "Europe/Berlin".
+    pub(crate) fn parse(value: &str) -> Option<Zone> {
+        if let Ok(offset) = value.parse::<i64>() {
+            Zone::from_offset(offset)
+        } else if tzdb::tz_by_name(value).is_some() {
+            Some(Zone::Named(value.to_string()))
+        } else {
+            None
+        }
+    }
+
+    fn from_offset(offset: i64) -> Option<Zone> {
+        match offset {
+            0..=1400 => Some(Zone::Time((offset / 100 * 3600) + (offset % 100 * 60))),
+            -1200..=-1 => Some(Zone::Time((offset / 100 * 3600) - (-offset % 100 * 60))),
+            _ => None,
+        }
+    }
+}
+
 /*
      "year"      => the year, "0000" .. "9999".
      "month"     => the month, "01" .. "12".
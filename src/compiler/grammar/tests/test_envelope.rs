@@ -4,8 +4,6 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-
-
 use crate::{
     compiler::{
         grammar::{instruction::CompilerState, Capability, Comparator},
@@ -15,7 +13,7 @@ use crate::{
     Envelope,
 };
 
-use crate::compiler::grammar::{test::Test, AddressPart, MatchType};
+use crate::compiler::grammar::{test::Test, tests::test_date::Zone, AddressPart, MatchType};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[cfg_attr(
@@ -32,7 +30,7 @@ pub(crate) struct TestEnvelope {
     pub address_part: AddressPart,
     pub match_type: MatchType,
     pub comparator: Comparator,
-    pub zone: Option<i64>,
+    pub zone: Option<Zone>,
     pub is_not: bool,
 }
 
@@ -183,7 +181,7 @@ impl CompilerState<'_> {
                 }
             }
         }
-        self.validate_match(&match_type, &mut key_list)?;
+        self.validate_match(&match_type, &comparator, &mut key_list)?;
 
         Ok(Test::Envelope(TestEnvelope {
             envelope_list: envelope_list.unwrap(),
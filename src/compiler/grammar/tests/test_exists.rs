@@ -4,13 +4,10 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use mail_parser::HeaderName;
-
-
 use crate::compiler::{
     grammar::{instruction::CompilerState, Capability},
     lexer::{word::Word, Token},
-    CompileError, ErrorType, Value,
+    CompileError, Value,
 };
 
 use crate::compiler::grammar::test::Test;
@@ -59,17 +56,9 @@ impl CompilerState<'_> {
                     mime_anychild = true;
                 }
                 _ => {
+                    let (line_num, line_pos) = (token_info.line_num, token_info.line_pos);
                     let headers = self.parse_strings_token(token_info)?;
-                    for header in &headers {
-                        if let Value::Text(header_name) = &header {
-                            if HeaderName::parse(header_name.as_ref()).is_none() {
-                                return Err(self
-                                    .tokens
-                                    .unwrap_next()?
-                                    .custom(ErrorType::InvalidHeaderName));
-                            }
-                        }
-                    }
+                    self.validate_header_names(&headers, line_num, line_pos)?;
                     header_names = headers.into();
                 }
             }
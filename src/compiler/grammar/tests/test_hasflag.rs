@@ -4,8 +4,6 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-
-
 use crate::compiler::{
     grammar::{instruction::CompilerState, Capability, Comparator},
     lexer::{tokenizer::TokenInfo, word::Word, Token},
@@ -98,14 +96,15 @@ impl CompilerState<'_> {
                     for variable in maybe_variables {
                         match variable {
                             Value::Text(var_name) => {
-                                variable_list.push(
-                                    self.register_variable(var_name.to_string(), is_local)
-                                        .map_err(|error_type| CompileError {
-                                            line_num,
-                                            line_pos,
-                                            error_type,
-                                        })?,
-                                );
+                                let var = self
+                                    .register_variable(var_name.to_string(), is_local)
+                                    .map_err(|error_type| CompileError {
+                                        line_num,
+                                        line_pos,
+                                        error_type,
+                                    })?;
+                                self.register_var_read(&var);
+                                variable_list.push(var);
                             }
                             _ => {
                                 return Err(self
@@ -116,7 +115,7 @@ impl CompilerState<'_> {
                         }
                     }
                     let mut flags = self.parse_strings(false)?;
-                    self.validate_match(&match_type, &mut flags)?;
+                    self.validate_match(&match_type, &comparator, &mut flags)?;
 
                     Ok(Test::HasFlag(TestHasFlag {
                         comparator,
@@ -133,7 +132,7 @@ impl CompilerState<'_> {
                 }
             }
             _ => {
-                self.validate_match(&match_type, &mut maybe_variables)?;
+                self.validate_match(&match_type, &comparator, &mut maybe_variables)?;
 
                 Ok(Test::HasFlag(TestHasFlag {
                     comparator,
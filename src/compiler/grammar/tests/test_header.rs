@@ -4,9 +4,6 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use mail_parser::HeaderName;
-
-
 use crate::compiler::{
     grammar::{
         actions::action_mime::MimeOpts,
@@ -14,10 +11,11 @@ use crate::compiler::{
         Capability, Comparator,
     },
     lexer::{word::Word, Token},
-    CompileError, ErrorType, Value,
+    CompileError, Value,
 };
 
 use crate::compiler::grammar::{test::Test, MatchType};
+use crate::runtime::tests::key_set::KeySet;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[cfg_attr(
@@ -31,6 +29,11 @@ use crate::compiler::grammar::{test::Test, MatchType};
 pub(crate) struct TestHeader {
     pub header_list: Vec<Value>,
     pub key_list: Vec<Value>,
+    #[cfg_attr(
+        any(test, feature = "serde"),
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub key_set: Option<KeySet>,
     pub match_type: MatchType,
     pub comparator: Comparator,
     pub index: Option<i32>,
@@ -135,17 +138,9 @@ impl CompilerState<'_> {
                 }
                 _ => {
                     if header_list.is_none() {
+                        let (line_num, line_pos) = (token_info.line_num, token_info.line_pos);
                         let headers = self.parse_strings_token(token_info)?;
-                        for header in &headers {
-                            if let Value::Text(header_name) = &header {
-                                if HeaderName::parse(header_name.as_ref()).is_none() {
-                                    return Err(self
-                                        .tokens
-                                        .unwrap_next()?
-                                        .custom(ErrorType::InvalidHeaderName));
-                                }
-                            }
-                        }
+                        self.validate_header_names(&headers, line_num, line_pos)?;
                         header_list = headers.into();
                     } else {
                         key_list = self.parse_strings_token(token_info)?;
@@ -158,11 +153,13 @@ impl CompilerState<'_> {
         if !mime && (mime_anychild || mime_opts != MimeOpts::None) {
             return Err(self.tokens.unwrap_next()?.missing_tag(":mime"));
         }
-        self.validate_match(&match_type, &mut key_list)?;
+        self.validate_match(&match_type, &comparator, &mut key_list)?;
+        let key_set = KeySet::new(&match_type, &comparator, &key_list);
 
         Ok(Test::Header(TestHeader {
             header_list: header_list.unwrap(),
             key_list,
+            key_set,
             match_type,
             comparator,
             index: if index_last { index.map(|i| -i) } else { index },
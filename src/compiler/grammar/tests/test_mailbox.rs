@@ -4,8 +4,6 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-
-
 use crate::{
     compiler::{
         grammar::{
@@ -83,8 +81,12 @@ servermetadata [MATCH-TYPE] [COMPARATOR]
 
 impl CompilerState<'_> {
     pub(crate) fn parse_test_mailboxexists(&mut self) -> Result<Test, CompileError> {
+        self.begin_mailbox_refs();
+        let mailbox_names = self.parse_strings(false)?;
+        self.end_mailbox_refs();
+
         Ok(Test::MailboxExists(TestMailboxExists {
-            mailbox_names: self.parse_strings(false)?,
+            mailbox_names,
             is_not: false,
         }))
     }
@@ -153,7 +155,7 @@ impl CompilerState<'_> {
                 }
             }
         }
-        self.validate_match(&match_type, &mut key_list)?;
+        self.validate_match(&match_type, &comparator, &mut key_list)?;
 
         Ok(Test::Metadata(TestMetadata {
             match_type,
@@ -212,7 +214,7 @@ impl CompilerState<'_> {
                 }
             }
         }
-        self.validate_match(&match_type, &mut key_list)?;
+        self.validate_match(&match_type, &comparator, &mut key_list)?;
 
         Ok(Test::Metadata(TestMetadata {
             match_type,
@@ -4,8 +4,6 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-
-
 use crate::compiler::{
     grammar::{instruction::CompilerState, Capability, Comparator},
     lexer::{word::Word, Token},
@@ -102,7 +100,7 @@ impl CompilerState<'_> {
                 }
             }
         }
-        self.validate_match(&match_type, &mut key_list)?;
+        self.validate_match(&match_type, &comparator, &mut key_list)?;
 
         Ok(Test::NotifyMethodCapability(TestNotifyMethodCapability {
             key_list,
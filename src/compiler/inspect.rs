@@ -0,0 +1,299 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::borrow::Cow;
+
+use crate::{ExternalId, FunctionMap, Metadata, Sieve};
+
+use super::{
+    grammar::{
+        expr::{parser::ID_EXTERNAL, Expression},
+        instruction::Instruction,
+        test::Test,
+        tests::test_duplicate::DupMatch,
+        AddressPart, Capability, MatchType,
+    },
+    Value,
+};
+
+/// Callbacks invoked by [`Sieve::visit`] for every item of interest found in
+/// a compiled script. `position` is the index of the instruction the item
+/// belongs to, which can be passed to [`Sieve::source_position`].
+#[allow(unused_variables)]
+pub trait Visitor {
+    fn visit_capability(&mut self, position: usize, capability: &Capability) {}
+
+    fn visit_mailbox(&mut self, position: usize, usage: MailboxUsage, mailbox: ScriptString<'_>) {}
+
+    fn visit_header(&mut self, position: usize, usage: HeaderUsage, name: ScriptString<'_>) {}
+
+    fn visit_address(&mut self, position: usize, usage: AddressUsage, address: ScriptString<'_>) {}
+
+    fn visit_function(&mut self, position: usize, function: FunctionCall) {}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ScriptString<'x> {
+    Constant(Cow<'x, str>),
+    Dynamic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MailboxUsage {
+    FileInto,
+    Fcc,
+    MailboxExists,
+    Metadata,
+    SpecialUseExists,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HeaderUsage {
+    Test,
+    Duplicate,
+    Add,
+    Delete,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AddressUsage {
+    Redirect,
+    VacationFrom,
+    VacationAddresses,
+    NotifyFrom,
+    /// Keys of an `address :is` test comparing whole addresses.
+    AddressTest,
+    /// Keys of an `envelope :is` test comparing whole addresses.
+    EnvelopeTest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FunctionCall {
+    Internal { id: u32, num_args: u32 },
+    External { id: ExternalId, num_args: u32 },
+}
+
+impl Sieve {
+    /// Walks the compiled script, calling the visitor for every capability,
+    /// mailbox, header name, address and function call it references.
+    /// Function calls are resolved against the `functions` the script is
+    /// run with.
+    pub fn visit(&self, functions: &FunctionMap, visitor: &mut impl Visitor) {
+        self.inspect(functions.functions.len(), visitor);
+    }
+
+    fn inspect(&self, num_functions: usize, visitor: &mut impl Visitor) {
+        for (position, instruction) in self.instructions.iter().enumerate() {
+            let mut inspector = Inspector {
+                position,
+                num_functions,
+                visitor,
+            };
+            inspector.instruction(instruction);
+        }
+    }
+
+    pub fn capabilities(&self) -> Vec<&Capability> {
+        self.instructions
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Require(capabilities) => Some(capabilities),
+                _ => None,
+            })
+            .flatten()
+            .collect()
+    }
+
+    pub fn mailboxes(&self) -> Vec<(MailboxUsage, ScriptString<'_>)> {
+        struct Mailboxes<'x>(Vec<(MailboxUsage, ScriptString<'x>)>);
+
+        impl Visitor for Mailboxes<'_> {
+            fn visit_mailbox(&mut self, _: usize, usage: MailboxUsage, mailbox: ScriptString<'_>) {
+                self.0.push((usage, mailbox.into_owned()));
+            }
+        }
+
+        let mut mailboxes = Mailboxes(Vec::new());
+        self.inspect(0, &mut mailboxes);
+        mailboxes.0
+    }
+}
+
+impl<'x> ScriptString<'x> {
+    pub fn as_constant(&self) -> Option<&str> {
+        match self {
+            ScriptString::Constant(value) => Some(value.as_ref()),
+            ScriptString::Dynamic => None,
+        }
+    }
+
+    pub fn is_dynamic(&self) -> bool {
+        matches!(self, ScriptString::Dynamic)
+    }
+
+    pub fn into_owned(self) -> ScriptString<'static> {
+        match self {
+            ScriptString::Constant(value) => ScriptString::Constant(value.into_owned().into()),
+            ScriptString::Dynamic => ScriptString::Dynamic,
+        }
+    }
+}
+
+impl<'x> From<&'x Value> for ScriptString<'x> {
+    fn from(value: &'x Value) -> Self {
+        match value {
+            Value::Text(text) => ScriptString::Constant(text.as_str().into()),
+            Value::Number(number) => ScriptString::Constant(number.to_string().into()),
+            Value::Glob(glob) => ScriptString::Constant(glob.expr.as_str().into()),
+            Value::Variable(_) | Value::Regex(_) | Value::List(_) => ScriptString::Dynamic,
+        }
+    }
+}
+
+struct Inspector<'x, V: Visitor> {
+    position: usize,
+    num_functions: usize,
+    visitor: &'x mut V,
+}
+
+impl<V: Visitor> Inspector<'_, V> {
+    fn instruction(&mut self, instruction: &Instruction) {
+        match instruction {
+            Instruction::Require(capabilities) => {
+                for capability in capabilities {
+                    self.visitor.visit_capability(self.position, capability);
+                }
+            }
+            Instruction::FileInto(fileinto) => {
+                self.mailbox(MailboxUsage::FileInto, &fileinto.folder);
+            }
+            Instruction::Redirect(redirect) if !redirect.list => {
+                self.address(AddressUsage::Redirect, &redirect.address);
+            }
+            Instruction::Vacation(vacation) => {
+                if let Some(from) = &vacation.from {
+                    self.address(AddressUsage::VacationFrom, from);
+                }
+                if let Some(fcc) = &vacation.fcc {
+                    self.mailbox(MailboxUsage::Fcc, &fcc.mailbox);
+                }
+            }
+            Instruction::Notify(notify) => {
+                if let Some(from) = &notify.from {
+                    self.address(AddressUsage::NotifyFrom, from);
+                }
+                if let Some(fcc) = &notify.fcc {
+                    self.mailbox(MailboxUsage::Fcc, &fcc.mailbox);
+                }
+            }
+            Instruction::AddHeader(add_header) => {
+                self.header(HeaderUsage::Add, &add_header.field_name);
+            }
+            Instruction::DeleteHeader(delete_header) => {
+                self.header(HeaderUsage::Delete, &delete_header.field_name);
+            }
+            Instruction::Test(test) => self.test(test),
+            Instruction::Eval(expr) => self.expression(expr),
+            Instruction::Let(let_) => self.expression(&let_.expr),
+            Instruction::While(while_) => self.expression(&while_.expr),
+            _ => (),
+        }
+    }
+
+    fn test(&mut self, test: &Test) {
+        match test {
+            Test::Header(test) => self.headers(&test.header_list),
+            Test::Address(test) => {
+                self.headers(&test.header_list);
+                if test.address_part == AddressPart::All && test.match_type == MatchType::Is {
+                    for key in &test.key_list {
+                        self.address(AddressUsage::AddressTest, key);
+                    }
+                }
+            }
+            Test::Envelope(test)
+                if test.address_part == AddressPart::All && test.match_type == MatchType::Is =>
+            {
+                for key in &test.key_list {
+                    self.address(AddressUsage::EnvelopeTest, key);
+                }
+            }
+            Test::Exists(test) => self.headers(&test.header_names),
+            Test::Date(test) => self.header(HeaderUsage::Test, &test.header_name),
+            Test::Duplicate(test) => {
+                if let DupMatch::Header(header) = &test.dup_match {
+                    self.header(HeaderUsage::Duplicate, header);
+                }
+            }
+            Test::MailboxExists(test) => {
+                for mailbox in &test.mailbox_names {
+                    self.mailbox(MailboxUsage::MailboxExists, mailbox);
+                }
+            }
+            Test::MetadataExists(test) => {
+                if let Some(mailbox) = &test.mailbox {
+                    self.mailbox(MailboxUsage::Metadata, mailbox);
+                }
+            }
+            Test::Metadata(test) => {
+                if let Metadata::Mailbox { name, .. } = &test.medatata {
+                    self.mailbox(MailboxUsage::Metadata, name);
+                }
+            }
+            Test::SpecialUseExists(test) => {
+                if let Some(mailbox) = &test.mailbox {
+                    self.mailbox(MailboxUsage::SpecialUseExists, mailbox);
+                }
+            }
+            Test::Vacation(test) => {
+                for address in &test.addresses {
+                    self.address(AddressUsage::VacationAddresses, address);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn expression(&mut self, expr: &[Expression]) {
+        for item in expr {
+            if let Expression::Function { id, num_args } = item {
+                let function = if (*id as usize) < self.num_functions {
+                    FunctionCall::Internal {
+                        id: *id,
+                        num_args: *num_args,
+                    }
+                } else {
+                    FunctionCall::External {
+                        id: ID_EXTERNAL - *id,
+                        num_args: *num_args,
+                    }
+                };
+                self.visitor.visit_function(self.position, function);
+            }
+        }
+    }
+
+    fn mailbox(&mut self, usage: MailboxUsage, value: &Value) {
+        self.visitor
+            .visit_mailbox(self.position, usage, ScriptString::from(value));
+    }
+
+    fn headers(&mut self, values: &[Value]) {
+        for value in values {
+            self.header(HeaderUsage::Test, value);
+        }
+    }
+
+    fn header(&mut self, usage: HeaderUsage, value: &Value) {
+        self.visitor
+            .visit_header(self.position, usage, ScriptString::from(value));
+    }
+
+    fn address(&mut self, usage: AddressUsage, value: &Value) {
+        self.visitor
+            .visit_address(self.position, usage, ScriptString::from(value));
+    }
+}
@@ -13,7 +13,7 @@ use crate::{
         grammar::{
             expr::{self},
             instruction::CompilerState,
-            AddressPart,
+            AddressPart, Capability,
         },
         ContentTypePart, ErrorType, HeaderPart, HeaderVariable, MessagePart, Number,
         ReceivedHostname, ReceivedPart, Value, VariableType,
@@ -88,45 +88,43 @@ impl CompilerState<'_> {
                         var_has_namespace = true;
                     }
                     b'0'..=b'9' => {}
-                    b'}' => {
-                        if pos > var_start_pos {
-                            // Add any text before the variable
-                            if !decode_buf.is_empty() {
-                                self.add_value(
-                                    &mut items,
-                                    &decode_buf,
-                                    parse_decoded,
-                                    text_has_digits,
-                                    text_has_dots,
-                                )?;
-                                decode_buf.clear();
-                                text_has_digits = true;
-                                text_has_dots = false;
-                            }
-
-                            // Parse variable type
-                            let var_name = std::str::from_utf8(&bytes[var_start_pos..pos]).unwrap();
-                            let var_type = if !var_is_number {
-                                self.parse_variable(var_name, var_has_namespace)
-                            } else {
-                                self.parse_match_variable(var_name)
-                            };
-
-                            match var_type {
-                                Ok(Some(var)) => items.push(Value::Variable(var)),
-                                Ok(None) => {}
-                                Err(
-                                    ErrorType::InvalidNamespace(_) | ErrorType::InvalidEnvelope(_),
-                                ) => {
-                                    is_var_error = true;
-                                }
-                                Err(e) => return Err(e),
-                            }
+                    b'}' if pos > var_start_pos => {
+                        // Add any text before the variable
+                        if !decode_buf.is_empty() {
+                            self.add_value(
+                                &mut items,
+                                &decode_buf,
+                                parse_decoded,
+                                text_has_digits,
+                                text_has_dots,
+                            )?;
+                            decode_buf.clear();
+                            text_has_digits = true;
+                            text_has_dots = false;
+                        }
 
-                            state = State::None;
+                        // Parse variable type
+                        let var_name = std::str::from_utf8(&bytes[var_start_pos..pos]).unwrap();
+                        let var_type = if !var_is_number {
+                            self.parse_variable(var_name, var_has_namespace)
                         } else {
-                            is_var_error = true;
+                            self.parse_match_variable(var_name)
+                        };
+
+                        match var_type {
+                            Ok(Some(var)) => {
+                                self.register_var_read(&var);
+                                self.register_capability_use(Capability::Variables);
+                                items.push(Value::Variable(var));
+                            }
+                            Ok(None) => {}
+                            Err(ErrorType::InvalidNamespace(_) | ErrorType::InvalidEnvelope(_)) => {
+                                is_var_error = true;
+                            }
+                            Err(e) => return Err(e),
                         }
+
+                        state = State::None;
                     }
                     b':' => {
                         if parse_decoded && !var_has_namespace {
@@ -192,6 +190,7 @@ impl CompilerState<'_> {
                         }
                         if ch == b'}' {
                             if decode_buf.len() != initial_buf_size {
+                                self.register_capability_use(Capability::EncodedCharacter);
                                 state = State::None;
                             } else {
                                 is_var_error = true;
@@ -642,6 +641,7 @@ impl Display for Value {
             Value::Number(n) => n.fmt(f),
             Value::Variable(v) => v.fmt(f),
             Value::Regex(r) => f.write_str(&r.expr),
+            Value::Glob(g) => f.write_str(&g.expr),
         }
     }
 }
@@ -729,8 +729,9 @@ mod tests {
     use crate::compiler::grammar::{Comparator, MatchType};
     use crate::compiler::lexer::tokenizer::Tokenizer;
     use crate::compiler::lexer::word::Word;
+    use crate::compiler::lexer::{StringConstant, Token};
     use crate::compiler::{AddressPart, HeaderPart, HeaderVariable, VariableType};
-    use crate::{AHashSet, Compiler};
+    use crate::{AHashMap, AHashSet, Compiler};
 
     #[test]
     fn tokenize_string() {
@@ -746,6 +747,7 @@ mod tests {
                 key_list: vec![Value::Variable(VariableType::Local(0))],
                 is_not: false,
             }))],
+            positions: Vec::new(),
             block_stack: Vec::new(),
             block,
             last_block_type: Word::Not,
@@ -757,6 +759,15 @@ mod tests {
             vars_match_max: usize::MAX,
             param_check: [false; MAX_PARAMS],
             includes_num: 0,
+            vars_written: AHashMap::new(),
+            vars_read: AHashSet::new(),
+            capabilities_required: Vec::new(),
+            capabilities_used: AHashSet::new(),
+            warnings: Vec::new(),
+            string_refs: None,
+            mailbox_refs: Vec::new(),
+            source_hash: 0,
+            is_lenient: false,
         };
 
         for (input, expected_result) in [
@@ -926,4 +937,24 @@ mod tests {
             assert!(compiler.tokenize_string(input.as_bytes(), true).is_err());
         }
     }
+
+    #[test]
+    fn tokenize_quoted_string() {
+        let c = Compiler::new();
+
+        for (input, expected_result) in [
+            (r#""a\"b""#, vec![r#"a"b"#]),
+            (r#""a\\" "b""#, vec![r#"a\"#, "b"]),
+            (r#""\\\"" "b""#, vec![r#"\""#, "b"]),
+            (r#""\\" "\\""#, vec![r#"\"#, r#"\"#]),
+        ] {
+            let result = Tokenizer::new(&c, input.as_bytes())
+                .map(|token| match token.unwrap().token {
+                    Token::StringConstant(StringConstant::String(value)) => value,
+                    token => panic!("Unexpected token {token:?} for {input}"),
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(result, expected_result, "Failed for {input}");
+        }
+    }
 }
@@ -19,6 +19,7 @@ pub(crate) struct Tokenizer<'x> {
     pub iter: Peekable<Iter<'x, u8>>,
     pub buf: Vec<u8>,
     pub next_token: Vec<TokenInfo>,
+    pub last_delimiter: Option<TokenInfo>,
 
     pub pos: usize,
     pub line_num: usize,
@@ -34,6 +35,8 @@ pub(crate) struct Tokenizer<'x> {
 
     pub last_ch: u8,
     pub state: State,
+
+    pub comments: Option<Vec<Comment>>,
 }
 
 #[derive(Debug)]
@@ -43,6 +46,14 @@ pub(crate) struct TokenInfo {
     pub(crate) line_pos: usize,
 }
 
+#[derive(Debug)]
+pub(crate) struct Comment {
+    pub(crate) text: Vec<u8>,
+    pub(crate) is_bracket: bool,
+    pub(crate) line_num: usize,
+    pub(crate) line_pos: usize,
+}
+
 pub(crate) enum State {
     None,
     BracketComment,
@@ -74,8 +85,43 @@ impl<'x> Tokenizer<'x> {
             token_line_pos: 0,
             token_is_tag: false,
             next_token: Vec::with_capacity(2),
+            last_delimiter: None,
             last_ch: 0,
             state: State::None,
+            comments: None,
+        }
+    }
+
+    pub fn with_comments(mut self) -> Self {
+        self.comments = Some(Vec::new());
+        self
+    }
+
+    #[inline(always)]
+    fn comment_start(&mut self, is_bracket: bool) {
+        if let Some(comments) = &mut self.comments {
+            comments.push(Comment {
+                text: Vec::new(),
+                is_bracket,
+                line_num: self.line_num,
+                line_pos: self.pos - self.line_start - usize::from(is_bracket),
+            });
+        }
+    }
+
+    #[inline(always)]
+    fn comment_push(&mut self, ch: u8) {
+        if let Some(comment) = self.comments.as_mut().and_then(|c| c.last_mut()) {
+            comment.text.push(ch);
+        }
+    }
+
+    #[inline(always)]
+    fn comment_end(&mut self) {
+        if let Some(comment) = self.comments.as_mut().and_then(|c| c.last_mut()) {
+            if comment.is_bracket || comment.text.last() == Some(&b'\r') {
+                comment.text.pop();
+            }
         }
     }
 
@@ -316,23 +362,18 @@ impl<'x> Tokenizer<'x> {
 
     pub fn peek(&mut self) -> Option<Result<&TokenInfo, CompileError>> {
         if self.next_token.is_empty() {
-            match self.next()? {
+            let last_delimiter = self.last_delimiter.take();
+            let next_token = self.next();
+            self.last_delimiter = last_delimiter;
+            match next_token? {
                 Ok(next_token) => self.next_token.push(next_token),
                 Err(err) => return Some(Err(err)),
             }
         }
         self.next_token.last().map(Ok)
     }
-}
-
-impl Iterator for Tokenizer<'_> {
-    type Item = Result<TokenInfo, CompileError>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if let Some(prev_token) = self.next_token.pop() {
-            return Some(Ok(prev_token));
-        }
 
+    fn read_token(&mut self) -> Option<Result<TokenInfo, CompileError>> {
         'outer: while let Some((ch, last_ch)) = self.next_byte() {
             match self.state {
                 State::None => match ch {
@@ -398,6 +439,7 @@ impl Iterator for Tokenizer<'_> {
                             self.last_ch = 0;
                             self.state = State::BracketComment;
                             self.text_start();
+                            self.comment_start(true);
                             if let Some(token) = self.get_current_token() {
                                 return Some(Ok(token));
                             }
@@ -407,6 +449,7 @@ impl Iterator for Tokenizer<'_> {
                     }
                     b'#' => {
                         self.state = State::HashComment;
+                        self.comment_start(false);
                         if let Some(token) = self.get_current_token() {
                             return Some(Ok(token));
                         }
@@ -426,19 +469,24 @@ impl Iterator for Tokenizer<'_> {
                         return Some(Err(self.invalid_character()));
                     }
                 },
-                State::BracketComment { .. } => match ch {
+                State::BracketComment => match ch {
                     b'/' if last_ch == b'*' => {
                         self.state = State::None;
+                        self.comment_end();
                     }
                     b'\n' => {
                         self.new_line();
+                        self.comment_push(ch);
                     }
-                    _ => (),
+                    _ => self.comment_push(ch),
                 },
                 State::HashComment => {
                     if ch == b'\n' {
                         self.state = State::None;
+                        self.comment_end();
                         self.new_line();
+                    } else {
+                        self.comment_push(ch);
                     }
                 }
                 State::QuotedString(mut str_type) => match ch {
@@ -460,6 +508,8 @@ impl Iterator for Tokenizer<'_> {
                     b'\\' => {
                         if last_ch == b'\\' {
                             self.push_byte(ch);
+                            // An escaped backslash does not escape the next character
+                            self.last_ch = 0;
                         }
                     }
                     b'0'..=b'9' => {
@@ -560,17 +610,52 @@ impl Iterator for Tokenizer<'_> {
 
         match self.state {
             State::BracketComment | State::QuotedString(_) | State::MultiLine(_) => {
+                let error_type = (&self.state).into();
+                self.state = State::None;
                 Some(Err(CompileError {
                     line_num: self.text_line_num,
                     line_pos: self.text_line_pos,
-                    error_type: (&self.state).into(),
+                    error_type,
                 }))
             }
+            State::HashComment => {
+                self.state = State::None;
+                self.comment_end();
+                None
+            }
             _ => None,
         }
     }
 }
 
+impl Iterator for Tokenizer<'_> {
+    type Item = Result<TokenInfo, CompileError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let token = if let Some(prev_token) = self.next_token.pop() {
+            Some(Ok(prev_token))
+        } else {
+            self.read_token()
+        };
+
+        // Keep track of the last delimiter for error recovery
+        self.last_delimiter = match &token {
+            Some(Ok(TokenInfo {
+                token: token @ (Token::Semicolon | Token::CurlyOpen | Token::CurlyClose),
+                line_num,
+                line_pos,
+            })) => Some(TokenInfo {
+                token: token.clone(),
+                line_num: *line_num,
+                line_pos: *line_pos,
+            }),
+            _ => None,
+        };
+
+        token
+    }
+}
+
 impl From<&State> for ErrorType {
     fn from(state: &State) -> Self {
         match state {
@@ -5,17 +5,29 @@
  */
 
 use self::{
-    grammar::{AddressPart, Capability},
+    grammar::{AddressPart, Capability, SourcePosition},
     lexer::tokenizer::TokenInfo,
+    posix::translate_ere,
+};
+use crate::{
+    runtime::{tests::glob::GlobPattern, RuntimeError},
+    Compiler, Envelope, FunctionMap, Sieve,
 };
-use crate::{runtime::RuntimeError, Compiler, Envelope, FunctionMap};
 use ahash::AHashMap;
 use arc_swap::ArcSwap;
 use mail_parser::HeaderName;
 use std::{borrow::Cow, fmt::Display, sync::Arc};
 
+pub mod binary;
+pub mod format;
 pub mod grammar;
+pub mod inspect;
 pub mod lexer;
+pub(crate) mod optimize;
+pub(crate) mod posix;
+pub mod rename;
+pub mod rules;
+pub mod verify;
 
 #[derive(Debug)]
 pub struct CompileError {
@@ -24,6 +36,20 @@ pub struct CompileError {
     error_type: ErrorType,
 }
 
+#[derive(Debug)]
+pub struct CompileWarning {
+    line_num: usize,
+    line_pos: usize,
+    warning_type: WarningType,
+}
+
+#[derive(Debug)]
+pub struct Diagnostics {
+    sieve: Option<Sieve>,
+    errors: Vec<CompileError>,
+    warnings: Vec<CompileWarning>,
+}
+
 #[derive(Debug)]
 pub enum ErrorType {
     InvalidCharacter(u8),
@@ -68,6 +94,14 @@ pub enum ErrorType {
     MissingTag(Cow<'static, str>),
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WarningType {
+    UnreachableCode,
+    UnusedVariable(String),
+    UnusedCapability(Capability),
+    InvalidHeaderName(String),
+}
+
 impl Default for Compiler {
     fn default() -> Self {
         Self::new()
@@ -107,6 +141,7 @@ pub(crate) enum Value {
     Number(Number),
     Variable(VariableType),
     Regex(Regex),
+    Glob(Glob),
     List(#[cfg_attr(feature = "rkyv", rkyv(omit_bounds))] Vec<Value>),
 }
 
@@ -126,6 +161,22 @@ pub struct Regex {
     pub expr: String,
 }
 
+/// A constant `:matches` pattern compiled for the case folding of the
+/// comparator used by the test.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Serialize, rkyv::Deserialize, rkyv::Archive)
+)]
+#[cfg_attr(
+    any(test, feature = "serde"),
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct Glob {
+    pub pattern: GlobPattern,
+    pub expr: String,
+}
+
 #[derive(Debug, Clone)]
 pub struct LazyRegex(pub Arc<ArcSwap<Option<fancy_regex::Regex>>>);
 
@@ -313,7 +364,7 @@ impl Display for Number {
 }
 
 impl Compiler {
-    pub const VERSION: u32 = 2;
+    pub const VERSION: u32 = 3;
 
     pub fn new() -> Self {
         Compiler {
@@ -327,8 +378,14 @@ impl Compiler {
             max_local_variables: 128,
             max_header_size: 1024,
             max_includes: 6,
+            max_regex_size: usize::MAX,
+            regex_backtrack_limit: 1_000_000,
+            linear_regex: false,
+            posix_regex: false,
             functions: AHashMap::new(),
             no_capability_check: false,
+            source_positions: false,
+            optimize: false,
         }
     }
 
@@ -422,6 +479,54 @@ impl Compiler {
         self
     }
 
+    /// Maximum length in bytes of a `:regex` pattern, unlimited by default.
+    pub fn set_max_regex_size(&mut self, size: usize) {
+        self.max_regex_size = size;
+    }
+
+    pub fn with_max_regex_size(mut self, size: usize) -> Self {
+        self.max_regex_size = size;
+        self
+    }
+
+    /// Maximum number of backtracking steps a compiled `:regex` pattern may
+    /// take on a single value before evaluation fails with
+    /// [`RuntimeError::RegexLimitReached`].
+    pub fn set_regex_backtrack_limit(&mut self, limit: usize) {
+        self.regex_backtrack_limit = limit;
+    }
+
+    pub fn with_regex_backtrack_limit(mut self, limit: usize) -> Self {
+        self.regex_backtrack_limit = limit;
+        self
+    }
+
+    /// Rejects `:regex` patterns that use look-around, backreferences or
+    /// any other feature that requires backtracking, so that all patterns
+    /// run in linear time.
+    pub fn set_linear_regex(&mut self, value: bool) {
+        self.linear_regex = value;
+    }
+
+    pub fn with_linear_regex(mut self, value: bool) -> Self {
+        self.linear_regex = value;
+        self
+    }
+
+    /// Treats `:regex` patterns as POSIX extended regular expressions, as
+    /// defined by the regex extension draft, rather than using the Perl-like
+    /// syntax of the regex engine. The setting is stored in the compiled
+    /// script, so it also applies to patterns built from variables, and
+    /// match variables are set using leftmost-longest semantics.
+    pub fn set_posix_regex(&mut self, value: bool) {
+        self.posix_regex = value;
+    }
+
+    pub fn with_posix_regex(mut self, value: bool) -> Self {
+        self.posix_regex = value;
+        self
+    }
+
     pub fn register_functions(mut self, fnc_map: &mut FunctionMap) -> Self {
         self.functions = std::mem::take(&mut fnc_map.map);
         self
@@ -435,6 +540,36 @@ impl Compiler {
     pub fn set_no_capability_check(&mut self, value: bool) {
         self.no_capability_check = value;
     }
+
+    pub fn with_source_positions(mut self, value: bool) -> Self {
+        self.source_positions = value;
+        self
+    }
+
+    pub fn set_source_positions(&mut self, value: bool) {
+        self.source_positions = value;
+    }
+
+    /// Folds constant expressions and removes jumps and branches that are
+    /// redundant or can never run from the compiled script.
+    pub fn with_optimize(mut self, value: bool) -> Self {
+        self.optimize = value;
+        self
+    }
+
+    pub fn set_optimize(&mut self, value: bool) {
+        self.optimize = value;
+    }
+}
+
+impl Sieve {
+    pub fn source_position(&self, pos: usize) -> Option<SourcePosition> {
+        self.positions.get(pos).copied()
+    }
+
+    pub fn has_source_positions(&self) -> bool {
+        !self.positions.is_empty()
+    }
 }
 
 impl CompileError {
@@ -451,6 +586,46 @@ impl CompileError {
     }
 }
 
+impl Diagnostics {
+    pub fn sieve(&self) -> Option<&Sieve> {
+        self.sieve.as_ref()
+    }
+
+    pub fn into_sieve(self) -> Option<Sieve> {
+        self.sieve
+    }
+
+    pub fn errors(&self) -> &[CompileError] {
+        &self.errors
+    }
+
+    pub fn has_errors(&self) -> bool {
+        !self.errors.is_empty()
+    }
+
+    pub fn warnings(&self) -> &[CompileWarning] {
+        &self.warnings
+    }
+
+    pub fn has_warnings(&self) -> bool {
+        !self.warnings.is_empty()
+    }
+}
+
+impl CompileWarning {
+    pub fn line_num(&self) -> usize {
+        self.line_num
+    }
+
+    pub fn line_pos(&self) -> usize {
+        self.line_pos
+    }
+
+    pub fn warning_type(&self) -> &WarningType {
+        &self.warning_type
+    }
+}
+
 impl PartialEq for Regex {
     fn eq(&self, other: &Self) -> bool {
         self.expr == other.expr
@@ -501,6 +676,108 @@ impl Regex {
             regex: LazyRegex(Arc::new(ArcSwap::new(Arc::new(Some(regex))))),
         }
     }
+
+    pub(crate) fn build(
+        expr: &str,
+        max_size: usize,
+        backtrack_limit: usize,
+        linear: bool,
+        posix: bool,
+    ) -> Result<fancy_regex::Regex, String> {
+        check_regex_size(expr, max_size)?;
+        if posix {
+            build_regex(&translate_ere(expr, true, true)?, backtrack_limit, linear)
+        } else {
+            build_regex(expr, backtrack_limit, linear)
+        }
+    }
+
+    /// Builds a POSIX expression that has to match a whole slice of the
+    /// value, `^` and `$` only matching if the slice starts or ends where
+    /// the value does.
+    pub(crate) fn build_anchored(
+        expr: &str,
+        max_size: usize,
+        backtrack_limit: usize,
+        linear: bool,
+        match_start: bool,
+        match_end: bool,
+    ) -> Result<fancy_regex::Regex, String> {
+        check_regex_size(expr, max_size)?;
+        build_regex(
+            &format!("\\A(?:{})\\z", translate_ere(expr, match_start, match_end)?),
+            backtrack_limit,
+            linear,
+        )
+    }
+
+    /// Builds a POSIX expression that finds where the longest match
+    /// starting at a given position ends.
+    pub(crate) fn build_longest(
+        expr: &str,
+        max_size: usize,
+    ) -> Result<regex_automata::hybrid::dfa::DFA, String> {
+        check_regex_size(expr, max_size)?;
+        regex_automata::hybrid::dfa::DFA::builder()
+            .configure(
+                regex_automata::hybrid::dfa::DFA::config()
+                    .match_kind(regex_automata::MatchKind::All),
+            )
+            .build(&translate_ere(expr, true, true)?)
+            .map_err(|err| err.to_string())
+    }
+}
+
+fn check_regex_size(expr: &str, max_size: usize) -> Result<(), String> {
+    if expr.len() > max_size {
+        Err(format!("pattern exceeds {max_size} bytes"))
+    } else {
+        Ok(())
+    }
+}
+
+fn build_regex(
+    expr: &str,
+    backtrack_limit: usize,
+    linear: bool,
+) -> Result<fancy_regex::Regex, String> {
+    if linear
+        && !fancy_regex::Expr::parse_tree(expr)
+            .map_err(|err| err.to_string())?
+            .expr
+            .is_regular()
+    {
+        return Err("look-around and backreferences are not allowed".to_string());
+    }
+    fancy_regex::RegexBuilder::new(expr)
+        .backtrack_limit(backtrack_limit)
+        .build()
+        .map_err(|err| err.to_string())
+}
+
+trait IsRegular {
+    fn is_regular(&self) -> bool;
+}
+
+impl IsRegular for fancy_regex::Expr {
+    fn is_regular(&self) -> bool {
+        match self {
+            fancy_regex::Expr::Concat(exprs) | fancy_regex::Expr::Alt(exprs) => {
+                exprs.iter().all(|expr| expr.is_regular())
+            }
+            fancy_regex::Expr::Group(expr) | fancy_regex::Expr::Repeat { child: expr, .. } => {
+                expr.is_regular()
+            }
+            fancy_regex::Expr::LookAround(..)
+            | fancy_regex::Expr::Backref(_)
+            | fancy_regex::Expr::AtomicGroup(_)
+            | fancy_regex::Expr::KeepOut
+            | fancy_regex::Expr::ContinueFromPreviousMatchEnd
+            | fancy_regex::Expr::BackrefExistsCondition(_)
+            | fancy_regex::Expr::Conditional { .. } => false,
+            _ => true,
+        }
+    }
 }
 
 impl Display for CompileError {
@@ -570,6 +847,30 @@ impl Display for CompileError {
     }
 }
 
+impl Display for CompileWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.warning_type {
+            WarningType::UnreachableCode => write!(f, "Unreachable code after 'stop'"),
+            WarningType::UnusedVariable(value) => {
+                write!(f, "Variable {value:?} is set but never used")
+            }
+            WarningType::UnusedCapability(value) => {
+                write!(f, "Capability '{value}' is required but never used")
+            }
+            WarningType::InvalidHeaderName(value) => {
+                write!(f, "Invalid header name {value:?}")
+            }
+        }?;
+
+        write!(
+            f,
+            " at line {}, column {}.",
+            self.line_num(),
+            self.line_pos()
+        )
+    }
+}
+
 impl Display for RuntimeError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -594,6 +895,11 @@ impl Display for RuntimeError {
                 f,
                 "Script exceeded the maximum number of instructions allowed to execute."
             ),
+            RuntimeError::InvalidRegex(value) => write!(f, "Invalid regular expression: {value}"),
+            RuntimeError::RegexLimitReached => write!(
+                f,
+                "Regular expression exceeded the maximum number of backtracking steps allowed."
+            ),
         }
     }
 }
@@ -602,7 +908,13 @@ impl Display for RuntimeError {
 mod tests {
     use std::{fs, path::PathBuf};
 
-    use crate::Compiler;
+    use super::{
+        grammar::{instruction::Instruction, test::Test, AddressPart, Capability},
+        inspect::{AddressUsage, FunctionCall, HeaderUsage, MailboxUsage, ScriptString, Visitor},
+        rules::{Condition, Rule, RuleAction, RuleErrorType, RuleMatch, RuleSet},
+        CompileError, ErrorType, Value, WarningType,
+    };
+    use crate::{Compiler, FunctionMap, Sieve};
 
     #[test]
     fn parse_rfc() {
@@ -636,6 +948,7 @@ mod tests {
                 tests_run += 1;
 
                 let sieve = compiler.compile(&script).unwrap();
+                sieve.verify().unwrap();
                 let json_sieve = serde_json::to_string_pretty(
                     &sieve
                         .instructions
@@ -659,4 +972,558 @@ mod tests {
             test_dir.display()
         );
     }
+
+    #[test]
+    fn compiled_globs() {
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require \"variables\";\n",
+                    "set \"word\" \"money\";\n",
+                    "if header :matches \"subject\" \"*MAKE ${word}*\" { stop; }\n",
+                    "if header :matches \"subject\" \"*MAKE money?*\" { stop; }\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+
+        // Only constant patterns are compiled, and they survive serialization
+        let script: Sieve = serde_json::from_str(&serde_json::to_string(&script).unwrap()).unwrap();
+        assert_eq!(
+            script
+                .instructions
+                .iter()
+                .filter_map(|instruction| match instruction {
+                    Instruction::Test(Test::Header(test)) => {
+                        Some(matches!(test.key_list.as_slice(), [Value::Glob(_)]))
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>(),
+            [false, true]
+        );
+    }
+
+    #[test]
+    fn key_sets() {
+        let keys = |keys: &[&str]| {
+            let mut list = (0..8).map(|n| format!("\"unused{n}\"")).collect::<Vec<_>>();
+            list.extend(keys.iter().map(|key| format!("{key:?}")));
+            format!("[{}]", list.join(", "))
+        };
+        let script = format!(
+            concat!(
+                "require \"body\";\n",
+                "if header :contains \"subject\" {} {{ stop; }}\n",
+                "if address :is \"from\" {} {{ stop; }}\n",
+                "if body :contains {} {{ stop; }}\n",
+                "if header :contains \"subject\" [\"money\", \"fast\"] {{ stop; }}\n",
+            ),
+            keys(&["MONEY"]),
+            keys(&["john@example.org"]),
+            keys(&["lottery"]),
+        );
+        let script = Compiler::new().compile(script.as_bytes()).unwrap();
+
+        // Long key lists are matched with a key set, which survives serialization
+        let script: Sieve = serde_json::from_str(&serde_json::to_string(&script).unwrap()).unwrap();
+        assert_eq!(
+            script
+                .instructions
+                .iter()
+                .filter_map(|instruction| match instruction {
+                    Instruction::Test(Test::Header(test)) => Some(test.key_set.is_some()),
+                    Instruction::Test(Test::Address(test)) => Some(test.key_set.is_some()),
+                    Instruction::Test(Test::Body(test)) => Some(test.key_set.is_some()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>(),
+            [true, true, true, false]
+        );
+    }
+
+    #[test]
+    fn compile_with_diagnostics() {
+        let mut test_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        test_dir.push("tests");
+        test_dir.push("compile");
+        test_dir.push("recover");
+
+        let compiler = Compiler::new();
+
+        for (file_name, expected_errors) in [
+            ("commands-semicolon.sieve", vec![9, 16]),
+            ("commands-endblock.sieve", vec![5, 10, 25]),
+            ("tests-endcomma.sieve", vec![4, 12]),
+            ("commands-lexer.sieve", vec![4, 5, 10]),
+        ] {
+            let script = fs::read(test_dir.join(file_name)).unwrap();
+            assert!(compiler.compile(&script).is_err());
+
+            let diagnostics = compiler.compile_with_diagnostics(&script);
+            assert!(diagnostics.sieve().is_some(), "{file_name}");
+            assert_eq!(
+                diagnostics
+                    .errors()
+                    .iter()
+                    .map(|err| err.line_num())
+                    .collect::<Vec<_>>(),
+                expected_errors,
+                "{file_name}: {:?}",
+                diagnostics.errors()
+            );
+        }
+
+        // Scripts without errors compile to the same program
+        let script = b"require \"fileinto\";\nif true { fileinto \"Spam\"; }\n";
+        let diagnostics = compiler.compile_with_diagnostics(script);
+        assert!(!diagnostics.has_errors());
+        assert_eq!(
+            diagnostics.into_sieve().unwrap(),
+            compiler.compile(script).unwrap()
+        );
+    }
+
+    #[test]
+    fn compile_warnings() {
+        let mut test_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        test_dir.push("tests");
+        test_dir.push("compile");
+        test_dir.push("warnings");
+
+        let compiler = Compiler::new();
+
+        for (file_name, expected_warnings) in [
+            ("eof.sieve", vec![]),
+            (
+                "invalid-headers.sieve",
+                vec![
+                    (2, WarningType::InvalidHeaderName("from:".into())),
+                    (7, WarningType::InvalidHeaderName("from:".into())),
+                    (12, WarningType::InvalidHeaderName("from:".into())),
+                ],
+            ),
+            (
+                "unused.sieve",
+                vec![
+                    (1, WarningType::UnusedCapability(Capability::Envelope)),
+                    (4, WarningType::UnusedVariable("unused".into())),
+                    (9, WarningType::UnreachableCode),
+                ],
+            ),
+        ] {
+            let script = fs::read(test_dir.join(file_name)).unwrap();
+            let diagnostics = compiler.compile_with_diagnostics(&script);
+            assert!(!diagnostics.has_errors(), "{file_name}");
+            assert_eq!(
+                diagnostics
+                    .warnings()
+                    .iter()
+                    .map(|warning| (warning.line_num(), warning.warning_type().clone()))
+                    .collect::<Vec<_>>(),
+                expected_warnings,
+                "{file_name}: {:?}",
+                diagnostics.warnings()
+            );
+
+            let (_, warnings) = compiler.compile_with_warnings(&script).unwrap();
+            assert_eq!(
+                warnings
+                    .iter()
+                    .map(|warning| (warning.line_num(), warning.warning_type().clone()))
+                    .collect::<Vec<_>>(),
+                expected_warnings,
+                "{file_name}: {warnings:?}"
+            );
+        }
+
+        // Invalid header names in address tests are never an error
+        assert!(compiler
+            .compile(br#"if address "from:" "x" { stop; }"#)
+            .is_ok());
+
+        // Invalid header names in other tests are only warnings when warnings are requested
+        let script = fs::read(test_dir.join("invalid-headers.sieve")).unwrap();
+        assert!(matches!(
+            compiler.compile(&script),
+            Err(CompileError {
+                error_type: ErrorType::InvalidHeaderName,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn format_scripts() {
+        let mut test_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        test_dir.push("tests");
+
+        let compiler = Compiler::new().with_max_nested_foreverypart(10);
+
+        // Formatting is canonical
+        let script = fs::read(test_dir.join("compile").join("format").join("input.sieve")).unwrap();
+        let expected = fs::read_to_string(
+            test_dir
+                .join("compile")
+                .join("format")
+                .join("expected.sieve"),
+        )
+        .unwrap();
+        let formatted = compiler.format(&script).unwrap();
+        assert_eq!(formatted, expected);
+        assert_eq!(compiler.format(formatted.as_bytes()).unwrap(), expected);
+        assert_eq!(
+            without_source_details(compiler.compile(&script).unwrap()),
+            without_source_details(compiler.compile(formatted.as_bytes()).unwrap())
+        );
+
+        // Custom comparators keep their capability prefix
+        assert!(compiler
+            .format(b"require \"comparator-x-phone\";\n")
+            .unwrap()
+            .contains("\"comparator-x-phone\""));
+
+        // Formatted scripts compile to the same program
+        let mut dirs = vec![test_dir];
+        let mut tests_run = 0;
+        while let Some(dir) = dirs.pop() {
+            for file_name in fs::read_dir(&dir).unwrap() {
+                let file_name = file_name.unwrap().path();
+                if file_name.is_dir() {
+                    dirs.push(file_name);
+                } else if file_name
+                    .extension()
+                    .is_some_and(|e| e == "sieve" || e == "svtest")
+                {
+                    let script = fs::read(&file_name).unwrap();
+                    let Ok(sieve) = compiler.compile(&script) else {
+                        assert!(compiler.format(&script).is_err());
+                        continue;
+                    };
+                    let formatted = compiler.format(&script).unwrap();
+                    assert_eq!(
+                        without_source_details(compiler.compile(formatted.as_bytes()).unwrap()),
+                        without_source_details(sieve),
+                        "{}:\n{formatted}",
+                        file_name.display()
+                    );
+                    assert_eq!(
+                        compiler.format(formatted.as_bytes()).unwrap(),
+                        formatted,
+                        "{}",
+                        file_name.display()
+                    );
+                    tests_run += 1;
+                }
+            }
+        }
+        assert!(tests_run > 0);
+    }
+
+    #[test]
+    fn inspect_script() {
+        #[derive(Default)]
+        struct Collector {
+            headers: Vec<(HeaderUsage, String)>,
+            addresses: Vec<(AddressUsage, String)>,
+            functions: Vec<FunctionCall>,
+        }
+
+        impl Visitor for Collector {
+            fn visit_header(&mut self, _: usize, usage: HeaderUsage, name: ScriptString<'_>) {
+                self.headers
+                    .push((usage, name.as_constant().unwrap_or("*").to_string()));
+            }
+
+            fn visit_address(&mut self, _: usize, usage: AddressUsage, address: ScriptString<'_>) {
+                self.addresses
+                    .push((usage, address.as_constant().unwrap_or("*").to_string()));
+            }
+
+            fn visit_function(&mut self, _: usize, function: FunctionCall) {
+                self.functions.push(function);
+            }
+        }
+
+        let mut fnc_map = FunctionMap::new()
+            .with_function("trim", |_, v| v[0].to_string().trim().to_string().into())
+            .with_external_function("lookup", 7, 2);
+        let compiler = Compiler::new().register_functions(&mut fnc_map);
+        let sieve = compiler
+            .compile(
+                br#"require ["fileinto", "mailbox", "variables", "editheader", "vacation", "fcc", "envelope", "vnd.stalwart.expressions"];
+if header :contains ["Subject", "X-Spam"] "spam" {
+    fileinto "Junk";
+} elsif address :is "from" "boss@example.com" {
+    redirect "assistant@example.com";
+} elsif mailboxexists "Archive" {
+    set "year" "2024";
+    fileinto "Archive/${year}";
+}
+if eval "lookup(trim('a'), 'b')" {
+    addheader "X-Checked" "yes";
+}
+if envelope :domain "from" "example.com" {
+    keep;
+} elsif envelope "to" "me@example.com" {
+    keep;
+} elsif envelope :matches "to" "*@example.com" {
+    keep;
+} elsif address :contains "to" "example.com" {
+    keep;
+}
+vacation :from "me@example.com" :fcc "Sent" "I'm away";
+"#,
+            )
+            .unwrap();
+
+        assert_eq!(
+            sieve
+                .capabilities()
+                .into_iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>(),
+            [
+                "fileinto",
+                "mailbox",
+                "variables",
+                "editheader",
+                "vacation",
+                "fcc",
+                "envelope",
+                "vnd.stalwart.expressions"
+            ]
+        );
+        assert_eq!(
+            sieve.mailboxes(),
+            [
+                (
+                    MailboxUsage::FileInto,
+                    ScriptString::Constant("Junk".into())
+                ),
+                (
+                    MailboxUsage::MailboxExists,
+                    ScriptString::Constant("Archive".into())
+                ),
+                (MailboxUsage::FileInto, ScriptString::Dynamic),
+                (MailboxUsage::Fcc, ScriptString::Constant("Sent".into())),
+            ]
+        );
+
+        let mut collector = Collector::default();
+        sieve.visit(&fnc_map, &mut collector);
+        assert_eq!(
+            collector.headers,
+            [
+                (HeaderUsage::Test, "Subject".to_string()),
+                (HeaderUsage::Test, "X-Spam".to_string()),
+                (HeaderUsage::Test, "from".to_string()),
+                (HeaderUsage::Add, "X-Checked".to_string()),
+                (HeaderUsage::Test, "to".to_string()),
+            ]
+        );
+        assert_eq!(
+            collector.addresses,
+            [
+                (AddressUsage::AddressTest, "boss@example.com".to_string()),
+                (AddressUsage::Redirect, "assistant@example.com".to_string()),
+                (AddressUsage::EnvelopeTest, "me@example.com".to_string()),
+                (AddressUsage::VacationFrom, "me@example.com".to_string()),
+            ]
+        );
+        assert_eq!(
+            collector.functions,
+            [
+                FunctionCall::Internal { id: 0, num_args: 1 },
+                FunctionCall::External { id: 7, num_args: 2 }
+            ]
+        );
+    }
+
+    #[test]
+    fn rename_mailboxes() {
+        let compiler = Compiler::new();
+        let script = br#"require ["fileinto", "mailbox", "variables", "vacation", "fcc"];
+if mailboxexists ["Work", "Work/Reports"] {
+    fileinto :create "Work";
+}
+if header :matches "subject" "*" {
+    fileinto "Archive/${1}";
+}
+vacation :fcc "Sent Items" "I'm away";
+"#;
+
+        let result = compiler
+            .rename_mailboxes(
+                script,
+                &[("Work", "Projects \"2024\""), ("Sent Items", "Sent")],
+            )
+            .unwrap();
+        assert_eq!(
+            std::str::from_utf8(result.script()).unwrap(),
+            r#"require ["fileinto", "mailbox", "variables", "vacation", "fcc"];
+if mailboxexists ["Projects \"2024\"", "Work/Reports"] {
+    fileinto :create "Projects \"2024\"";
+}
+if header :matches "subject" "*" {
+    fileinto "Archive/${1}";
+}
+vacation :fcc "Sent" "I'm away";
+"#
+        );
+        assert_eq!(
+            result
+                .renamed()
+                .iter()
+                .map(|pos| (pos.line_num(), pos.line_pos()))
+                .collect::<Vec<_>>(),
+            [(2, 19), (3, 22), (8, 15)]
+        );
+        assert_eq!(
+            result
+                .dynamic()
+                .iter()
+                .map(|pos| pos.line_num())
+                .collect::<Vec<_>>(),
+            [6]
+        );
+        assert!(compiler.compile(result.script()).is_ok());
+
+        // Strings ending in an escaped backslash
+        let result = compiler
+            .rename_mailboxes(
+                br#"require "fileinto"; fileinto "Work\\"; fileinto "Work";"#,
+                &[("Work\\", "Old"), ("Work", "New")],
+            )
+            .unwrap();
+        assert_eq!(
+            std::str::from_utf8(result.script()).unwrap(),
+            r#"require "fileinto"; fileinto "Old"; fileinto "New";"#
+        );
+    }
+
+    #[test]
+    fn rule_builder() {
+        let rules = RuleSet::new()
+            .with_rule(
+                Rule::new()
+                    .with_condition(Condition::Address {
+                        names: vec!["from".into()],
+                        part: AddressPart::Domain,
+                        match_type: RuleMatch::Is,
+                        keys: vec!["example.com".into()],
+                        negate: false,
+                    })
+                    .with_condition(Condition::Header {
+                        names: vec!["subject".into()],
+                        match_type: RuleMatch::Contains,
+                        keys: vec!["invoice".into(), "receipt".into()],
+                        negate: false,
+                    })
+                    .with_match_any(true)
+                    .with_action(RuleAction::AddFlags(vec!["\\Seen".into()]))
+                    .with_action(RuleAction::FileInto {
+                        mailbox: "Billing".into(),
+                        copy: false,
+                        create: true,
+                    })
+                    .with_stop(true),
+            )
+            .with_rule(
+                Rule::new()
+                    .with_condition(Condition::Exists {
+                        names: vec!["list-id".into()],
+                        negate: true,
+                    })
+                    .with_condition(Condition::Size {
+                        over: true,
+                        limit: 1048576,
+                    })
+                    .with_action(RuleAction::Redirect {
+                        address: "archive@example.com".into(),
+                        copy: true,
+                    }),
+            )
+            .with_rule(Rule::new().with_action(RuleAction::Keep));
+
+        let script = rules.to_sieve().unwrap();
+        assert_eq!(
+            script,
+            r#"require ["imap4flags", "fileinto", "mailbox", "copy"];
+
+if anyof (address :domain :is "from" "example.com",
+          header :contains "subject" ["invoice", "receipt"]) {
+    addflag "\\Seen";
+    fileinto :create "Billing";
+    stop;
+}
+
+if allof (not exists "list-id",
+          size :over 1M) {
+    redirect :copy "archive@example.com";
+}
+
+if true {
+    keep;
+}
+"#
+        );
+
+        let compiler = Compiler::new();
+        let sieve = compiler.compile(script.as_bytes()).unwrap();
+        assert_eq!(RuleSet::from_sieve(&sieve), Some(rules));
+
+        // Scripts outside the supported subset are not recognized
+        for script in [
+            "if header :contains \"subject\" \"a\" { keep; } else { discard; }",
+            "require \"variables\"; set \"a\" \"b\";",
+            "if header :matches \"subject\" \"*\" { stop; keep; }",
+        ] {
+            let sieve = compiler.compile(script.as_bytes()).unwrap();
+            assert_eq!(RuleSet::from_sieve(&sieve), None, "{script}");
+        }
+
+        // Empty lists cannot be expressed in Sieve
+        for rule in [
+            Rule::new().with_condition(Condition::Header {
+                names: vec![],
+                match_type: RuleMatch::Is,
+                keys: vec!["a".into()],
+                negate: false,
+            }),
+            Rule::new().with_condition(Condition::Address {
+                names: vec!["from".into()],
+                part: AddressPart::All,
+                match_type: RuleMatch::Is,
+                keys: vec![],
+                negate: false,
+            }),
+            Rule::new().with_condition(Condition::Exists {
+                names: vec![],
+                negate: false,
+            }),
+            Rule::new().with_action(RuleAction::SetFlags(vec![])),
+        ] {
+            let err = RuleSet::new()
+                .with_rule(Rule::new().with_action(RuleAction::Keep))
+                .with_rule(rule)
+                .to_sieve()
+                .unwrap_err();
+            assert_eq!(err.rule(), 1);
+            assert_eq!(err.error_type(), &RuleErrorType::EmptyList);
+        }
+    }
+
+    fn without_source_details(mut sieve: Sieve) -> Sieve {
+        sieve.source_hash = 0;
+        for instruction in &mut sieve.instructions {
+            if let Instruction::Invalid(invalid) | Instruction::Test(Test::Invalid(invalid)) =
+                instruction
+            {
+                invalid.line_num = 0;
+                invalid.line_pos = 0;
+            }
+        }
+        sieve
+    }
 }
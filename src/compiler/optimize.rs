@@ -0,0 +1,333 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use ahash::AHashSet;
+
+use crate::{runtime::Variable, Sieve};
+
+use super::grammar::{
+    expr::{BinaryOperator, Constant, Expression, UnaryOperator},
+    instruction::Instruction,
+    test::Test,
+};
+
+impl Sieve {
+    /// Rewrites the script into an equivalent program that folds constant
+    /// expressions, threads jumps and drops branches that can never run.
+    pub(crate) fn optimize(&mut self) {
+        for instruction in &mut self.instructions {
+            match instruction {
+                Instruction::Eval(expr) => fold_constants(expr),
+                Instruction::Let(let_) => fold_constants(&mut let_.expr),
+                Instruction::While(while_) => fold_constants(&mut while_.expr),
+                _ => {}
+            }
+        }
+
+        loop {
+            let changed = self.thread_jumps() | self.fold_branches();
+            if !self.remove_dead_code() && !changed {
+                break;
+            }
+        }
+    }
+
+    /// Points jumps that land on a `Jmp` to its final destination.
+    fn thread_jumps(&mut self) -> bool {
+        let mut changed = false;
+
+        for pos in 0..self.instructions.len() {
+            let (Instruction::Jmp(target) | Instruction::Jz(target) | Instruction::Jnz(target)) =
+                &self.instructions[pos]
+            else {
+                continue;
+            };
+            let mut new_target = *target;
+            for _ in 0..self.instructions.len() {
+                match self.instructions.get(new_target) {
+                    Some(Instruction::Jmp(next)) if *next != new_target => {
+                        new_target = *next;
+                    }
+                    _ => break,
+                }
+            }
+
+            // Conditional jumps only go forward
+            let is_valid = match &self.instructions[pos] {
+                Instruction::Jmp(_) => new_target != pos,
+                _ => new_target > pos,
+            };
+            if is_valid && new_target != *target {
+                match &mut self.instructions[pos] {
+                    Instruction::Jmp(target)
+                    | Instruction::Jz(target)
+                    | Instruction::Jnz(target) => {
+                        *target = new_target;
+                    }
+                    _ => unreachable!(),
+                }
+                changed = true;
+            }
+        }
+
+        changed
+    }
+
+    /// Resolves conditional jumps that follow a test with a constant result.
+    fn fold_branches(&mut self) -> bool {
+        let targets = self.jump_targets();
+        let mut changed = false;
+
+        for pos in 1..self.instructions.len() {
+            // Jumps landing here may carry a different test result
+            if targets.contains(&pos) {
+                continue;
+            }
+            let result = match &self.instructions[pos - 1] {
+                Instruction::Test(Test::True) => true,
+                Instruction::Test(Test::False) => false,
+                Instruction::Eval(expr) => match expr.as_slice() {
+                    [Expression::Constant(value)] => Variable::from(value).to_bool(),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            let instruction = &mut self.instructions[pos];
+            match *instruction {
+                Instruction::Jz(target) | Instruction::Jnz(target) => {
+                    let is_taken = matches!(instruction, Instruction::Jnz(_)) == result;
+                    *instruction = Instruction::Jmp(if is_taken { target } else { pos + 1 });
+                    changed = true;
+                }
+                _ => {}
+            }
+        }
+
+        changed
+    }
+
+    /// Removes unreachable instructions along with jumps to the next
+    /// instruction and `Clear` instructions with nothing to clear.
+    fn remove_dead_code(&mut self) -> bool {
+        let num_instructions = self.instructions.len();
+        let mut reachable = vec![false; num_instructions];
+        let mut stack = vec![0];
+        while let Some(pos) = stack.pop() {
+            if pos >= num_instructions || reachable[pos] {
+                continue;
+            }
+            reachable[pos] = true;
+            match &self.instructions[pos] {
+                Instruction::Jmp(target) => stack.push(*target),
+                Instruction::Jz(target) | Instruction::Jnz(target) => {
+                    stack.extend([pos + 1, *target])
+                }
+                Instruction::ForEveryPart(fep) => stack.extend([pos + 1, fep.jz_pos]),
+                Instruction::While(while_) => stack.extend([pos + 1, while_.jz_pos]),
+                Instruction::Stop | Instruction::Return => {}
+                _ => stack.push(pos + 1),
+            }
+        }
+
+        // Removing the target of a backward jump could turn it into a self-loop
+        let loop_starts = self
+            .instructions
+            .iter()
+            .enumerate()
+            .filter_map(|(pos, instruction)| match instruction {
+                Instruction::Jmp(target) if *target <= pos => Some(*target),
+                _ => None,
+            })
+            .collect::<AHashSet<_>>();
+        let keep = self
+            .instructions
+            .iter()
+            .enumerate()
+            .map(|(pos, instruction)| {
+                reachable[pos]
+                    && (loop_starts.contains(&pos)
+                        || !match instruction {
+                            Instruction::Jmp(target)
+                            | Instruction::Jz(target)
+                            | Instruction::Jnz(target) => *target == pos + 1,
+                            Instruction::Clear(clear) => {
+                                clear.local_vars_num == 0 && clear.match_vars == 0
+                            }
+                            _ => false,
+                        })
+            })
+            .collect::<Vec<_>>();
+        if keep.iter().all(|keep| *keep) {
+            return false;
+        }
+
+        // Removed instructions map to the next one that is kept
+        let mut new_pos = Vec::with_capacity(num_instructions + 1);
+        let mut num_kept = 0;
+        for keep in &keep {
+            new_pos.push(num_kept);
+            num_kept += usize::from(*keep);
+        }
+        new_pos.push(num_kept);
+
+        let instructions = std::mem::take(&mut self.instructions);
+        for (pos, mut instruction) in instructions.into_iter().enumerate() {
+            if !keep[pos] {
+                continue;
+            }
+            match &mut instruction {
+                Instruction::Jmp(target) | Instruction::Jz(target) | Instruction::Jnz(target) => {
+                    *target = new_pos[(*target).min(num_instructions)];
+                }
+                Instruction::ForEveryPart(fep) => {
+                    fep.jz_pos = new_pos[fep.jz_pos.min(num_instructions)];
+                }
+                Instruction::While(while_) => {
+                    while_.jz_pos = new_pos[while_.jz_pos.min(num_instructions)];
+                }
+                _ => {}
+            }
+            self.instructions.push(instruction);
+        }
+        if self.positions.len() == num_instructions {
+            let mut pos = 0;
+            self.positions.retain(|_| {
+                pos += 1;
+                keep[pos - 1]
+            });
+        }
+
+        true
+    }
+
+    fn jump_targets(&self) -> AHashSet<usize> {
+        self.instructions
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Jmp(target) | Instruction::Jz(target) | Instruction::Jnz(target) => {
+                    Some(*target)
+                }
+                Instruction::ForEveryPart(fep) => Some(fep.jz_pos),
+                Instruction::While(while_) => Some(while_.jz_pos),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Replaces operators applied to constants with their result.
+fn fold_constants(expr: &mut Vec<Expression>) {
+    if !expr.iter().any(|item| {
+        matches!(
+            item,
+            Expression::UnaryOperator(_) | Expression::BinaryOperator(_)
+        )
+    }) {
+        return;
+    }
+
+    // Short-circuit jumps skip to the item after their operator
+    let targets = expr
+        .iter()
+        .enumerate()
+        .filter_map(|(pos, item)| match item {
+            Expression::JmpIf { pos: offset, .. } => Some(pos + 1 + *offset as usize),
+            _ => None,
+        })
+        .collect::<AHashSet<_>>();
+
+    let num_items = expr.len();
+    let mut folded: Vec<(usize, Expression)> = Vec::with_capacity(num_items);
+    let mut new_pos = Vec::with_capacity(num_items + 1);
+    for (pos, item) in std::mem::take(expr).into_iter().enumerate() {
+        let result = match &item {
+            _ if targets.contains(&pos) => None,
+            Expression::UnaryOperator(op) => match folded.as_slice() {
+                [.., (_, Expression::Constant(value))] => op.fold(value).map(|value| (1, value)),
+                _ => None,
+            },
+            Expression::BinaryOperator(op) => match folded.as_slice() {
+                [.., (_, Expression::Constant(left)), (right_pos, Expression::Constant(right))]
+                    if !targets.contains(right_pos) =>
+                {
+                    op.fold(left, right).map(|value| (2, value))
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+
+        if let Some((num_args, value)) = result {
+            let (origin, _) = folded[folded.len() - num_args];
+            folded.truncate(folded.len() - num_args);
+            new_pos.push(folded.len());
+            folded.push((origin, Expression::Constant(value)));
+        } else {
+            new_pos.push(folded.len());
+            folded.push((pos, item));
+        }
+    }
+    new_pos.push(folded.len());
+
+    *expr = folded
+        .into_iter()
+        .enumerate()
+        .map(|(pos, (origin, mut item))| {
+            if let Expression::JmpIf { pos: offset, .. } = &mut item {
+                let target = new_pos[(origin + 1 + *offset as usize).min(num_items)];
+                *offset = (target - pos - 1) as u32;
+            }
+            item
+        })
+        .collect();
+}
+
+impl UnaryOperator {
+    fn fold(&self, value: &Constant) -> Option<Constant> {
+        match (self, value) {
+            (UnaryOperator::Not, _) => Variable::from(value).op_not().into_constant(),
+            (UnaryOperator::Minus, Constant::Integer(value)) if *value != i64::MIN => {
+                Some(Constant::Integer(-value))
+            }
+            (UnaryOperator::Minus, Constant::Float(value)) => Some(Constant::Float(-value)),
+            _ => None,
+        }
+    }
+}
+
+impl BinaryOperator {
+    fn fold(&self, left: &Constant, right: &Constant) -> Option<Constant> {
+        let left = Variable::from(left);
+        let right = Variable::from(right);
+        match self {
+            BinaryOperator::Add => left.op_add(right),
+            BinaryOperator::Subtract => left.op_subtract(right),
+            BinaryOperator::Multiply => left.op_multiply(right),
+            BinaryOperator::Divide => left.op_divide(right),
+            BinaryOperator::And => left.op_and(right),
+            BinaryOperator::Or => left.op_or(right),
+            BinaryOperator::Xor => left.op_xor(right),
+            BinaryOperator::Eq => left.op_eq(right),
+            BinaryOperator::Ne => left.op_ne(right),
+            BinaryOperator::Lt => left.op_lt(right),
+            BinaryOperator::Le => left.op_le(right),
+            BinaryOperator::Gt => left.op_gt(right),
+            BinaryOperator::Ge => left.op_ge(right),
+        }
+        .into_constant()
+    }
+}
+
+impl Variable {
+    fn into_constant(self) -> Option<Constant> {
+        match self {
+            Variable::String(value) => Some(Constant::String(value)),
+            Variable::Integer(value) => Some(Constant::Integer(value)),
+            Variable::Float(value) => Some(Constant::Float(value)),
+            Variable::Array(_) => None,
+        }
+    }
+}
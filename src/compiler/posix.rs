@@ -0,0 +1,229 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::{iter::Peekable, str::Chars};
+
+// Matches nothing, used in place of anchors that cannot match
+const NEVER_MATCH: &str = r"[^\x00-\x{10FFFF}]";
+
+const CHARACTER_CLASSES: &[&str] = &[
+    "alnum", "alpha", "blank", "cntrl", "digit", "graph", "lower", "print", "punct", "space",
+    "upper", "xdigit",
+];
+
+/// Translates a POSIX extended regular expression into the syntax used by
+/// the regex engine. When `match_start` or `match_end` are false, the `^`
+/// and `$` anchors never match, which is used to evaluate the pattern on a
+/// slice of the original value.
+pub(crate) fn translate_ere(
+    expr: &str,
+    match_start: bool,
+    match_end: bool,
+) -> Result<String, String> {
+    let mut result = String::with_capacity(expr.len() + 8);
+    let mut chars = expr.chars().peekable();
+    let mut can_repeat = false;
+    let mut depth = 0u32;
+
+    while let Some(char) = chars.next() {
+        match char {
+            '\\' => {
+                let char = chars
+                    .next()
+                    .ok_or_else(|| "trailing backslash".to_string())?;
+                push_literal(&mut result, char);
+                can_repeat = true;
+            }
+            '[' => {
+                translate_bracket(&mut chars, &mut result)?;
+                can_repeat = true;
+            }
+            '(' => {
+                depth += 1;
+                result.push('(');
+                can_repeat = false;
+            }
+            ')' => {
+                if depth == 0 {
+                    return Err("unmatched parenthesis".to_string());
+                }
+                depth -= 1;
+                result.push(')');
+                can_repeat = true;
+            }
+            '|' => {
+                result.push('|');
+                can_repeat = false;
+            }
+            '*' | '+' | '?' => {
+                if !can_repeat {
+                    return Err(format!("nothing to repeat before {char:?}"));
+                }
+                result.push(char);
+                can_repeat = false;
+            }
+            '{' => {
+                if let Some(interval) = parse_interval(&mut chars) {
+                    if !can_repeat {
+                        return Err("nothing to repeat before interval".to_string());
+                    }
+                    result.push_str(&interval);
+                    can_repeat = false;
+                } else {
+                    push_literal(&mut result, '{');
+                    can_repeat = true;
+                }
+            }
+            '^' => {
+                result.push_str(if match_start { "^" } else { NEVER_MATCH });
+                can_repeat = false;
+            }
+            '$' => {
+                result.push_str(if match_end { "$" } else { NEVER_MATCH });
+                can_repeat = false;
+            }
+            '.' => {
+                result.push_str("(?s:.)");
+                can_repeat = true;
+            }
+            _ => {
+                push_literal(&mut result, char);
+                can_repeat = true;
+            }
+        }
+    }
+
+    if depth == 0 {
+        Ok(result)
+    } else {
+        Err("unmatched parenthesis".to_string())
+    }
+}
+
+fn translate_bracket(chars: &mut Peekable<Chars<'_>>, result: &mut String) -> Result<(), String> {
+    result.push('[');
+    if chars.next_if_eq(&'^').is_some() {
+        result.push('^');
+    }
+
+    // A closing bracket at the start of the list is a literal
+    let mut prev_char = None;
+    let mut is_first = true;
+    loop {
+        let char = chars
+            .next()
+            .ok_or_else(|| "unterminated bracket expression".to_string())?;
+        let element = match char {
+            ']' if !is_first => {
+                break;
+            }
+            '[' => match chars.peek() {
+                Some(':') => {
+                    chars.next();
+                    let name = read_until(chars, ':')?;
+                    if !CHARACTER_CLASSES.contains(&name.as_str()) {
+                        return Err(format!("unknown character class {name:?}"));
+                    }
+                    result.push_str("[:");
+                    result.push_str(&name);
+                    result.push_str(":]");
+                    prev_char = None;
+                    is_first = false;
+                    continue;
+                }
+                Some('=' | '.') => {
+                    let delimiter = chars.next().unwrap();
+                    collating_element(chars, delimiter)?
+                }
+                _ => '[',
+            },
+            '-' if prev_char.is_some() && chars.peek().is_some_and(|&char| char != ']') => {
+                let start = prev_char.take().unwrap();
+                let end = match chars.next() {
+                    Some('[') if matches!(chars.peek(), Some('.' | '=')) => {
+                        let delimiter = chars.next().unwrap();
+                        collating_element(chars, delimiter)?
+                    }
+                    Some(char) => char,
+                    None => return Err("unterminated bracket expression".to_string()),
+                };
+                if end < start {
+                    return Err(format!("invalid range {start:?}-{end:?}"));
+                }
+                result.push('-');
+                push_literal(result, end);
+                is_first = false;
+                continue;
+            }
+            _ => char,
+        };
+        push_literal(result, element);
+        prev_char = Some(element);
+        is_first = false;
+    }
+
+    result.push(']');
+    Ok(())
+}
+
+fn read_until(chars: &mut Peekable<Chars<'_>>, delimiter: char) -> Result<String, String> {
+    let mut value = String::new();
+    while let Some(char) = chars.next() {
+        if char == delimiter && chars.next_if_eq(&']').is_some() {
+            return Ok(value);
+        }
+        value.push(char);
+    }
+    Err("unterminated bracket expression".to_string())
+}
+
+// Only single character collating elements and equivalence classes
+// are supported
+fn collating_element(chars: &mut Peekable<Chars<'_>>, delimiter: char) -> Result<char, String> {
+    let value = read_until(chars, delimiter)?;
+    let mut value = value.chars();
+    match (value.next(), value.next()) {
+        (Some(char), None) => Ok(char),
+        _ => Err("unsupported collating element".to_string()),
+    }
+}
+
+fn parse_interval(chars: &mut Peekable<Chars<'_>>) -> Option<String> {
+    let mut lookahead = chars.clone();
+    let mut interval = String::from("{");
+    let mut has_digits = false;
+    let mut has_comma = false;
+
+    loop {
+        match lookahead.next()? {
+            char @ '0'..='9' => {
+                has_digits = true;
+                interval.push(char);
+            }
+            ',' if has_digits && !has_comma => {
+                has_comma = true;
+                interval.push(',');
+            }
+            '}' if has_digits => {
+                interval.push('}');
+                break;
+            }
+            _ => return None,
+        }
+    }
+
+    *chars = lookahead;
+    Some(interval)
+}
+
+// Punctuation is always escaped, which also covers backslashes inside
+// bracket expressions as they are not special in POSIX
+fn push_literal(result: &mut String, char: char) {
+    if char.is_ascii_punctuation() {
+        result.push('\\');
+    }
+    result.push(char);
+}
@@ -0,0 +1,144 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::ops::Range;
+
+use crate::Compiler;
+
+use super::{
+    grammar::{instruction::CompilerState, SourcePosition},
+    CompileError, Value,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MailboxRename {
+    script: Vec<u8>,
+    renamed: Vec<SourcePosition>,
+    dynamic: Vec<SourcePosition>,
+}
+
+impl Compiler {
+    /// Rewrites the constant mailbox names used by `fileinto`, `:fcc` and
+    /// `mailboxexists` according to `renames`, a list of `(old, new)` names.
+    /// Mailbox names built from variables cannot be rewritten and are
+    /// reported instead.
+    pub fn rename_mailboxes(
+        &self,
+        script: &[u8],
+        renames: &[(&str, &str)],
+    ) -> Result<MailboxRename, CompileError> {
+        let mut state = CompilerState::new(self, script)?;
+        state.string_refs = Some(Vec::new());
+        state.parse_script()?;
+
+        let new_lines = script
+            .iter()
+            .enumerate()
+            .filter_map(|(pos, &ch)| (ch == b'\n').then_some(pos))
+            .collect::<Vec<_>>();
+        let mut result = MailboxRename {
+            script: Vec::with_capacity(script.len()),
+            renamed: Vec::new(),
+            dynamic: Vec::new(),
+        };
+        let mut last_pos = 0;
+
+        for string_ref in state.mailbox_refs {
+            let position = string_ref.position;
+            let name = match &string_ref.value {
+                Value::Text(text) => text.to_string(),
+                Value::Number(number) => number.to_string(),
+                _ => {
+                    result.dynamic.push(position);
+                    continue;
+                }
+            };
+            let Some((_, new_name)) = renames.iter().find(|(old_name, _)| *old_name == name) else {
+                continue;
+            };
+            let offset = match position.line_num {
+                1 => Some(position.line_pos),
+                line_num => new_lines
+                    .get(line_num - 2)
+                    .map(|line_start| line_start + position.line_pos),
+            };
+
+            if let Some(span) = offset
+                .and_then(|offset| string_span(script, offset))
+                .filter(|span| span.start >= last_pos)
+            {
+                result
+                    .script
+                    .extend_from_slice(&script[last_pos..span.start]);
+                result.script.push(b'"');
+                for ch in new_name.bytes() {
+                    if matches!(ch, b'"' | b'\\') {
+                        result.script.push(b'\\');
+                    }
+                    result.script.push(ch);
+                }
+                result.script.push(b'"');
+                result.renamed.push(position);
+                last_pos = span.end;
+            }
+        }
+        result.script.extend_from_slice(&script[last_pos..]);
+
+        Ok(result)
+    }
+}
+
+impl MailboxRename {
+    pub fn script(&self) -> &[u8] {
+        &self.script
+    }
+
+    pub fn into_script(self) -> Vec<u8> {
+        self.script
+    }
+
+    pub fn renamed(&self) -> &[SourcePosition] {
+        &self.renamed
+    }
+
+    pub fn dynamic(&self) -> &[SourcePosition] {
+        &self.dynamic
+    }
+
+    pub fn has_changes(&self) -> bool {
+        !self.renamed.is_empty()
+    }
+}
+
+fn string_span(script: &[u8], offset: usize) -> Option<Range<usize>> {
+    match script.get(offset)? {
+        b'"' => {
+            let mut is_escaped = false;
+            for (pos, &ch) in script.iter().enumerate().skip(offset + 1) {
+                match ch {
+                    b'"' if !is_escaped => return Some(offset..pos + 1),
+                    b'\\' => is_escaped = !is_escaped,
+                    _ => is_escaped = false,
+                }
+            }
+            None
+        }
+        b':' if offset >= 4 && script[offset - 4..offset].eq_ignore_ascii_case(b"text") => {
+            let mut pos = offset + script[offset..].iter().position(|&ch| ch == b'\n')? + 1;
+            loop {
+                let line_end = script[pos..]
+                    .iter()
+                    .position(|&ch| ch == b'\n')
+                    .map(|end| pos + end)?;
+                if matches!(&script[pos..line_end], b"." | b".\r") {
+                    return Some(offset - 4..line_end + 1);
+                }
+                pos = line_end + 1;
+            }
+        }
+        _ => None,
+    }
+}
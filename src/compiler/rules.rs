@@ -0,0 +1,687 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::fmt::Display;
+
+use crate::Sieve;
+
+use super::{
+    format::{write_number, write_string},
+    grammar::{
+        actions::{
+            action_flags::Action as FlagAction,
+            action_mime::MimeOpts,
+            action_redirect::{ByTime, Notify, Ret},
+        },
+        instruction::Instruction,
+        test::Test,
+        tests::{test_address::TestAddress, test_header::TestHeader},
+        AddressPart, Capability, Comparator, MatchType,
+    },
+    Value,
+};
+
+/// A list of simple filtering rules that can be converted to and from Sieve.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RuleSet {
+    rules: Vec<Rule>,
+}
+
+/// A rule executes its actions when all (or any) of its conditions match.
+/// A rule without conditions always matches.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Rule {
+    conditions: Vec<Condition>,
+    match_any: bool,
+    actions: Vec<RuleAction>,
+    stop: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    Header {
+        names: Vec<String>,
+        match_type: RuleMatch,
+        keys: Vec<String>,
+        negate: bool,
+    },
+    Address {
+        names: Vec<String>,
+        part: AddressPart,
+        match_type: RuleMatch,
+        keys: Vec<String>,
+        negate: bool,
+    },
+    Exists {
+        names: Vec<String>,
+        negate: bool,
+    },
+    Size {
+        over: bool,
+        limit: usize,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleError {
+    rule: usize,
+    error_type: RuleErrorType,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuleErrorType {
+    EmptyList,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RuleMatch {
+    Is,
+    Contains,
+    Matches,
+    Regex,
+}
+
+/// Actions run in order, flag changes only apply to the `Keep` and
+/// `FileInto` actions that follow them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuleAction {
+    Keep,
+    Discard,
+    FileInto {
+        mailbox: String,
+        copy: bool,
+        create: bool,
+    },
+    Redirect {
+        address: String,
+        copy: bool,
+    },
+    Reject {
+        reason: String,
+    },
+    AddFlags(Vec<String>),
+    RemoveFlags(Vec<String>),
+    SetFlags(Vec<String>),
+}
+
+impl RuleSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn add_rule(&mut self, rule: Rule) {
+        self.rules.push(rule);
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn rules_mut(&mut self) -> &mut Vec<Rule> {
+        &mut self.rules
+    }
+
+    /// Returns the capabilities required by the rules, in order of first use.
+    pub fn capabilities(&self) -> Vec<Capability> {
+        let mut capabilities = Vec::new();
+        for capability in self.rules.iter().flat_map(|rule| rule.capabilities()) {
+            if !capabilities.contains(&capability) {
+                capabilities.push(capability);
+            }
+        }
+        capabilities
+    }
+
+    /// Emits the rules as a Sieve script. Fails if a condition or flag
+    /// action has an empty list, which Sieve cannot express.
+    pub fn to_sieve(&self) -> Result<String, RuleError> {
+        for (rule_num, rule) in self.rules.iter().enumerate() {
+            if !rule.is_valid() {
+                return Err(RuleError {
+                    rule: rule_num,
+                    error_type: RuleErrorType::EmptyList,
+                });
+            }
+        }
+
+        let mut out = String::new();
+
+        let capabilities = self.capabilities();
+        if !capabilities.is_empty() {
+            out.push_str("require ");
+            write_strings(
+                &mut out,
+                capabilities
+                    .iter()
+                    .map(|capability| capability.to_string())
+                    .collect::<Vec<_>>()
+                    .as_slice(),
+            );
+            out.push_str(";\n");
+        }
+
+        for rule in &self.rules {
+            if !out.is_empty() {
+                out.push('\n');
+            }
+            rule.write(&mut out);
+        }
+
+        Ok(out)
+    }
+
+    /// Recognizes a compiled script that only contains rules expressible by
+    /// this builder, as produced by [`RuleSet::to_sieve`].
+    pub fn from_sieve(sieve: &Sieve) -> Option<Self> {
+        let instructions = &sieve.instructions;
+        let mut rules = Vec::new();
+        let mut pos = 0;
+
+        while let Some(Instruction::Require(_)) = instructions.get(pos) {
+            pos += 1;
+        }
+
+        while pos < instructions.len() {
+            let mut rule = Rule::default();
+            let mut tests = Vec::new();
+
+            // anyof() and allof() compile to a chain of tests that jump to
+            // the last test's Jz, which in turn jumps to the end of the block
+            let (chain_end, match_any) = match instructions.get(pos + 1)? {
+                Instruction::Jnz(jmp_pos) => (*jmp_pos, true),
+                Instruction::Jz(jmp_pos)
+                    if *jmp_pos > pos + 1
+                        && matches!(instructions.get(*jmp_pos), Some(Instruction::Jz(_))) =>
+                {
+                    (*jmp_pos, false)
+                }
+                Instruction::Jz(_) => (pos + 1, false),
+                _ => return None,
+            };
+            loop {
+                let Instruction::Test(test) = instructions.get(pos)? else {
+                    return None;
+                };
+                tests.push(test);
+                if pos + 1 == chain_end {
+                    break;
+                }
+                match instructions.get(pos + 1)? {
+                    Instruction::Jnz(jmp_pos) if match_any && *jmp_pos == chain_end => (),
+                    Instruction::Jz(jmp_pos) if !match_any && *jmp_pos == chain_end => (),
+                    _ => return None,
+                }
+                pos += 2;
+            }
+            let Instruction::Jz(end_pos) = instructions.get(chain_end)? else {
+                return None;
+            };
+            let end_pos = *end_pos;
+            pos = chain_end + 1;
+            rule.match_any = match_any;
+
+            // Conditions
+            if !matches!(tests.as_slice(), [Test::True]) {
+                for test in tests {
+                    rule.conditions.push(Condition::from_test(test)?);
+                }
+            }
+
+            // Actions
+            if end_pos < pos || end_pos > instructions.len() {
+                return None;
+            }
+            while pos < end_pos {
+                match &instructions[pos] {
+                    Instruction::Stop if pos + 1 == end_pos => {
+                        rule.stop = true;
+                    }
+                    instruction => rule
+                        .actions
+                        .push(RuleAction::from_instruction(instruction)?),
+                }
+                pos += 1;
+            }
+
+            rules.push(rule);
+        }
+
+        Some(RuleSet { rules })
+    }
+}
+
+impl Rule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_condition(mut self, condition: Condition) -> Self {
+        self.conditions.push(condition);
+        self
+    }
+
+    pub fn with_match_any(mut self, match_any: bool) -> Self {
+        self.match_any = match_any;
+        self
+    }
+
+    pub fn with_action(mut self, action: RuleAction) -> Self {
+        self.actions.push(action);
+        self
+    }
+
+    pub fn with_stop(mut self, stop: bool) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    pub fn set_match_any(&mut self, match_any: bool) {
+        self.match_any = match_any;
+    }
+
+    pub fn set_stop(&mut self, stop: bool) {
+        self.stop = stop;
+    }
+
+    pub fn conditions(&self) -> &[Condition] {
+        &self.conditions
+    }
+
+    pub fn conditions_mut(&mut self) -> &mut Vec<Condition> {
+        &mut self.conditions
+    }
+
+    pub fn match_any(&self) -> bool {
+        self.match_any
+    }
+
+    pub fn actions(&self) -> &[RuleAction] {
+        &self.actions
+    }
+
+    pub fn actions_mut(&mut self) -> &mut Vec<RuleAction> {
+        &mut self.actions
+    }
+
+    pub fn stop(&self) -> bool {
+        self.stop
+    }
+
+    fn capabilities(&self) -> impl Iterator<Item = Capability> + '_ {
+        self.conditions
+            .iter()
+            .flat_map(|condition| condition.capabilities())
+            .chain(self.actions.iter().flat_map(|action| action.capabilities()))
+    }
+
+    fn is_valid(&self) -> bool {
+        self.conditions.iter().all(|condition| condition.is_valid())
+            && self.actions.iter().all(|action| action.is_valid())
+    }
+
+    fn write(&self, out: &mut String) {
+        out.push_str("if ");
+        match self.conditions.as_slice() {
+            [] => out.push_str("true"),
+            [condition] => condition.write(out),
+            conditions => {
+                out.push_str(if self.match_any { "anyof (" } else { "allof (" });
+                for (pos, condition) in conditions.iter().enumerate() {
+                    if pos > 0 {
+                        out.push_str(",\n          ");
+                    }
+                    condition.write(out);
+                }
+                out.push(')');
+            }
+        }
+        out.push_str(" {\n");
+
+        for action in &self.actions {
+            out.push_str("    ");
+            action.write(out);
+            out.push_str(";\n");
+        }
+        if self.stop {
+            out.push_str("    stop;\n");
+        }
+        out.push_str("}\n");
+    }
+}
+
+impl Condition {
+    fn capabilities(&self) -> Vec<Capability> {
+        let mut capabilities = Vec::new();
+        match self {
+            Condition::Header { match_type, .. } => {
+                capabilities.extend(match_type.capability());
+            }
+            Condition::Address {
+                part, match_type, ..
+            } => {
+                if matches!(part, AddressPart::User | AddressPart::Detail) {
+                    capabilities.push(Capability::SubAddress);
+                }
+                capabilities.extend(match_type.capability());
+            }
+            Condition::Exists { .. } | Condition::Size { .. } => (),
+        }
+        capabilities
+    }
+
+    fn is_valid(&self) -> bool {
+        match self {
+            Condition::Header { names, keys, .. } | Condition::Address { names, keys, .. } => {
+                !names.is_empty() && !keys.is_empty()
+            }
+            Condition::Exists { names, .. } => !names.is_empty(),
+            Condition::Size { .. } => true,
+        }
+    }
+
+    fn write(&self, out: &mut String) {
+        match self {
+            Condition::Header {
+                names,
+                match_type,
+                keys,
+                negate,
+            } => {
+                if *negate {
+                    out.push_str("not ");
+                }
+                out.push_str("header ");
+                match_type.write(out);
+                write_strings(out, names);
+                out.push(' ');
+                write_strings(out, keys);
+            }
+            Condition::Address {
+                names,
+                part,
+                match_type,
+                keys,
+                negate,
+            } => {
+                if *negate {
+                    out.push_str("not ");
+                }
+                out.push_str("address ");
+                match part {
+                    AddressPart::All => (),
+                    AddressPart::LocalPart => out.push_str(":localpart "),
+                    AddressPart::Domain => out.push_str(":domain "),
+                    AddressPart::User => out.push_str(":user "),
+                    AddressPart::Detail => out.push_str(":detail "),
+                    AddressPart::Name => out.push_str(":name "),
+                }
+                match_type.write(out);
+                write_strings(out, names);
+                out.push(' ');
+                write_strings(out, keys);
+            }
+            Condition::Exists { names, negate } => {
+                if *negate {
+                    out.push_str("not ");
+                }
+                out.push_str("exists ");
+                write_strings(out, names);
+            }
+            Condition::Size { over, limit } => {
+                out.push_str(if *over { "size :over " } else { "size :under " });
+                write_number(out, *limit);
+            }
+        }
+    }
+
+    fn from_test(test: &Test) -> Option<Self> {
+        match test {
+            Test::Header(TestHeader {
+                header_list,
+                key_list,
+                key_set: _,
+                match_type,
+                comparator: Comparator::AsciiCaseMap,
+                index: None,
+                mime_opts: MimeOpts::None,
+                mime_anychild: false,
+                is_not,
+            }) => Some(Condition::Header {
+                names: constant_strings(header_list)?,
+                match_type: RuleMatch::from_match_type(match_type)?,
+                keys: constant_strings(key_list)?,
+                negate: *is_not,
+            }),
+            Test::Address(TestAddress {
+                header_list,
+                key_list,
+                key_set: _,
+                address_part,
+                match_type,
+                comparator: Comparator::AsciiCaseMap,
+                index: None,
+                mime_anychild: false,
+                is_not,
+            }) => Some(Condition::Address {
+                names: constant_strings(header_list)?,
+                part: *address_part,
+                match_type: RuleMatch::from_match_type(match_type)?,
+                keys: constant_strings(key_list)?,
+                negate: *is_not,
+            }),
+            Test::Exists(test) if !test.mime_anychild => Some(Condition::Exists {
+                names: constant_strings(&test.header_names)?,
+                negate: test.is_not,
+            }),
+            Test::Size(test) if !test.is_not => Some(Condition::Size {
+                over: test.over,
+                limit: test.limit,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl RuleMatch {
+    fn capability(&self) -> Option<Capability> {
+        match self {
+            RuleMatch::Regex => Some(Capability::Regex),
+            RuleMatch::Is | RuleMatch::Contains | RuleMatch::Matches => None,
+        }
+    }
+
+    fn write(&self, out: &mut String) {
+        out.push_str(match self {
+            RuleMatch::Is => ":is ",
+            RuleMatch::Contains => ":contains ",
+            RuleMatch::Matches => ":matches ",
+            RuleMatch::Regex => ":regex ",
+        });
+    }
+
+    fn from_match_type(match_type: &MatchType) -> Option<Self> {
+        match match_type {
+            MatchType::Is => Some(RuleMatch::Is),
+            MatchType::Contains => Some(RuleMatch::Contains),
+            MatchType::Matches(0) => Some(RuleMatch::Matches),
+            MatchType::Regex(0) => Some(RuleMatch::Regex),
+            _ => None,
+        }
+    }
+}
+
+impl RuleAction {
+    fn capabilities(&self) -> Vec<Capability> {
+        match self {
+            RuleAction::Keep | RuleAction::Discard => vec![],
+            RuleAction::FileInto { copy, create, .. } => [
+                Some(Capability::FileInto),
+                copy.then_some(Capability::Copy),
+                create.then_some(Capability::Mailbox),
+            ]
+            .into_iter()
+            .flatten()
+            .collect(),
+            RuleAction::Redirect { copy, .. } => {
+                copy.then_some(Capability::Copy).into_iter().collect()
+            }
+            RuleAction::Reject { .. } => vec![Capability::Reject],
+            RuleAction::AddFlags(_) | RuleAction::RemoveFlags(_) | RuleAction::SetFlags(_) => {
+                vec![Capability::Imap4Flags]
+            }
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        match self {
+            RuleAction::AddFlags(flags)
+            | RuleAction::RemoveFlags(flags)
+            | RuleAction::SetFlags(flags) => !flags.is_empty(),
+            _ => true,
+        }
+    }
+
+    fn write(&self, out: &mut String) {
+        match self {
+            RuleAction::Keep => out.push_str("keep"),
+            RuleAction::Discard => out.push_str("discard"),
+            RuleAction::FileInto {
+                mailbox,
+                copy,
+                create,
+            } => {
+                out.push_str("fileinto ");
+                if *copy {
+                    out.push_str(":copy ");
+                }
+                if *create {
+                    out.push_str(":create ");
+                }
+                write_string(out, mailbox.as_bytes());
+            }
+            RuleAction::Redirect { address, copy } => {
+                out.push_str("redirect ");
+                if *copy {
+                    out.push_str(":copy ");
+                }
+                write_string(out, address.as_bytes());
+            }
+            RuleAction::Reject { reason } => {
+                out.push_str("reject ");
+                write_string(out, reason.as_bytes());
+            }
+            RuleAction::AddFlags(flags) => {
+                out.push_str("addflag ");
+                write_strings(out, flags);
+            }
+            RuleAction::RemoveFlags(flags) => {
+                out.push_str("removeflag ");
+                write_strings(out, flags);
+            }
+            RuleAction::SetFlags(flags) => {
+                out.push_str("setflag ");
+                write_strings(out, flags);
+            }
+        }
+    }
+
+    fn from_instruction(instruction: &Instruction) -> Option<Self> {
+        match instruction {
+            Instruction::Keep(keep) if keep.flags.is_empty() => Some(RuleAction::Keep),
+            Instruction::Discard => Some(RuleAction::Discard),
+            Instruction::FileInto(fileinto)
+                if fileinto.flags.is_empty()
+                    && fileinto.mailbox_id.is_none()
+                    && fileinto.special_use.is_none() =>
+            {
+                Some(RuleAction::FileInto {
+                    mailbox: constant_string(&fileinto.folder)?,
+                    copy: fileinto.copy,
+                    create: fileinto.create,
+                })
+            }
+            Instruction::Redirect(redirect)
+                if !redirect.list
+                    && matches!(redirect.by_time, ByTime::None)
+                    && matches!(redirect.notify, Notify::Default)
+                    && matches!(redirect.return_of_content, Ret::Default) =>
+            {
+                Some(RuleAction::Redirect {
+                    address: constant_string(&redirect.address)?,
+                    copy: redirect.copy,
+                })
+            }
+            Instruction::Reject(reject) if !reject.ereject => Some(RuleAction::Reject {
+                reason: constant_string(&reject.reason)?,
+            }),
+            Instruction::EditFlags(edit_flags) if edit_flags.name.is_none() => {
+                let flags = constant_strings(&edit_flags.flags)?;
+                Some(match edit_flags.action {
+                    FlagAction::Add => RuleAction::AddFlags(flags),
+                    FlagAction::Remove => RuleAction::RemoveFlags(flags),
+                    FlagAction::Set => RuleAction::SetFlags(flags),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl RuleError {
+    pub fn rule(&self) -> usize {
+        self.rule
+    }
+
+    pub fn error_type(&self) -> &RuleErrorType {
+        &self.error_type
+    }
+}
+
+impl Display for RuleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.error_type {
+            RuleErrorType::EmptyList => write!(f, "Empty list"),
+        }?;
+
+        write!(f, " in rule {}.", self.rule)
+    }
+}
+
+impl std::error::Error for RuleError {}
+
+fn constant_string(value: &Value) -> Option<String> {
+    match value {
+        Value::Text(text) => Some(text.to_string()),
+        Value::Number(number) => Some(number.to_string()),
+        Value::Regex(regex) => Some(regex.expr.clone()),
+        Value::Glob(glob) => Some(glob.expr.clone()),
+        Value::Variable(_) | Value::List(_) => None,
+    }
+}
+
+fn constant_strings(values: &[Value]) -> Option<Vec<String>> {
+    values.iter().map(constant_string).collect()
+}
+
+fn write_strings(out: &mut String, strings: &[String]) {
+    if let [string] = strings {
+        write_string(out, string.as_bytes());
+    } else {
+        out.push('[');
+        for (pos, string) in strings.iter().enumerate() {
+            if pos > 0 {
+                out.push_str(", ");
+            }
+            write_string(out, string.as_bytes());
+        }
+        out.push(']');
+    }
+}
@@ -0,0 +1,548 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::fmt::Display;
+
+use crate::{
+    compiler::{
+        grammar::{
+            actions::{action_mime::MimeOpts, action_redirect::ByTime},
+            expr::Expression,
+            instruction::Instruction,
+            test::Test,
+            tests::test_duplicate::DupMatch,
+            MatchType,
+        },
+        Value, VariableType,
+    },
+    FileCarbonCopy, Metadata, Sieve, MAX_LOCAL_VARIABLES, MAX_MATCH_VARIABLES,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    position: usize,
+    error_type: VerifyErrorType,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyErrorType {
+    TooManyVariables,
+    InvalidJump(usize),
+    InvalidLocalVariable(usize),
+    InvalidMatchVariable(usize),
+    InvalidExpression,
+    InvalidInstruction,
+}
+
+struct Verifier {
+    num_instructions: usize,
+    num_vars: usize,
+    num_match_vars: usize,
+}
+
+impl Sieve {
+    /// Checks the invariants the interpreter relies on, such as jump targets
+    /// and variable indexes. Scripts loaded from untrusted storage should be
+    /// verified before they are executed.
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        if self.num_vars > MAX_LOCAL_VARIABLES || self.num_match_vars > MAX_MATCH_VARIABLES {
+            return Err(VerifyError {
+                position: 0,
+                error_type: VerifyErrorType::TooManyVariables,
+            });
+        }
+
+        let verifier = Verifier {
+            num_instructions: self.instructions.len(),
+            num_vars: self.num_vars as usize,
+            num_match_vars: self.num_match_vars as usize,
+        };
+        let mut part_loops = Vec::new();
+        for (position, instruction) in self.instructions.iter().enumerate() {
+            verifier
+                .instruction(position, instruction)
+                .and_then(|_| {
+                    verifier.part_loops(
+                        position,
+                        instruction,
+                        self.instructions.get(position + 1),
+                        &mut part_loops,
+                    )
+                })
+                .map_err(|error_type| VerifyError {
+                    position,
+                    error_type,
+                })?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Verifier {
+    fn instruction(
+        &self,
+        position: usize,
+        instruction: &Instruction,
+    ) -> Result<(), VerifyErrorType> {
+        match instruction {
+            Instruction::Jz(target) | Instruction::Jnz(target) => {
+                self.jump(position, *target, true)?;
+            }
+            Instruction::Jmp(target) => {
+                self.jump(position, *target, false)?;
+            }
+            Instruction::ForEveryPart(v) => {
+                self.jump(position, v.jz_pos, true)?;
+            }
+            Instruction::Clear(v) => {
+                if (v.local_vars_idx as usize + v.local_vars_num as usize) > self.num_vars {
+                    return Err(VerifyErrorType::InvalidInstruction);
+                }
+                self.match_positions(v.match_vars)?;
+            }
+            Instruction::Test(v) => self.test(v)?,
+            Instruction::Keep(k) => k.flags.verify_vars(self)?,
+            Instruction::FileInto(v) => {
+                v.folder.verify_vars(self)?;
+                v.flags.verify_vars(self)?;
+                v.mailbox_id.verify_vars(self)?;
+                v.special_use.verify_vars(self)?;
+            }
+            Instruction::Redirect(v) => {
+                v.address.verify_vars(self)?;
+                v.by_time.verify_vars(self)?;
+            }
+            Instruction::Replace(v) => {
+                v.subject.verify_vars(self)?;
+                v.from.verify_vars(self)?;
+                v.replacement.verify_vars(self)?;
+            }
+            Instruction::Enclose(v) => {
+                v.subject.verify_vars(self)?;
+                v.headers.verify_vars(self)?;
+                v.value.verify_vars(self)?;
+            }
+            Instruction::ExtractText(v) => {
+                v.name.verify_vars(self)?;
+            }
+            Instruction::Convert(v) => {
+                v.from_media_type.verify_vars(self)?;
+                v.to_media_type.verify_vars(self)?;
+                v.transcoding_params.verify_vars(self)?;
+            }
+            Instruction::AddHeader(v) => {
+                v.field_name.verify_vars(self)?;
+                v.value.verify_vars(self)?;
+            }
+            Instruction::DeleteHeader(v) => {
+                self.match_type(&v.match_type)?;
+                v.field_name.verify_vars(self)?;
+                v.value_patterns.verify_vars(self)?;
+            }
+            Instruction::Set(v) => {
+                v.name.verify_vars(self)?;
+                v.value.verify_vars(self)?;
+            }
+            Instruction::Let(v) => {
+                v.name.verify_vars(self)?;
+                self.expression(&v.expr)?;
+            }
+            Instruction::While(v) => {
+                self.expression(&v.expr)?;
+                self.jump(position, v.jz_pos, true)?;
+            }
+            Instruction::Eval(v) => {
+                self.expression(v)?;
+            }
+            Instruction::Notify(v) => {
+                v.from.verify_vars(self)?;
+                v.importance.verify_vars(self)?;
+                v.options.verify_vars(self)?;
+                v.message.verify_vars(self)?;
+                v.fcc.verify_vars(self)?;
+                v.method.verify_vars(self)?;
+            }
+            Instruction::Reject(v) => {
+                v.reason.verify_vars(self)?;
+            }
+            Instruction::Vacation(v) => {
+                v.subject.verify_vars(self)?;
+                v.from.verify_vars(self)?;
+                v.fcc.verify_vars(self)?;
+                v.reason.verify_vars(self)?;
+            }
+            Instruction::Error(v) => {
+                v.message.verify_vars(self)?;
+            }
+            Instruction::EditFlags(v) => {
+                v.name.verify_vars(self)?;
+                v.flags.verify_vars(self)?;
+            }
+            Instruction::Include(v) => {
+                v.value.verify_vars(self)?;
+            }
+            #[cfg(test)]
+            Instruction::TestCmd(v) => {
+                v.verify_vars(self)?;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Tracks the end of the `foreverypart` loops enclosing `position`, so
+    /// that pops never exceed the number of part iterators pushed.
+    fn part_loops(
+        &self,
+        position: usize,
+        instruction: &Instruction,
+        next_instruction: Option<&Instruction>,
+        loop_ends: &mut Vec<usize>,
+    ) -> Result<(), VerifyErrorType> {
+        while loop_ends.last().is_some_and(|end| *end <= position) {
+            loop_ends.pop();
+        }
+
+        match instruction {
+            Instruction::ForEveryPartPush => match next_instruction {
+                Some(Instruction::ForEveryPart(v))
+                    if v.jz_pos > position + 1
+                        && loop_ends.last().is_none_or(|end| v.jz_pos <= *end) =>
+                {
+                    loop_ends.push(v.jz_pos);
+                }
+                _ => return Err(VerifyErrorType::InvalidInstruction),
+            },
+            Instruction::ForEveryPartPop(num_pops)
+                if *num_pops == 0 || *num_pops > loop_ends.len() =>
+            {
+                return Err(VerifyErrorType::InvalidInstruction);
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn test(&self, test: &Test) -> Result<(), VerifyErrorType> {
+        match test {
+            Test::Address(v) => {
+                self.match_type(&v.match_type)?;
+                v.header_list.verify_vars(self)?;
+                v.key_list.verify_vars(self)?;
+            }
+            Test::Envelope(v) => {
+                self.match_type(&v.match_type)?;
+                v.key_list.verify_vars(self)?;
+            }
+            Test::Exists(v) => {
+                v.header_names.verify_vars(self)?;
+            }
+            Test::Header(v) => {
+                self.match_type(&v.match_type)?;
+                v.key_list.verify_vars(self)?;
+                v.header_list.verify_vars(self)?;
+                v.mime_opts.verify_vars(self)?;
+            }
+            Test::Body(v) => {
+                self.match_type(&v.match_type)?;
+                v.key_list.verify_vars(self)?;
+            }
+            Test::Convert(v) => {
+                v.from_media_type.verify_vars(self)?;
+                v.to_media_type.verify_vars(self)?;
+                v.transcoding_params.verify_vars(self)?;
+            }
+            Test::Date(v) => {
+                self.match_type(&v.match_type)?;
+                v.key_list.verify_vars(self)?;
+                v.header_name.verify_vars(self)?;
+            }
+            Test::CurrentDate(v) => {
+                self.match_type(&v.match_type)?;
+                v.key_list.verify_vars(self)?;
+            }
+            Test::Duplicate(v) => {
+                v.handle.verify_vars(self)?;
+                v.dup_match.verify_vars(self)?;
+            }
+            Test::String(v) => {
+                self.match_type(&v.match_type)?;
+                v.source.verify_vars(self)?;
+                v.key_list.verify_vars(self)?;
+            }
+            Test::Environment(v) => {
+                self.match_type(&v.match_type)?;
+                v.source.verify_vars(self)?;
+                v.key_list.verify_vars(self)?;
+            }
+            Test::NotifyMethodCapability(v) => {
+                self.match_type(&v.match_type)?;
+                v.key_list.verify_vars(self)?;
+                v.notification_capability.verify_vars(self)?;
+                v.notification_uri.verify_vars(self)?;
+            }
+            Test::ValidNotifyMethod(v) => {
+                v.notification_uris.verify_vars(self)?;
+            }
+            Test::ValidExtList(v) => {
+                v.list_names.verify_vars(self)?;
+            }
+            Test::HasFlag(v) => {
+                self.match_type(&v.match_type)?;
+                v.variable_list.verify_vars(self)?;
+                v.flags.verify_vars(self)?;
+            }
+            Test::MailboxExists(v) => {
+                v.mailbox_names.verify_vars(self)?;
+            }
+            Test::Metadata(v) => {
+                self.match_type(&v.match_type)?;
+                v.key_list.verify_vars(self)?;
+                v.medatata.verify_vars(self)?;
+            }
+            Test::MetadataExists(v) => {
+                v.annotation_names.verify_vars(self)?;
+                v.mailbox.verify_vars(self)?;
+            }
+            Test::MailboxIdExists(v) => {
+                v.mailbox_ids.verify_vars(self)?;
+            }
+            Test::SpamTest(v) => {
+                self.match_type(&v.match_type)?;
+                v.value.verify_vars(self)?;
+            }
+            Test::VirusTest(v) => {
+                self.match_type(&v.match_type)?;
+                v.value.verify_vars(self)?;
+            }
+            Test::SpecialUseExists(v) => {
+                v.mailbox.verify_vars(self)?;
+                v.attributes.verify_vars(self)?;
+            }
+            Test::Vacation(v) => {
+                v.addresses.verify_vars(self)?;
+                v.handle.verify_vars(self)?;
+                v.reason.verify_vars(self)?;
+            }
+            #[cfg(test)]
+            Test::TestCmd { arguments, .. } => {
+                arguments.verify_vars(self)?;
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    fn jump(&self, position: usize, target: usize, forward: bool) -> Result<(), VerifyErrorType> {
+        // Jumping past the last instruction ends the script
+        if target <= self.num_instructions
+            && (target > position || (!forward && target != position))
+        {
+            Ok(())
+        } else {
+            Err(VerifyErrorType::InvalidJump(target))
+        }
+    }
+
+    fn match_type(&self, match_type: &MatchType) -> Result<(), VerifyErrorType> {
+        match match_type {
+            MatchType::Matches(positions) | MatchType::Regex(positions) => {
+                self.match_positions(*positions)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn match_positions(&self, positions: u64) -> Result<(), VerifyErrorType> {
+        match positions.checked_ilog2() {
+            Some(index) if index as usize >= self.num_match_vars => {
+                Err(VerifyErrorType::InvalidMatchVariable(index as usize))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Simulates the evaluation stack, which must never underflow and has to
+    /// hold exactly one item once a non-empty expression is evaluated.
+    fn expression(&self, expr: &[Expression]) -> Result<(), VerifyErrorType> {
+        let mut depth = 0usize;
+        let mut jumps: Vec<(usize, usize)> = Vec::new();
+
+        for (pos, item) in expr.iter().enumerate() {
+            // Short-circuit jumps have to land with the same stack depth
+            if jumps
+                .iter()
+                .any(|(target, target_depth)| *target == pos && *target_depth != depth)
+            {
+                return Err(VerifyErrorType::InvalidExpression);
+            }
+
+            let (pops, pushes) = match item {
+                Expression::Variable(var) => {
+                    var.verify_vars(self)?;
+                    (0, 1)
+                }
+                Expression::Constant(_) => (0, 1),
+                Expression::UnaryOperator(_) => (1, 1),
+                Expression::BinaryOperator(_) | Expression::ArrayAccess => (2, 1),
+                Expression::Function { num_args, .. } => (*num_args as usize, 1),
+                Expression::ArrayBuild(num_items) => (*num_items as usize, 1),
+                Expression::JmpIf { pos: offset, .. } => {
+                    let target = pos + 1 + *offset as usize;
+                    if depth == 0 || target > expr.len() {
+                        return Err(VerifyErrorType::InvalidExpression);
+                    }
+                    jumps.push((target, depth));
+                    (0, 0)
+                }
+            };
+            depth = depth
+                .checked_sub(pops)
+                .ok_or(VerifyErrorType::InvalidExpression)?
+                + pushes;
+        }
+
+        if (depth == 1 || expr.is_empty())
+            && jumps
+                .iter()
+                .all(|(target, target_depth)| *target != expr.len() || *target_depth == depth)
+        {
+            Ok(())
+        } else {
+            Err(VerifyErrorType::InvalidExpression)
+        }
+    }
+}
+
+trait VerifyVars {
+    fn verify_vars(&self, verifier: &Verifier) -> Result<(), VerifyErrorType>;
+}
+
+impl VerifyVars for VariableType {
+    fn verify_vars(&self, verifier: &Verifier) -> Result<(), VerifyErrorType> {
+        match self {
+            VariableType::Local(id) if *id >= verifier.num_vars => {
+                Err(VerifyErrorType::InvalidLocalVariable(*id))
+            }
+            VariableType::Match(id) if *id >= verifier.num_match_vars => {
+                Err(VerifyErrorType::InvalidMatchVariable(*id))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl VerifyVars for Value {
+    fn verify_vars(&self, verifier: &Verifier) -> Result<(), VerifyErrorType> {
+        match self {
+            Value::Variable(var) => var.verify_vars(verifier),
+            Value::List(items) => items.verify_vars(verifier),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<T: VerifyVars> VerifyVars for Option<T> {
+    fn verify_vars(&self, verifier: &Verifier) -> Result<(), VerifyErrorType> {
+        if let Some(value) = self {
+            value.verify_vars(verifier)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<T: VerifyVars> VerifyVars for Vec<T> {
+    fn verify_vars(&self, verifier: &Verifier) -> Result<(), VerifyErrorType> {
+        self.iter().try_for_each(|item| item.verify_vars(verifier))
+    }
+}
+
+impl VerifyVars for FileCarbonCopy<Value> {
+    fn verify_vars(&self, verifier: &Verifier) -> Result<(), VerifyErrorType> {
+        self.mailbox.verify_vars(verifier)?;
+        self.mailbox_id.verify_vars(verifier)?;
+        self.flags.verify_vars(verifier)?;
+        self.special_use.verify_vars(verifier)
+    }
+}
+
+impl VerifyVars for ByTime<Value> {
+    fn verify_vars(&self, verifier: &Verifier) -> Result<(), VerifyErrorType> {
+        if let ByTime::Absolute { alimit, .. } = self {
+            alimit.verify_vars(verifier)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl VerifyVars for Metadata<Value> {
+    fn verify_vars(&self, verifier: &Verifier) -> Result<(), VerifyErrorType> {
+        match self {
+            Metadata::Mailbox { name, annotation } => {
+                name.verify_vars(verifier)?;
+                annotation.verify_vars(verifier)
+            }
+            Metadata::Server { annotation } => annotation.verify_vars(verifier),
+        }
+    }
+}
+
+impl VerifyVars for DupMatch {
+    fn verify_vars(&self, verifier: &Verifier) -> Result<(), VerifyErrorType> {
+        match self {
+            DupMatch::Header(header) => header.verify_vars(verifier),
+            DupMatch::UniqueId(unique_id) => unique_id.verify_vars(verifier),
+            DupMatch::Default => Ok(()),
+        }
+    }
+}
+
+impl VerifyVars for MimeOpts<Value> {
+    fn verify_vars(&self, verifier: &Verifier) -> Result<(), VerifyErrorType> {
+        if let MimeOpts::Param(value) = self {
+            value.verify_vars(verifier)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl VerifyError {
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn error_type(&self) -> &VerifyErrorType {
+        &self.error_type
+    }
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.error_type {
+            VerifyErrorType::TooManyVariables => write!(f, "Too many variables"),
+            VerifyErrorType::InvalidJump(target) => write!(f, "Invalid jump to {target}"),
+            VerifyErrorType::InvalidLocalVariable(id) => {
+                write!(f, "Local variable {id} out of range")
+            }
+            VerifyErrorType::InvalidMatchVariable(id) => {
+                write!(f, "Match variable {id} out of range")
+            }
+            VerifyErrorType::InvalidExpression => write!(f, "Invalid expression"),
+            VerifyErrorType::InvalidInstruction => write!(f, "Invalid instruction"),
+        }?;
+
+        write!(f, " at instruction {}.", self.position)
+    }
+}
+
+impl std::error::Error for VerifyError {}
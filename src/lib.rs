@@ -6,16 +6,20 @@
 
 #![doc = include_str!("../README.md")]
 
-use std::{borrow::Cow, sync::Arc, vec::IntoIter};
+use std::{borrow::Cow, cmp::Ordering, sync::Arc, vec::IntoIter};
 
 use ahash::{AHashMap, AHashSet};
 use compiler::grammar::{
     actions::action_redirect::{ByTime, Notify, Ret},
     instruction::Instruction,
-    Capability,
+    tests::test_date::Zone,
+    Capability, SourcePosition,
 };
 use mail_parser::{HeaderName, Message};
-use runtime::{context::ScriptStack, Variable};
+use runtime::{
+    context::ScriptStack, regex_cache::RegexCache, result::FilterResult, text_cache::TextCache,
+    trace::Trace, Variable,
+};
 
 pub mod compiler;
 pub mod runtime;
@@ -34,8 +38,12 @@ pub(crate) const MAX_LOCAL_VARIABLES: u32 = 256;
 )]
 pub struct Sieve {
     instructions: Vec<Instruction>,
+    positions: Vec<SourcePosition>,
     num_vars: u32,
     num_match_vars: u32,
+    posix_regex: bool,
+    source_hash: u64,
+    capabilities: u64,
 }
 
 #[derive(Clone)]
@@ -51,7 +59,13 @@ pub struct Compiler {
     pub(crate) max_local_variables: usize,
     pub(crate) max_header_size: usize,
     pub(crate) max_includes: usize,
+    pub(crate) max_regex_size: usize,
+    pub(crate) regex_backtrack_limit: usize,
+    pub(crate) linear_regex: bool,
+    pub(crate) posix_regex: bool,
     pub(crate) no_capability_check: bool,
+    pub(crate) source_positions: bool,
+    pub(crate) optimize: bool,
 
     // Functions
     pub(crate) functions: AHashMap<String, (u32, u32)>,
@@ -59,6 +73,23 @@ pub struct Compiler {
 
 pub type Function = for<'x> fn(&'x Context<'x>, Vec<Variable>) -> Variable;
 
+/// Returns a unique value for the Message-ID headers and MIME boundaries of
+/// the messages built by a script. The sequence number starts at zero and is
+/// incremented on each call made during a filter run.
+pub type IdGenerator = Arc<dyn Fn(IdKind, u64) -> String + Send + Sync>;
+
+#[derive(Clone)]
+pub(crate) struct IdGeneratorFn(pub(crate) IdGenerator);
+
+/// A comparator provided by the host, made available to scripts that
+/// declare it with `require "comparator-<name>"`.
+#[derive(Debug, Clone, Copy)]
+pub struct CustomComparator {
+    pub(crate) normalize: fn(&str) -> String,
+    pub(crate) ordering: Option<fn(&str, &str) -> Ordering>,
+    pub(crate) match_as: MatchAs,
+}
+
 #[derive(Default, Clone)]
 pub struct FunctionMap {
     pub(crate) map: AHashMap<String, (u32, u32)>,
@@ -76,6 +107,8 @@ pub struct Runtime {
     pub(crate) include_scripts: AHashMap<String, Arc<Sieve>>,
     pub(crate) local_hostname: Cow<'static, str>,
     pub(crate) functions: Vec<Function>,
+    pub(crate) comparators: AHashMap<String, CustomComparator>,
+    pub(crate) id_generator: Option<IdGeneratorFn>,
 
     pub(crate) max_nested_includes: usize,
     pub(crate) cpu_limit: usize,
@@ -84,6 +117,10 @@ pub struct Runtime {
     pub(crate) max_received_headers: usize,
     pub(crate) max_header_size: usize,
     pub(crate) max_out_messages: usize,
+    pub(crate) max_regex_size: usize,
+    pub(crate) regex_backtrack_limit: usize,
+    pub(crate) linear_regex: bool,
+    pub(crate) regex_cache: Arc<RegexCache>,
 
     pub(crate) default_vacation_expiry: u64,
     pub(crate) default_duplicate_expiry: u64,
@@ -91,6 +128,8 @@ pub struct Runtime {
     pub(crate) vacation_use_orig_rcpt: bool,
     pub(crate) vacation_default_subject: Cow<'static, str>,
     pub(crate) vacation_subject_prefix: Cow<'static, str>,
+
+    pub(crate) trace: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -102,6 +141,7 @@ pub struct Context<'x> {
     pub(crate) user_address: Cow<'x, str>,
     pub(crate) user_full_name: Cow<'x, str>,
     pub(crate) current_time: i64,
+    pub(crate) time_zone: Option<Zone>,
 
     pub(crate) message: Message<'x>,
     pub(crate) message_size: usize,
@@ -128,13 +168,20 @@ pub struct Context<'x> {
 
     pub(crate) queued_events: IntoIter<Event>,
     pub(crate) final_event: Option<Event>,
+    pub(crate) final_position: Option<SourcePosition>,
+    pub(crate) trace: Option<Vec<Trace>>,
+    pub(crate) filter_result: Option<FilterResult>,
     pub(crate) last_message_id: usize,
     pub(crate) main_message_id: usize,
 
     pub(crate) has_changes: bool,
+    pub(crate) text_cache: TextCache,
+    pub(crate) imap_sieve: bool,
     pub(crate) num_redirects: usize,
     pub(crate) num_instructions: usize,
     pub(crate) num_out_messages: usize,
+    pub(crate) id_generator: Option<IdGeneratorFn>,
+    pub(crate) num_generated_ids: u64,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
@@ -272,6 +319,12 @@ pub enum Importance {
     Low,
 }
 
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum IdKind {
+    MessageId,
+    Boundary,
+}
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum MatchAs {
     Octet,
@@ -322,7 +375,9 @@ pub enum VirusStatus {
 mod tests {
     use std::{
         fs,
+        future::Future,
         path::{Path, PathBuf},
+        sync::Arc,
     };
 
     use ahash::{AHashMap, AHashSet};
@@ -332,10 +387,26 @@ mod tests {
     };
 
     use crate::{
-        compiler::grammar::Capability,
-        runtime::{actions::action_mime::reset_test_boundary, Variable},
-        Compiler, Context, Envelope, Event, FunctionMap, Input, Mailbox, Recipient, Runtime,
-        SpamStatus, VirusStatus,
+        compiler::{
+            binary::{DecodeError, Header, HEADER_LEN},
+            grammar::{
+                expr::{BinaryOperator, Constant, Expression},
+                instruction::Instruction,
+                Capability,
+            },
+            verify::VerifyErrorType,
+            VariableType,
+        },
+        runtime::{
+            actions::action_mime::reset_test_boundary,
+            driver::Driver,
+            imapsieve::{ImapCause, ImapEvent},
+            result::{FilterResult, SendAction},
+            trace::Trace,
+            Variable,
+        },
+        Compiler, Context, CustomComparator, Envelope, Event, FunctionMap, Input, Mailbox,
+        Recipient, Runtime, Script, Sieve, SpamStatus, VirusStatus,
     };
 
     impl Variable {
@@ -363,7 +434,480 @@ mod tests {
                 continue;
             }*/
             println!("===== {} =====", test.display());
-            run_test(&test);
+            run_test(&test, false);
+        }
+    }
+
+    #[test]
+    fn optimized_test_suite() {
+        let mut tests = Vec::new();
+        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("tests");
+
+        read_dir(path, &mut tests);
+
+        for test in tests {
+            println!("===== {} (optimized) =====", test.display());
+            run_test(&test, true);
+        }
+    }
+
+    #[test]
+    fn source_positions() {
+        let script = concat!(
+            "require [\"ihave\", \"fileinto\"];\n",
+            "if true {\n",
+            "    fileinto \"Junk\";\n",
+            "    error \"Script failed\";\n",
+            "}\n"
+        );
+        assert!(!Compiler::new()
+            .compile(script.as_bytes())
+            .unwrap()
+            .has_source_positions());
+        let script = Compiler::new()
+            .with_source_positions(true)
+            .compile(script.as_bytes())
+            .unwrap();
+
+        let runtime = Runtime::new();
+        let mut instance = Context::new(
+            &runtime,
+            MessageParser::new()
+                .parse(b"Subject: test\r\n\r\ntest\r\n")
+                .unwrap(),
+        );
+        let mut input = Input::script("", script);
+        let mut position = None;
+        while let Some(event) = instance.run(input) {
+            match event {
+                Ok(_) => input = Input::True,
+                Err(crate::runtime::RuntimeError::ScriptErrorMessage(_)) => {
+                    position = instance.current_position();
+                    break;
+                }
+                Err(err) => panic!("Unexpected error {err:?}"),
+            }
+        }
+        let position = position.unwrap();
+        assert_eq!((position.line_num(), position.line_pos()), (4, 5));
+    }
+
+    #[test]
+    fn execution_trace() {
+        let script = Compiler::new()
+            .with_source_positions(true)
+            .compile(
+                concat!(
+                    "require [\"fileinto\", \"variables\"];\n",
+                    "if header :matches \"subject\" \"*money*\" {\n",
+                    "    fileinto \"Junk ${1}\";\n",
+                    "}\n"
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let runtime = Runtime::new().with_trace(true);
+        let mut instance = Context::new(
+            &runtime,
+            MessageParser::new()
+                .parse(b"Subject: Make money fast\r\n\r\ntest\r\n")
+                .unwrap(),
+        );
+        let mut input = Input::script("", script);
+        while let Some(event) = instance.run(input) {
+            event.unwrap();
+            input = Input::True;
+        }
+
+        let trace = instance.take_trace();
+        assert_eq!(trace.len(), 2, "{trace:#?}");
+        assert!(
+            matches!(
+                &trace[0],
+                Trace::Test {
+                    position: Some(position),
+                    name: "header",
+                    values,
+                    keys,
+                    result: Some(true),
+                    match_vars,
+                } if position.line_num() == 2
+                    && values == &["Make money fast"]
+                    && keys == &["*money*"]
+                    && match_vars == &[(1, "Make ".to_string())]
+            ),
+            "{trace:#?}"
+        );
+        assert!(
+            matches!(
+                &trace[1],
+                Trace::Action {
+                    position: Some(position),
+                    event: Event::FileInto { folder, .. },
+                } if position.line_num() == 3 && folder == "Junk Make "
+            ),
+            "{trace:#?}"
+        );
+    }
+
+    #[test]
+    fn run_driver() {
+        struct TestDriver {
+            compiler: Compiler,
+            mailboxes: Vec<&'static str>,
+        }
+
+        impl Driver for TestDriver {
+            async fn include_script(&mut self, name: &Script, _: bool) -> Option<Arc<Sieve>> {
+                (name.as_str() == "archive").then(|| {
+                    self.compiler
+                        .compile(b"require \"fileinto\";\nfileinto \"Archive\";\n")
+                        .unwrap()
+                        .into()
+                })
+            }
+
+            async fn mailbox_exists(&mut self, mailboxes: &[Mailbox], _: &[String]) -> bool {
+                mailboxes.iter().all(|mailbox| {
+                    matches!(mailbox, Mailbox::Name(name) if self.mailboxes.contains(&name.as_str()))
+                })
+            }
+        }
+
+        let mut driver = TestDriver {
+            compiler: Compiler::new(),
+            mailboxes: vec!["Lists"],
+        };
+        let script = driver
+            .compiler
+            .compile(
+                concat!(
+                    "require [\"fileinto\", \"mailbox\", \"include\", \"editheader\"];\n",
+                    "addheader \"X-Filtered\" \"yes\";\n",
+                    "include :optional \"missing\";\n",
+                    "include \"archive\";\n",
+                    "if mailboxexists \"Lists\" {\n",
+                    "    fileinto \"Lists\";\n",
+                    "}\n",
+                    "if mailboxexists \"Other\" {\n",
+                    "    fileinto \"Other\";\n",
+                    "}\n"
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let runtime = Runtime::new();
+        let mut instance = Context::new(
+            &runtime,
+            MessageParser::new()
+                .parse(b"Subject: test\r\n\r\ntest\r\n")
+                .unwrap(),
+        );
+        let future = instance.run_driver(Input::script("", script), &mut driver);
+        fn assert_send<T: Send>(_: &T) {}
+        assert_send(&future);
+        let result = block_on(future);
+
+        assert!(result.errors().is_empty(), "{result:?}");
+        assert_eq!(
+            result
+                .file_into()
+                .iter()
+                .map(|action| (action.folder.as_str(), action.message_id))
+                .collect::<Vec<_>>(),
+            [("Archive", 1), ("Lists", 1)]
+        );
+        assert!(result.keep().is_none());
+        assert!(
+            String::from_utf8_lossy(result.modified_message().unwrap()).contains("X-Filtered: yes")
+        );
+    }
+
+    #[test]
+    fn regex_cache() {
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require [\"regex\", \"variables\", \"fileinto\"];\n",
+                    "set \"prefix\" \"re\";\n",
+                    "if header :regex \"subject\" \"^${prefix}: (.*)$\" {\n",
+                    "    fileinto \"${1}\";\n",
+                    "}\n",
+                    "if header :regex \"subject\" \"^(${prefix}|fwd):\" {\n",
+                    "    fileinto \"${1}\";\n",
+                    "}\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let message = MessageParser::new()
+            .parse(b"Subject: re: hello\r\n\r\ntest\r\n")
+            .unwrap();
+        let run = |runtime: &Runtime| {
+            let mut instance = Context::new(runtime, message.clone()).with_filter_result(true);
+            let mut input = Input::script("", script.clone());
+            while let Some(event) = instance.run(input) {
+                event.unwrap();
+                input = Input::True;
+            }
+            let result = instance.take_filter_result();
+            assert!(result.errors().is_empty(), "{:?}", result.errors());
+            result
+                .file_into()
+                .iter()
+                .map(|action| action.folder.clone())
+                .collect::<Vec<_>>()
+        };
+
+        // Contexts created from a runtime or its clones share the cache
+        let runtime = Runtime::new();
+        for _ in 0..3 {
+            assert_eq!(run(&runtime), ["hello", "re"]);
+        }
+        assert_eq!(run(&runtime.clone()), ["hello", "re"]);
+        assert_eq!(runtime.regex_cache_misses(), 2);
+        assert_eq!(runtime.regex_cache_hits(), 6);
+
+        // Least recently used patterns are evicted
+        for (runtime, hits) in [
+            (Runtime::new().with_regex_cache_size(1), 0),
+            (Runtime::new().with_regex_cache_size(0), 0),
+            (Runtime::new().with_regex_cache_size(2), 2),
+        ] {
+            run(&runtime);
+            run(&runtime);
+            assert_eq!(runtime.regex_cache_hits(), hits);
+            assert_eq!(runtime.regex_cache_misses(), 4 - hits);
+        }
+    }
+
+    #[test]
+    fn binary_format() {
+        let source = b"require \"fileinto\";\nfileinto \"Spam\";\n";
+        let compiler = Compiler::new();
+        let script = compiler.compile(source).unwrap();
+        assert!(script.is_compiled_from(source));
+        assert!(!script.is_compiled_from(b"keep;"));
+
+        let mut bytes = Vec::new();
+        Header::new(&script).write(&mut bytes);
+        assert_eq!(bytes.len(), HEADER_LEN);
+        assert_eq!(
+            Header::parse_current(&bytes, &compiler)
+                .unwrap()
+                .source_hash,
+            script.source_hash()
+        );
+        assert_eq!(
+            Header::parse(b"keep;").unwrap_err(),
+            DecodeError::InvalidFormat
+        );
+        bytes[4..8].copy_from_slice(&(Compiler::VERSION - 1).to_le_bytes());
+        assert_eq!(
+            Header::parse_current(&bytes, &compiler).unwrap_err(),
+            DecodeError::RecompileNeeded {
+                version: Compiler::VERSION - 1,
+                source_hash: script.source_hash()
+            }
+        );
+
+        // Scripts have to be recompiled when the enabled capabilities change
+        let mut fnc_map = FunctionMap::new().with_external_function("ext", 0, 1);
+        let other_compiler = Compiler::new().register_functions(&mut fnc_map);
+        assert_ne!(
+            compiler.capability_fingerprint(),
+            other_compiler.capability_fingerprint()
+        );
+        assert_ne!(
+            compiler.capability_fingerprint(),
+            Compiler::new()
+                .with_no_capability_check(true)
+                .capability_fingerprint()
+        );
+
+        let mut bytes = script.to_bytes_with(serde_json::to_vec).unwrap();
+        assert!(matches!(
+            Sieve::from_bytes_with(&bytes, &other_compiler, |payload| {
+                serde_json::from_slice(payload)
+            }),
+            Err(DecodeError::RecompileNeeded { .. })
+        ));
+        bytes.truncate(bytes.len() - 4);
+        assert!(matches!(
+            Sieve::from_bytes_with(&bytes, &compiler, |payload| {
+                serde_json::from_slice(payload)
+            }),
+            Err(DecodeError::Corrupted(_))
+        ));
+
+        #[cfg(feature = "rkyv")]
+        {
+            let mut bytes = script.to_bytes().unwrap();
+            assert_eq!(Sieve::from_bytes(&bytes, &compiler).unwrap(), script);
+            bytes[8] ^= 0xff;
+            assert!(matches!(
+                Sieve::from_bytes(&bytes, &compiler),
+                Err(DecodeError::RecompileNeeded { .. })
+            ));
+            bytes[8] ^= 0xff;
+            bytes.truncate(bytes.len() - 4);
+            assert!(matches!(
+                Sieve::from_bytes(&bytes, &compiler),
+                Err(DecodeError::Corrupted(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn verify_scripts() {
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require [\"variables\", \"vnd.stalwart.expressions\"];\n",
+                    "set \"subject\" \"\";\n",
+                    "if header :matches \"subject\" \"*\" {\n",
+                    "    set \"subject\" \"${1}\";\n",
+                    "}\n",
+                    "let \"i\" \"subject == 'x' && 1 + 2\";\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        assert_eq!(script.verify(), Ok(()));
+
+        let mut tampered = script.clone();
+        let jz = tampered
+            .instructions
+            .iter()
+            .position(|instruction| matches!(instruction, Instruction::Jz(_)))
+            .unwrap();
+        tampered.instructions[jz] = Instruction::Jz(tampered.instructions.len() + 1);
+        let err = tampered.verify().unwrap_err();
+        assert_eq!(err.position(), jz);
+        assert!(matches!(err.error_type(), VerifyErrorType::InvalidJump(_)));
+
+        let mut tampered = script.clone();
+        tampered.num_vars = 0;
+        assert!(matches!(
+            tampered.verify().unwrap_err().error_type(),
+            VerifyErrorType::InvalidLocalVariable(_)
+        ));
+
+        let mut tampered = script.clone();
+        tampered.num_match_vars = 1;
+        assert!(matches!(
+            tampered.verify().unwrap_err().error_type(),
+            VerifyErrorType::InvalidMatchVariable(1)
+        ));
+
+        for expr in [
+            vec![Expression::BinaryOperator(BinaryOperator::Add)],
+            vec![
+                Expression::Variable(VariableType::Local(0)),
+                Expression::JmpIf { val: true, pos: 5 },
+            ],
+            vec![
+                Expression::Variable(VariableType::Local(0)),
+                Expression::Variable(VariableType::Local(0)),
+            ],
+        ] {
+            let mut tampered = script.clone();
+            tampered.instructions.push(Instruction::Eval(expr));
+            assert_eq!(
+                tampered.verify().unwrap_err().error_type(),
+                &VerifyErrorType::InvalidExpression
+            );
+        }
+
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require [\"foreverypart\", \"mime\"];\n",
+                    "foreverypart {\n",
+                    "    if header :mime :type \"content-type\" \"text\" {\n",
+                    "        break;\n",
+                    "    }\n",
+                    "}\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        assert_eq!(script.verify(), Ok(()));
+        let pop = script
+            .instructions
+            .iter()
+            .position(|instruction| matches!(instruction, Instruction::ForEveryPartPop(1)))
+            .unwrap();
+        let push = script
+            .instructions
+            .iter()
+            .position(|instruction| matches!(instruction, Instruction::ForEveryPartPush))
+            .unwrap();
+
+        for (position, instruction) in [
+            (pop, Instruction::ForEveryPartPop(0)),
+            (pop, Instruction::ForEveryPartPop(2)),
+            (push, Instruction::Discard),
+        ] {
+            let mut tampered = script.clone();
+            tampered.instructions[position] = instruction;
+            let err = tampered.verify().unwrap_err();
+            assert_eq!(err.position(), pop);
+            assert_eq!(err.error_type(), &VerifyErrorType::InvalidInstruction);
+        }
+
+        let mut tampered = script.clone();
+        tampered.instructions.push(Instruction::ForEveryPartPop(1));
+        assert_eq!(
+            tampered.verify().unwrap_err().error_type(),
+            &VerifyErrorType::InvalidInstruction
+        );
+    }
+
+    #[test]
+    fn optimizer() {
+        let source = concat!(
+            "require [\"fileinto\", \"variables\", \"vnd.stalwart.expressions\"];\n",
+            "set \"x\" \"1\";\n",
+            "let \"y\" \"x && 2 + 3\";\n",
+            "if false { fileinto \"never\"; }\n",
+            "if true { fileinto \"always\"; } else { fileinto \"else\"; }\n",
+            "if eval \"1 + 1 == 2\" { fileinto \"${y}\"; }\n",
+            "stop;\n",
+            "fileinto \"unreachable\";\n",
+        );
+        let compiler = Compiler::new();
+        let script = compiler.compile(source.as_bytes()).unwrap();
+        let optimized = compiler
+            .clone()
+            .with_optimize(true)
+            .compile(source.as_bytes())
+            .unwrap();
+        assert_eq!(optimized.verify(), Ok(()));
+        assert!(optimized.instructions.len() < script.instructions.len());
+        assert!(!optimized
+            .instructions
+            .iter()
+            .any(|instruction| matches!(instruction, Instruction::Jz(_) | Instruction::Jnz(_))));
+        assert!(optimized.instructions.iter().any(|instruction| matches!(
+            instruction,
+            Instruction::Let(let_) if let_.expr == [
+                Expression::Variable(VariableType::Local(0)),
+                Expression::JmpIf { val: false, pos: 2 },
+                Expression::Constant(Constant::Integer(5)),
+                Expression::BinaryOperator(BinaryOperator::And),
+            ]
+        )));
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+        loop {
+            if let std::task::Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
         }
     }
 
@@ -383,7 +927,7 @@ mod tests {
         }
     }
 
-    fn run_test(script_path: &Path) {
+    fn run_test(script_path: &Path, optimize: bool) {
         let mut fnc_map = FunctionMap::new()
             .with_function("trim", |_, v| match v.into_iter().next().unwrap() {
                 crate::runtime::Variable::String(s) => s.trim().to_string().into(),
@@ -457,14 +1001,18 @@ mod tests {
             .with_external_function("ext_false", 5, 0);
         let mut compiler = Compiler::new()
             .with_max_string_size(10240)
+            .with_optimize(optimize)
             .register_functions(&mut fnc_map);
 
         let mut ancestors = script_path.ancestors();
         ancestors.next();
         let base_path = ancestors.next().unwrap();
-        let script = compiler
-            .compile(&add_crlf(&fs::read(script_path).unwrap()))
-            .unwrap();
+        let script = round_trip(
+            compiler
+                .compile(&add_crlf(&fs::read(script_path).unwrap()))
+                .unwrap(),
+            &compiler,
+        );
 
         let mut input = Input::script("", script);
         let mut current_test = String::new();
@@ -474,6 +1022,8 @@ mod tests {
         let mut lists: AHashMap<String, AHashSet<String>> = AHashMap::new();
         let mut duplicated_ids = AHashSet::new();
         let mut actions = Vec::new();
+        let mut compiled_script = None;
+        let mut script_result = FilterResult::default();
 
         'outer: loop {
             let runtime = Runtime::new()
@@ -483,6 +1033,22 @@ mod tests {
                 .with_max_out_messages(100)
                 .with_capability(Capability::While)
                 .with_capability(Capability::Expressions)
+                .with_comparator(
+                    "x-phone",
+                    CustomComparator::new(|value| {
+                        value.chars().filter(|char| char.is_ascii_digit()).collect()
+                    }),
+                )
+                .with_comparator(
+                    "x-decimal",
+                    CustomComparator::new(|value| value.trim().to_string()).with_ordering(
+                        |a, b| {
+                            let a = a.trim().parse::<f64>().unwrap_or_default();
+                            let b = b.trim().parse::<f64>().unwrap_or_default();
+                            a.total_cmp(&b)
+                        },
+                    ),
+                )
                 .with_functions(&mut fnc_map.clone());
             let mut instance = Context::new(
                 &runtime,
@@ -501,6 +1067,7 @@ mod tests {
                 },
             );
             let raw_message = raw_message_.take().unwrap_or_default();
+            instance.text_cache.clear();
             instance.message =
                 MessageParser::new()
                     .parse(&raw_message)
@@ -562,7 +1129,8 @@ mod tests {
                         include_path.push(format!("{name}.sieve"));
 
                         if let Ok(bytes) = fs::read(include_path.as_path()) {
-                            let script = compiler.compile(&add_crlf(&bytes)).unwrap();
+                            let script =
+                                round_trip(compiler.compile(&add_crlf(&bytes)).unwrap(), &compiler);
                             input = Input::script(name, script);
                         } else if optional {
                             input = Input::False;
@@ -817,6 +1385,49 @@ mod tests {
                                                 3
                                             });
                                         }
+                                        "sieve_regex_max_size" => {
+                                            let size = value.parse::<usize>().unwrap();
+                                            instance.runtime.set_max_regex_size(size);
+                                            compiler.set_max_regex_size(size);
+                                        }
+                                        "sieve_regex_backtrack_limit" => {
+                                            let limit = value.parse::<usize>().unwrap();
+                                            instance.runtime.set_regex_backtrack_limit(limit);
+                                            compiler.set_regex_backtrack_limit(limit);
+                                        }
+                                        "sieve_regex_linear" => {
+                                            let linear = value.eq_ignore_ascii_case("yes");
+                                            instance.runtime.set_linear_regex(linear);
+                                            compiler.set_linear_regex(linear);
+                                        }
+                                        "sieve_regex_cache_size" => {
+                                            instance
+                                                .runtime
+                                                .set_regex_cache_size(value.parse().unwrap());
+                                        }
+                                        "sieve_regex_posix" => {
+                                            compiler
+                                                .set_posix_regex(value.eq_ignore_ascii_case("yes"));
+                                        }
+                                        "sieve_local_hostname" => {
+                                            instance.runtime.set_local_hostname(value);
+                                        }
+                                        "sieve_id_generator" => {
+                                            if value.eq_ignore_ascii_case("context") {
+                                                instance.set_id_generator(Arc::new(|kind, seq| {
+                                                    format!("context-{kind:?}-{seq}")
+                                                }));
+                                            } else if value.eq_ignore_ascii_case("yes") {
+                                                instance.runtime.set_id_generator(Arc::new(
+                                                    |kind, seq| format!("{kind:?}-{seq}"),
+                                                ));
+                                            } else {
+                                                instance.runtime.id_generator = None;
+                                            }
+                                        }
+                                        "sieve_time_zone" => {
+                                            input = instance.set_time_zone(&value).into();
+                                        }
                                         param => panic!("Invalid test_config_set param '{param}'"),
                                     }
                                 }
@@ -885,6 +1496,23 @@ mod tests {
                                         instance.set_medatata(annotation, value);
                                     }
                                 }
+                                "test_imap_event" => {
+                                    let mut params = params.into_iter();
+                                    let cause = match params.next().expect("IMAP cause").as_str() {
+                                        "APPEND" => ImapCause::Append,
+                                        "COPY" => ImapCause::Copy,
+                                        "FLAG" => ImapCause::Flag,
+                                        cause => panic!("Invalid IMAP cause '{cause}'"),
+                                    };
+                                    let mailbox = params.next().expect("IMAP mailbox");
+                                    let flags = params.next().unwrap_or_default();
+                                    let changed_flags = params.next().unwrap_or_default();
+                                    instance.set_imap_event(
+                                        ImapEvent::new(cause, mailbox)
+                                            .with_flags(flags.split_whitespace())
+                                            .with_changed_flags(changed_flags.split_whitespace()),
+                                    );
+                                }
                                 "test_mailbox_create" => {
                                     mailboxes.push(params.pop().expect("mailbox to create"));
                                 }
@@ -913,10 +1541,104 @@ mod tests {
                                             println!("Error: {:?}", err);
                                         }*/
                                         input = result.is_ok().into();
+                                        compiled_script =
+                                            result.ok().map(|script| round_trip(script, &compiler));
                                     } else {
                                         panic!("Script {} not found.", include_path.display());
                                     }
                                 }
+                                "test_script_run" => {
+                                    let script =
+                                        compiled_script.clone().expect("No script compiled");
+                                    let mut context =
+                                        Context::new(&instance.runtime, instance.message.clone())
+                                            .with_filter_result(true);
+                                    context.message_size = instance.message_size;
+                                    context.envelope = instance.envelope.clone();
+                                    context.vars_env = instance.vars_env.clone();
+                                    context.vars_global = instance.vars_global.clone();
+                                    context.user_address = instance.user_address.clone();
+                                    context.current_time = instance.current_time;
+                                    context.time_zone = instance.time_zone.clone();
+                                    context.imap_sieve = instance.imap_sieve;
+
+                                    let mut script_input = Input::script("", script);
+                                    while let Some(event) = context.run(script_input) {
+                                        script_input = match event {
+                                            Ok(Event::DuplicateId { id, .. }) => {
+                                                duplicated_ids.contains(&id).into()
+                                            }
+                                            Ok(_) => Input::True,
+                                            Err(_) => Input::False,
+                                        };
+                                    }
+                                    script_result = context.take_filter_result();
+                                    input = script_result.errors().is_empty().into();
+                                }
+                                "test_filter_result" => {
+                                    let mut params = params.into_iter();
+                                    let param =
+                                        params.next().expect("test_filter_result parameter");
+                                    let values = params.collect::<Vec<_>>();
+                                    let recipients = |actions: &[SendAction]| {
+                                        actions
+                                            .iter()
+                                            .map(|action| match &action.recipient {
+                                                Recipient::Address(address) => address.clone(),
+                                                recipient => format!("{recipient:?}"),
+                                            })
+                                            .collect::<Vec<_>>()
+                                    };
+                                    input = match param.as_str() {
+                                        ":keep" => script_result
+                                            .keep()
+                                            .is_some_and(|keep| keep.flags == values),
+                                        ":discard" => script_result.keep().is_none(),
+                                        ":fileinto" => script_result
+                                            .file_into()
+                                            .iter()
+                                            .map(|action| &action.folder)
+                                            .eq(values.iter()),
+                                        ":flags" => {
+                                            let (folder, flags) =
+                                                values.split_first().expect("fileinto folder");
+                                            script_result.file_into().iter().any(|action| {
+                                                &action.folder == folder && action.flags == flags
+                                            })
+                                        }
+                                        ":redirect" => {
+                                            recipients(script_result.redirects()) == values
+                                        }
+                                        ":vacation" => {
+                                            recipients(script_result.vacation()) == values
+                                                && script_result.vacation().iter().all(|action| {
+                                                    script_result
+                                                        .message(action.message_id)
+                                                        .is_some()
+                                                })
+                                        }
+                                        ":notify" => script_result
+                                            .notifications()
+                                            .iter()
+                                            .map(|action| &action.method)
+                                            .eq(values.iter()),
+                                        ":modified" => script_result.modified_message().is_some(),
+                                        param => {
+                                            panic!("Invalid test_filter_result param '{param}'")
+                                        }
+                                    }
+                                    .into();
+                                }
+                                "test_error" => {
+                                    let name = params.first().expect("test_error parameter");
+                                    input = script_result
+                                        .errors()
+                                        .iter()
+                                        .any(|error| {
+                                            format!("{error:?}").starts_with(name.as_str())
+                                        })
+                                        .into();
+                                }
                                 "test_config_reload" => (),
                                 "test_fail" => {
                                     panic!(
@@ -964,6 +1686,17 @@ mod tests {
         }
     }
 
+    // Scripts under test are run after an encode/decode cycle, which also
+    // verifies them
+    fn round_trip(script: Sieve, compiler: &Compiler) -> Sieve {
+        let bytes = script.to_bytes_with(serde_json::to_vec).unwrap();
+        let decoded =
+            Sieve::from_bytes_with(&bytes, compiler, |payload| serde_json::from_slice(payload))
+                .unwrap();
+        assert_eq!(decoded, script);
+        decoded
+    }
+
     fn add_crlf(bytes: &[u8]) -> Vec<u8> {
         let mut result = Vec::with_capacity(bytes.len());
         let mut last_ch = 0;
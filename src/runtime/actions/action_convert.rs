@@ -94,7 +94,7 @@ impl Convert {
         }
 
         if did_convert {
-            ctx.has_changes = true;
+            ctx.message_changed();
         }
 
         TestResult::Bool(did_convert ^ self.is_not)
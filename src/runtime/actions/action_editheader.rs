@@ -16,6 +16,7 @@ use crate::{
         },
         MatchType,
     },
+    runtime::RuntimeError,
     Context,
 };
 
@@ -34,7 +35,7 @@ impl AddHeader {
         if !header_name.is_empty() {
             if let Some(header_name) = HeaderName::parse(header_name) {
                 if !ctx.runtime.protected_headers.contains(&header_name) {
-                    ctx.has_changes = true;
+                    ctx.message_changed();
                     ctx.insert_header(
                         ctx.part,
                         header_name,
@@ -51,20 +52,21 @@ impl AddHeader {
 }
 
 impl DeleteHeader {
-    pub(crate) fn exec(&self, ctx: &mut Context) {
+    pub(crate) fn exec(&self, ctx: &mut Context) -> Result<(), RuntimeError> {
+        let comparator = ctx.comparator(&self.comparator);
         let header_name__ = ctx.eval_value(&self.field_name);
         let header_name_ = header_name__.to_string();
         let header_name = if let Some(header_name) = HeaderName::parse(header_name_.as_ref()) {
             header_name
         } else {
-            return;
+            return Ok(());
         };
         let value_patterns = ctx.eval_values(&self.value_patterns);
         let mut deleted_headers = Vec::new();
         let mut deleted_bytes = 0;
 
         if ctx.runtime.protected_headers.contains(&header_name) {
-            return;
+            return Ok(());
         }
 
         ctx.find_headers(
@@ -78,20 +80,21 @@ impl DeleteHeader {
                             value_patterns.iter().zip(self.value_patterns.iter())
                         {
                             if match &self.match_type {
-                                MatchType::Is => self.comparator.is(&value, pattern_expr),
-                                MatchType::Contains => self
-                                    .comparator
-                                    .contains(value, pattern_expr.to_string().as_ref()),
+                                MatchType::Is => comparator.is(&value, pattern_expr),
+                                MatchType::Contains => {
+                                    comparator.contains(value, pattern_expr.to_string().as_ref())
+                                }
                                 MatchType::Value(rel_match) => {
-                                    self.comparator.relational(rel_match, &value, pattern_expr)
+                                    comparator.relational(rel_match, &value, pattern_expr)
                                 }
-                                MatchType::Matches(_) => self.comparator.matches(
+                                MatchType::Matches(_) => comparator.glob(
+                                    pattern,
+                                    pattern_expr,
                                     value,
-                                    pattern_expr.to_string().as_ref(),
                                     0,
                                     &mut Vec::new(),
                                 ),
-                                MatchType::Regex(_) => self.comparator.regex(
+                                MatchType::Regex(_) => comparator.regex(
                                     pattern,
                                     pattern_expr,
                                     value,
@@ -123,8 +126,12 @@ impl DeleteHeader {
             },
         );
 
+        if let Some(err) = comparator.take_error() {
+            return Err(err);
+        }
+
         if !deleted_headers.is_empty() {
-            ctx.has_changes = true;
+            ctx.message_changed();
             for (part_id, header_pos) in deleted_headers.iter().rev() {
                 ctx.message.parts[*part_id as usize]
                     .headers
@@ -133,6 +140,8 @@ impl DeleteHeader {
         }
 
         ctx.message_size -= deleted_bytes;
+
+        Ok(())
     }
 }
 
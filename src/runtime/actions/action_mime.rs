@@ -15,13 +15,14 @@ use crate::{
         grammar::actions::action_mime::{Enclose, ExtractText, Replace},
         VariableType,
     },
-    Context, Event,
+    Context, Event, IdKind,
 };
 
 use super::action_editheader::RemoveCrLf;
 
+use mail_builder::headers::date::Date;
 #[cfg(not(test))]
-use mail_builder::headers::message_id::generate_message_id_header;
+use mail_builder::{headers::message_id::generate_message_id_header, mime::make_boundary};
 
 impl Replace {
     pub(crate) fn exec(&self, ctx: &mut Context) {
@@ -31,7 +32,7 @@ impl Replace {
         for part_id in part_ids {
             ctx.message.parts.remove(part_id as usize);
         }
-        ctx.has_changes = true;
+        ctx.message_changed();
 
         // Update part
         let body = ctx.eval_value(&self.replacement).to_string().into_owned();
@@ -125,11 +126,7 @@ impl Replace {
 
             // Add Date
             if add_date {
-                #[cfg(not(test))]
-                let header_value = mail_builder::headers::date::Date::now().to_rfc822();
-                #[cfg(test)]
-                let header_value = "Tue, 20 Nov 2022 05:14:20 -0300".to_string();
-
+                let header_value = ctx.date_header();
                 ctx.insert_header(
                     0,
                     HeaderName::Other("Date".to_string().into()),
@@ -139,16 +136,12 @@ impl Replace {
             }
 
             // Add Message-ID
-            let mut header_value = Vec::with_capacity(20);
-            #[cfg(not(test))]
-            generate_message_id_header(&mut header_value, &ctx.runtime.local_hostname).unwrap();
-            #[cfg(test)]
-            header_value.extend_from_slice(b"<auto-generated@message-id>");
+            let header_value = ctx.make_message_id();
 
             ctx.insert_header(
                 0,
                 HeaderName::Other("Message-ID".to_string().into()),
-                String::from_utf8(header_value).unwrap(),
+                header_value,
                 true,
             );
         }
@@ -180,14 +173,11 @@ impl Enclose {
             .unwrap_or_default();
 
         let message = std::mem::take(&mut ctx.message);
-        #[cfg(test)]
-        let boundary = make_test_boundary();
-        #[cfg(not(test))]
-        let boundary = mail_builder::mime::make_boundary(".");
+        let boundary = ctx.make_boundary();
 
         ctx.message_size += ((boundary.len() + 6) * 3) + body.len() + 2;
         ctx.part = 0;
-        ctx.has_changes = true;
+        ctx.message_changed();
         ctx.message = Message {
             html_body: Vec::with_capacity(0),
             text_body: Vec::with_capacity(0),
@@ -293,11 +283,7 @@ impl Enclose {
         }
 
         if add_date {
-            #[cfg(not(test))]
-            let header_value = mail_builder::headers::date::Date::now().to_rfc822();
-            #[cfg(test)]
-            let header_value = "Tue, 20 Nov 2022 05:14:20 -0300".to_string();
-
+            let header_value = ctx.date_header();
             ctx.insert_header(
                 0,
                 HeaderName::Other("Date".to_string().into()),
@@ -307,16 +293,12 @@ impl Enclose {
         }
 
         if add_message_id {
-            let mut header_value = Vec::with_capacity(20);
-            #[cfg(not(test))]
-            generate_message_id_header(&mut header_value, &ctx.runtime.local_hostname).unwrap();
-            #[cfg(test)]
-            header_value.extend_from_slice(b"<auto-generated@message-id>");
+            let header_value = ctx.make_message_id();
 
             ctx.insert_header(
                 0,
                 HeaderName::Other("Message-ID".to_string().into()),
-                String::from_utf8(header_value).unwrap(),
+                header_value,
                 true,
             );
         }
@@ -380,6 +362,48 @@ enum StackItem<'x> {
 }
 
 impl Context<'_> {
+    pub(crate) fn date_header(&self) -> String {
+        Date::new(self.current_time).to_rfc822()
+    }
+
+    pub(crate) fn make_message_id(&mut self) -> String {
+        if let Some(id) = self.generate_id(IdKind::MessageId) {
+            return format!("<{id}@{}>", self.runtime.local_hostname);
+        }
+
+        #[cfg(not(test))]
+        {
+            let mut message_id = Vec::with_capacity(20);
+            generate_message_id_header(&mut message_id, &self.runtime.local_hostname).unwrap();
+            String::from_utf8(message_id).unwrap()
+        }
+        #[cfg(test)]
+        "<auto-generated@message-id>".to_string()
+    }
+
+    pub(crate) fn make_boundary(&mut self) -> String {
+        if let Some(boundary) = self.generate_id(IdKind::Boundary) {
+            return boundary;
+        }
+
+        #[cfg(not(test))]
+        {
+            make_boundary(".")
+        }
+        #[cfg(test)]
+        make_test_boundary()
+    }
+
+    fn generate_id(&mut self, kind: IdKind) -> Option<String> {
+        let generator = self
+            .id_generator
+            .as_ref()
+            .or(self.runtime.id_generator.as_ref())?;
+        let id = (generator.0)(kind, self.num_generated_ids);
+        self.num_generated_ids += 1;
+        Some(id)
+    }
+
     pub(crate) fn build_message_id(&mut self) -> Option<Event> {
         if self.has_changes {
             self.last_message_id += 1;
@@ -4,7 +4,6 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use mail_builder::headers::{date::Date, message_id::generate_message_id_header};
 use mail_parser::{decoders::quoted_printable::HEX_MAP, HeaderName};
 
 use crate::{
@@ -12,6 +11,7 @@ use crate::{
         action_notify::Notify,
         action_redirect::{ByTime, Ret},
     },
+    runtime::result::SendOrigin,
     Context, Event, Importance, Recipient,
 };
 
@@ -21,7 +21,8 @@ impl Notify {
     pub(crate) fn exec(&self, ctx: &mut Context) {
         // Do not notify on Auto-Submitted messages
         for header in &ctx.message.parts[0].headers {
-            if matches!(&header.name, HeaderName::Other(name) if name.eq_ignore_ascii_case("Auto-Submitted"))
+            if (matches!(&header.name, HeaderName::AutoSubmitted)
+                || matches!(&header.name, HeaderName::Other(name) if name.eq_ignore_ascii_case("Auto-Submitted")))
                 && header
                     .value
                     .as_text()
@@ -144,13 +145,13 @@ impl Notify {
 
             if !has_date {
                 message.extend_from_slice(b"Date: ");
-                message.extend_from_slice(Date::now().to_rfc822().as_bytes());
+                message.extend_from_slice(ctx.date_header().as_bytes());
                 message.extend_from_slice(b"\r\n");
             }
 
             if !has_message_id {
                 message.extend_from_slice(b"Message-ID: ");
-                generate_message_id_header(&mut message, &ctx.runtime.local_hostname).unwrap();
+                message.extend_from_slice(ctx.make_message_id().as_bytes());
                 message.extend_from_slice(b"\r\n");
             }
 
@@ -237,6 +238,7 @@ impl Notify {
                     by_time: ByTime::None,
                     message_id: ctx.last_message_id,
                 });
+                ctx.set_send_origin(ctx.last_message_id, SendOrigin::Notify);
             }
         }
 
@@ -295,10 +297,8 @@ pub fn validate_from(addr: &str) -> bool {
 
     for &ch in addr.as_bytes().iter() {
         match ch {
-            b'\"' => {
-                if last_ch != b'\\' {
-                    in_quote = !in_quote;
-                }
+            b'\"' if last_ch != b'\\' => {
+                in_quote = !in_quote;
             }
             b'<' if !in_quote => {
                 if !in_angle {
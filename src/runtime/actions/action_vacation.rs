@@ -4,9 +4,6 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use std::borrow::Cow;
-
-use mail_builder::headers::{date::Date, message_id::generate_message_id_header};
 use mail_parser::{HeaderName, HeaderValue};
 
 use crate::{
@@ -17,7 +14,7 @@ use crate::{
         },
         AddressPart,
     },
-    runtime::tests::TestResult,
+    runtime::{result::SendOrigin, tests::TestResult},
     Context, Envelope, Event, Recipient,
 };
 
@@ -28,7 +25,7 @@ impl TestVacation {
         let mut from = String::new();
         let mut user_addresses = Vec::new();
 
-        if ctx.num_out_messages >= ctx.runtime.max_out_messages {
+        if ctx.num_out_messages >= ctx.runtime.max_out_messages || ctx.imap_sieve {
             return TestResult::Bool(false);
         }
 
@@ -38,15 +35,11 @@ impl TestVacation {
                     Envelope::From => {
                         from = value.to_string().to_ascii_lowercase();
                     }
-                    Envelope::To => {
-                        if !ctx.runtime.vacation_use_orig_rcpt {
-                            user_addresses.push(value.to_string());
-                        }
+                    Envelope::To if !ctx.runtime.vacation_use_orig_rcpt => {
+                        user_addresses.push(value.to_string());
                     }
-                    Envelope::Orcpt => {
-                        if ctx.runtime.vacation_use_orig_rcpt {
-                            user_addresses.push(value.to_string());
-                        }
+                    Envelope::Orcpt if ctx.runtime.vacation_use_orig_rcpt => {
+                        user_addresses.push(value.to_string());
                     }
                     _ => (),
                 }
@@ -105,6 +98,14 @@ impl TestVacation {
                 HeaderName::Received => {
                     received_count += 1;
                 }
+                HeaderName::AutoSubmitted
+                    if header
+                        .value
+                        .as_text()
+                        .is_none_or(|v| !v.eq_ignore_ascii_case("no")) =>
+                {
+                    return TestResult::Bool(false);
+                }
                 HeaderName::Other(header_name) => {
                     if header_name.eq_ignore_ascii_case("Auto-Submitted") {
                         if header
@@ -161,11 +162,11 @@ impl TestVacation {
 
 impl Vacation {
     pub(crate) fn exec(&self, ctx: &mut Context) {
-        let mut vacation_to = Cow::from("");
+        let mut vacation_to = String::new();
 
         for (name, value) in &ctx.envelope {
             if !value.is_empty() && name == &Envelope::From {
-                vacation_to = value.to_string();
+                vacation_to = value.to_string().into_owned();
                 break;
             }
         }
@@ -210,21 +211,18 @@ impl Vacation {
                 HeaderName::MessageId => {
                     message_id = header.value.as_text();
                 }
-                HeaderName::References => {
-                    if header.offset_start > 0 {
-                        references = (&ctx.message.raw_message
-                            [header.offset_start as usize..header.offset_end as usize])
-                            .into();
-                    }
+                HeaderName::References if header.offset_start > 0 => {
+                    references = (&ctx.message.raw_message
+                        [header.offset_start as usize..header.offset_end as usize])
+                        .into();
                 }
-                HeaderName::From | HeaderName::Sender => {
-                    if matches!(&header.value, HeaderValue::Address(address) if address.contains(vacation_to.as_ref()))
-                        && header.offset_start > 0
-                    {
-                        vacation_to_full = (&ctx.message.raw_message
-                            [header.offset_start as usize..header.offset_end as usize])
-                            .into();
-                    }
+                HeaderName::From | HeaderName::Sender
+                    if matches!(&header.value, HeaderValue::Address(address) if address.contains(&vacation_to))
+                        && header.offset_start > 0 =>
+                {
+                    vacation_to_full = (&ctx.message.raw_message
+                        [header.offset_start as usize..header.offset_end as usize])
+                        .into();
                 }
                 _ => (),
             }
@@ -264,7 +262,7 @@ impl Vacation {
             message.extend_from_slice(b"To:");
             message.extend_from_slice(vacation_to_full);
         } else {
-            write_header(&mut message, "To: ", vacation_to.to_string().as_ref());
+            write_header(&mut message, "To: ", &vacation_to);
         }
         write_header(
             &mut message,
@@ -286,11 +284,11 @@ impl Vacation {
             }
         }
         message.extend_from_slice(b"Date: ");
-        message.extend_from_slice(Date::now().to_rfc822().as_bytes());
+        message.extend_from_slice(ctx.date_header().as_bytes());
         message.extend_from_slice(b"\r\n");
 
         message.extend_from_slice(b"Message-ID: ");
-        generate_message_id_header(&mut message, &ctx.runtime.local_hostname).unwrap();
+        message.extend_from_slice(ctx.make_message_id().as_bytes());
         message.extend_from_slice(b"\r\n");
 
         write_header(&mut message, "Auto-Submitted: ", "auto-replied");
@@ -308,12 +306,13 @@ impl Vacation {
             message,
         });
         events.push(Event::SendMessage {
-            recipient: Recipient::Address(vacation_to.to_string()),
+            recipient: Recipient::Address(vacation_to),
             notify: Notify::Never,
             return_of_content: Ret::Default,
             by_time: ByTime::None,
             message_id: ctx.last_message_id,
         });
+        ctx.set_send_origin(ctx.last_message_id, SendOrigin::Vacation);
 
         // File carbon copy
         if let Some(fcc) = &self.fcc {
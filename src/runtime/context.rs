@@ -10,14 +10,18 @@ use ahash::AHashMap;
 use mail_parser::Message;
 
 use crate::{
-    compiler::grammar::{instruction::Instruction, Capability},
-    Context, Envelope, Event, Input, Metadata, Runtime, Sieve, SpamStatus, VirusStatus,
-    MAX_LOCAL_VARIABLES, MAX_MATCH_VARIABLES,
+    compiler::grammar::{
+        instruction::Instruction, tests::test_date::Zone, Capability, SourcePosition,
+    },
+    Context, Envelope, Event, IdGenerator, IdGeneratorFn, Input, Metadata, Runtime, Sieve,
+    SpamStatus, VirusStatus, MAX_LOCAL_VARIABLES, MAX_MATCH_VARIABLES,
 };
 
 use super::{
     actions::action_include::IncludeResult,
     tests::{test_envelope::parse_envelope_address, TestResult},
+    text_cache::TextCache,
+    trace::Trace,
     RuntimeError, Variable,
 };
 
@@ -61,15 +65,23 @@ impl<'x> Context<'x> {
             .into(),
             queued_events: vec![].into_iter(),
             has_changes: false,
+            text_cache: TextCache::default(),
+            imap_sieve: false,
             user_address: "".into(),
             user_full_name: "".into(),
             current_time: SystemTime::now()
                 .duration_since(SystemTime::UNIX_EPOCH)
                 .map(|d| d.as_secs())
                 .unwrap_or(0) as i64,
+            time_zone: None,
             num_redirects: 0,
+            final_position: None,
+            trace: runtime.trace.then(Vec::new),
+            filter_result: None,
             num_instructions: 0,
             num_out_messages: 0,
+            id_generator: None,
+            num_generated_ids: 0,
             last_message_id: 0,
             main_message_id: 0,
             virus_status: VirusStatus::Unknown,
@@ -77,11 +89,34 @@ impl<'x> Context<'x> {
         }
     }
 
-    #[allow(clippy::while_let_on_iterator)]
     pub fn run(&mut self, input: Input) -> Option<Result<Event, RuntimeError>> {
+        if self.trace.is_none() && self.filter_result.is_none() {
+            return self.run_next(input);
+        }
+
+        let result = self.run_next(input);
+        match &result {
+            Some(Ok(event)) => {
+                self.trace_event(event);
+                self.collect_event(event);
+            }
+            Some(Err(error)) => self.collect_error(error),
+            None => (),
+        }
+        result
+    }
+
+    #[allow(clippy::while_let_on_iterator)]
+    fn run_next(&mut self, input: Input) -> Option<Result<Event, RuntimeError>> {
         match input {
-            Input::True => self.test_result ^= true,
-            Input::False => self.test_result ^= false,
+            Input::True => {
+                self.test_result ^= true;
+                self.trace_test_result();
+            }
+            Input::False => {
+                self.test_result ^= false;
+                self.trace_test_result();
+            }
             Input::FncResult(result) => {
                 self.expr_stack.push(result);
             }
@@ -124,11 +159,11 @@ impl<'x> Context<'x> {
         'outer: loop {
             while let Some(instruction) = iter.next() {
                 self.num_instructions += 1;
+                self.pos += 1;
                 if self.num_instructions > self.runtime.cpu_limit {
                     self.finish_loop();
                     return Some(Err(RuntimeError::CPULimitReached));
                 }
-                self.pos += 1;
 
                 match instruction {
                     Instruction::Jz(jmp_pos) => {
@@ -153,7 +188,11 @@ impl<'x> Context<'x> {
                         iter = current_script.instructions.get(self.pos..)?.iter();
                         continue;
                     }
-                    Instruction::Test(test) => match test.exec(self) {
+                    Instruction::Test(test) => match if self.trace.is_some() {
+                        self.exec_test_traced(test)
+                    } else {
+                        test.exec(self)
+                    } {
                         TestResult::Bool(result) => {
                             self.test_result = result;
                         }
@@ -169,6 +208,9 @@ impl<'x> Context<'x> {
                     Instruction::Eval(expr) => match self.eval_expression(expr) {
                         Ok(result) => {
                             self.test_result = result.to_bool();
+                            if self.trace.is_some() {
+                                self.trace_eval();
+                            }
                         }
                         Err(event) => {
                             return Some(Ok(event));
@@ -224,6 +266,16 @@ impl<'x> Context<'x> {
                         break 'outer;
                     }
                     Instruction::Reject(reject) => {
+                        if self.imap_sieve {
+                            self.finish_loop();
+                            return Some(Err(RuntimeError::CapabilityNotAllowed(
+                                if reject.ereject {
+                                    Capability::Ereject
+                                } else {
+                                    Capability::Reject
+                                },
+                            )));
+                        }
                         self.final_event = None;
                         return Some(Ok(Event::Reject {
                             extended: reject.ereject,
@@ -301,7 +353,12 @@ impl<'x> Context<'x> {
                         }
                     }
                     Instruction::AddHeader(add_header) => add_header.exec(self),
-                    Instruction::DeleteHeader(delete_header) => delete_header.exec(self),
+                    Instruction::DeleteHeader(delete_header) => {
+                        if let Err(err) = delete_header.exec(self) {
+                            self.finish_loop();
+                            return Some(Err(err));
+                        }
+                    }
                     Instruction::Set(set) => {
                         set.exec(self);
                         if let Some(event) = self.queued_events.next() {
@@ -440,7 +497,14 @@ impl<'x> Context<'x> {
         }
     }
 
+    /// Flags the message as modified and drops the text cached from it.
+    pub(crate) fn message_changed(&mut self) {
+        self.has_changes = true;
+        self.text_cache.clear();
+    }
+
     pub(crate) fn finish_loop(&mut self) {
+        self.final_position = self.current_position();
         self.script_stack.clear();
         if let Some(event) = self.final_event.take() {
             self.queued_events = if let Event::Keep {
@@ -515,6 +579,49 @@ impl<'x> Context<'x> {
         self.envelope.clear()
     }
 
+    /// Sets the time, as a UNIX timestamp, used by `currentdate` and in the
+    /// Date headers of the messages built by a script.
+    pub fn set_current_time(&mut self, timestamp: i64) {
+        self.current_time = timestamp;
+    }
+
+    pub fn with_current_time(mut self, timestamp: i64) -> Self {
+        self.current_time = timestamp;
+        self
+    }
+
+    pub fn current_time(&self) -> i64 {
+        self.current_time
+    }
+
+    /// Overrides the Runtime's ID generator for this run.
+    pub fn set_id_generator(&mut self, generator: IdGenerator) {
+        self.id_generator = Some(IdGeneratorFn(generator));
+    }
+
+    pub fn with_id_generator(mut self, generator: IdGenerator) -> Self {
+        self.set_id_generator(generator);
+        self
+    }
+
+    /// Sets the user's time zone, either an IANA name such as "Europe/Berlin"
+    /// or an offset in "+hhmm" format. It is used by the `date` and
+    /// `currentdate` tests when the script does not specify a zone. Returns
+    /// `false`, leaving the current zone unchanged, if the zone is unknown.
+    pub fn set_time_zone(&mut self, zone: &str) -> bool {
+        if let Some(zone) = Zone::parse(zone) {
+            self.time_zone = Some(zone);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn with_time_zone(mut self, zone: &str) -> Self {
+        self.set_time_zone(zone);
+        self
+    }
+
     pub fn set_user_address(&mut self, from: impl Into<Cow<'x, str>>) {
         self.user_address = from.into();
     }
@@ -640,6 +747,37 @@ impl<'x> Context<'x> {
     pub fn part(&self) -> u32 {
         self.part
     }
+
+    pub fn set_trace(&mut self, value: bool) {
+        if !value {
+            self.trace = None;
+        } else if self.trace.is_none() {
+            self.trace = Some(Vec::new());
+        }
+    }
+
+    pub fn with_trace(mut self, value: bool) -> Self {
+        self.set_trace(value);
+        self
+    }
+
+    pub fn trace(&self) -> &[Trace] {
+        self.trace.as_deref().unwrap_or_default()
+    }
+
+    pub fn take_trace(&mut self) -> Vec<Trace> {
+        self.trace.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    pub fn current_position(&self) -> Option<SourcePosition> {
+        if let Some(script_stack) = self.script_stack.last() {
+            script_stack
+                .script
+                .source_position(self.pos.checked_sub(1)?)
+        } else {
+            self.final_position
+        }
+    }
 }
 
 #[cfg(test)]
@@ -671,15 +809,23 @@ impl<'x> Context<'x> {
             .into(),
             queued_events: vec![].into_iter(),
             has_changes: false,
+            text_cache: TextCache::default(),
+            imap_sieve: false,
             user_address: "".into(),
             user_full_name: "".into(),
             current_time: SystemTime::now()
                 .duration_since(SystemTime::UNIX_EPOCH)
                 .map(|d| d.as_secs())
                 .unwrap_or(0) as i64,
+            time_zone: None,
             num_redirects: 0,
+            final_position: None,
+            trace: runtime.trace.then(Vec::new),
+            filter_result: None,
             num_instructions: 0,
             num_out_messages: 0,
+            id_generator: None,
+            num_generated_ids: 0,
             last_message_id: 0,
             main_message_id: 0,
             virus_status: VirusStatus::Unknown,
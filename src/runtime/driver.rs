@@ -0,0 +1,107 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::{future::Future, sync::Arc};
+
+use crate::{Context, Envelope, Event, ExternalId, Input, Mailbox, MatchAs, Script, Sieve};
+
+use super::{result::FilterResult, Variable};
+
+/// Host-side handlers for the events a script can raise while running.
+/// The default implementations behave as if the host supported none of
+/// these features.
+#[allow(unused_variables)]
+pub trait Driver: Send {
+    /// Returns the compiled script to include, or `None` if it does not exist.
+    fn include_script(
+        &mut self,
+        name: &Script,
+        optional: bool,
+    ) -> impl Future<Output = Option<Arc<Sieve>>> + Send {
+        async { None }
+    }
+
+    fn mailbox_exists(
+        &mut self,
+        mailboxes: &[Mailbox],
+        special_use: &[String],
+    ) -> impl Future<Output = bool> + Send {
+        async { false }
+    }
+
+    fn list_contains(
+        &mut self,
+        lists: &[String],
+        values: &[String],
+        match_as: MatchAs,
+    ) -> impl Future<Output = bool> + Send {
+        async { false }
+    }
+
+    /// Returns `true` if the ID has been seen before.
+    fn duplicate_id(
+        &mut self,
+        id: &str,
+        expiry: u64,
+        last: bool,
+    ) -> impl Future<Output = bool> + Send {
+        async { false }
+    }
+
+    fn function(
+        &mut self,
+        id: ExternalId,
+        arguments: Vec<Variable>,
+    ) -> impl Future<Output = Variable> + Send {
+        async { Variable::default() }
+    }
+
+    fn set_envelope(&mut self, envelope: Envelope, value: &str) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+}
+
+impl Context<'_> {
+    /// Runs the script to completion, handing host-side events to `driver`
+    /// and returning the collected actions and created messages.
+    pub async fn run_driver(&mut self, input: Input, driver: &mut impl Driver) -> FilterResult {
+        let mut input = input;
+        self.set_filter_result(true);
+
+        while let Some(event) = self.run(input) {
+            input = match event {
+                Ok(Event::IncludeScript { name, optional }) => {
+                    match driver.include_script(&name, optional).await {
+                        Some(script) => Input::script(name, script),
+                        None => Input::False,
+                    }
+                }
+                Ok(Event::MailboxExists {
+                    mailboxes,
+                    special_use,
+                }) => driver.mailbox_exists(&mailboxes, &special_use).await.into(),
+                Ok(Event::ListContains {
+                    lists,
+                    values,
+                    match_as,
+                }) => driver.list_contains(&lists, &values, match_as).await.into(),
+                Ok(Event::DuplicateId { id, expiry, last }) => {
+                    driver.duplicate_id(&id, expiry, last).await.into()
+                }
+                Ok(Event::Function { id, arguments }) => {
+                    Input::result(driver.function(id, arguments).await)
+                }
+                Ok(Event::SetEnvelope { envelope, value }) => {
+                    driver.set_envelope(envelope, &value).await;
+                    Input::True
+                }
+                Ok(_) | Err(_) => Input::True,
+            };
+        }
+
+        self.take_filter_result()
+    }
+}
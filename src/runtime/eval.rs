@@ -114,13 +114,14 @@ impl<'x> Context<'x> {
                         Value::Number(n) => {
                             data.push_str(&n.to_string());
                         }
-                        Value::Regex(_) => (),
+                        Value::Regex(_) | Value::Glob(_) => (),
                     }
                 }
                 data.into()
             }
             Value::Number(n) => Variable::from(*n),
             Value::Regex(r) => Variable::String(r.expr.clone().into()),
+            Value::Glob(g) => Variable::String(g.expr.clone().into()),
         }
     }
 
@@ -0,0 +1,110 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use crate::Context;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ImapCause {
+    Append,
+    Copy,
+    Flag,
+}
+
+/// IMAP event that triggered a script, as defined in RFC 6785.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImapEvent {
+    pub(crate) cause: ImapCause,
+    pub(crate) mailbox: String,
+    pub(crate) user: String,
+    pub(crate) email: String,
+    pub(crate) flags: Vec<String>,
+    pub(crate) changed_flags: Vec<String>,
+}
+
+impl ImapEvent {
+    pub fn new(cause: ImapCause, mailbox: impl Into<String>) -> Self {
+        ImapEvent {
+            cause,
+            mailbox: mailbox.into(),
+            user: String::new(),
+            email: String::new(),
+            flags: Vec::new(),
+            changed_flags: Vec::new(),
+        }
+    }
+
+    pub fn with_user(mut self, user: impl Into<String>) -> Self {
+        self.user = user.into();
+        self
+    }
+
+    pub fn with_email(mut self, email: impl Into<String>) -> Self {
+        self.email = email.into();
+        self
+    }
+
+    /// Flags currently set on the message.
+    pub fn with_flags(mut self, flags: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.flags = flags.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Flags changed by a `FLAG` event.
+    pub fn with_changed_flags(
+        mut self,
+        flags: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.changed_flags = flags.into_iter().map(Into::into).collect();
+        self
+    }
+}
+
+impl ImapCause {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImapCause::Append => "APPEND",
+            ImapCause::Copy => "COPY",
+            ImapCause::Flag => "FLAG",
+        }
+    }
+}
+
+impl Context<'_> {
+    /// Filters a message already stored in a mailbox, following the semantics
+    /// of RFC 6785. The implicit keep leaves the message where it is, so a
+    /// `Keep` event means the message stays in `event.mailbox` with the
+    /// returned flags while its absence means it has to be removed from it.
+    /// The `imap4flags` internal variable starts with the message's current
+    /// flags, `reject` and `ereject` are runtime errors and no vacation
+    /// responses are sent.
+    pub fn set_imap_event(&mut self, event: ImapEvent) {
+        self.imap_sieve = true;
+        self.vars_env
+            .insert("imap.cause".into(), event.cause.as_str().into());
+        self.vars_env
+            .insert("imap.mailbox".into(), event.mailbox.into());
+        self.vars_env.insert("imap.user".into(), event.user.into());
+        self.vars_env
+            .insert("imap.email".into(), event.email.into());
+        self.vars_env.insert(
+            "imap.changedflags".into(),
+            event.changed_flags.join(" ").into(),
+        );
+        self.vars_env.insert("location".into(), "MS".into());
+        self.vars_env.insert("phase".into(), "post".into());
+        self.vars_global
+            .insert("__flags".into(), event.flags.join(" ").into());
+    }
+
+    pub fn with_imap_event(mut self, event: ImapEvent) -> Self {
+        self.set_imap_event(event);
+        self
+    }
+
+    pub fn is_imap_event(&self) -> bool {
+        self.imap_sieve
+    }
+}
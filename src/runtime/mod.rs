@@ -6,9 +6,15 @@
 
 pub mod actions;
 pub mod context;
+pub mod driver;
 pub mod eval;
 pub mod expression;
+pub mod imapsieve;
+pub(crate) mod regex_cache;
+pub mod result;
 pub mod tests;
+pub(crate) mod text_cache;
+pub mod trace;
 pub mod variables;
 
 use ahash::{AHashMap, AHashSet};
@@ -22,13 +28,14 @@ use crate::Context;
 
 use crate::{
     compiler::{
-        grammar::{expr::parser::ID_EXTERNAL, Capability, Invalid},
+        grammar::{expr::parser::ID_EXTERNAL, Capability, Comparator, Invalid},
         Number,
     },
-    ExternalId, Function, FunctionMap, Input, Metadata, Runtime, Script, Sieve,
+    CustomComparator, ExternalId, Function, FunctionMap, IdGenerator, IdGeneratorFn, Input,
+    Metadata, Runtime, Script, Sieve,
 };
 
-use self::eval::ToString;
+use self::{eval::ToString, regex_cache::RegexCache};
 
 #[derive(Debug, Clone)]
 #[cfg_attr(
@@ -42,7 +49,10 @@ pub enum Variable {
     Array(Arc<Vec<Variable>>),
 }
 
-#[derive(Debug)]
+/// Errors that stop a script. The enum is non-exhaustive as of 0.8.0, so
+/// matches on it need a wildcard arm.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
 pub enum RuntimeError {
     TooManyIncludes,
     InvalidInstruction(Invalid),
@@ -50,6 +60,8 @@ pub enum RuntimeError {
     CapabilityNotAllowed(Capability),
     CapabilityNotSupported(String),
     CPULimitReached,
+    InvalidRegex(String),
+    RegexLimitReached,
 }
 
 impl Default for Variable {
@@ -299,6 +311,12 @@ impl Hash for Variable {
     }
 }
 
+impl std::fmt::Debug for IdGeneratorFn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("IdGenerator")
+    }
+}
+
 #[cfg(not(test))]
 impl Runtime {
     pub fn filter<'z: 'x, 'x>(&'z self, raw_message: &'x [u8]) -> Context<'x> {
@@ -365,13 +383,38 @@ impl Runtime {
             vacation_subject_prefix: "Auto: ".into(),
             max_header_size: 1024,
             max_out_messages: 3,
+            max_regex_size: usize::MAX,
+            regex_backtrack_limit: 1_000_000,
+            linear_regex: false,
+            regex_cache: Arc::new(RegexCache::new(1024)),
             default_vacation_expiry: 30 * 86400,
             default_duplicate_expiry: 7 * 86400,
             local_hostname: "localhost".into(),
             functions: Vec::new(),
+            comparators: AHashMap::new(),
+            id_generator: None,
+            trace: false,
         }
     }
 
+    pub fn set_trace(&mut self, value: bool) {
+        self.trace = value;
+    }
+
+    pub fn with_trace(mut self, value: bool) -> Self {
+        self.trace = value;
+        self
+    }
+
+    pub fn set_id_generator(&mut self, generator: IdGenerator) {
+        self.id_generator = Some(IdGeneratorFn(generator));
+    }
+
+    pub fn with_id_generator(mut self, generator: IdGenerator) -> Self {
+        self.set_id_generator(generator);
+        self
+    }
+
     pub fn set_cpu_limit(&mut self, size: usize) {
         self.cpu_limit = size;
     }
@@ -399,6 +442,63 @@ impl Runtime {
         self
     }
 
+    /// Maximum length in bytes of a `:regex` pattern built from variables
+    /// at runtime, unlimited by default.
+    pub fn set_max_regex_size(&mut self, size: usize) {
+        self.max_regex_size = size;
+    }
+
+    pub fn with_max_regex_size(mut self, size: usize) -> Self {
+        self.max_regex_size = size;
+        self
+    }
+
+    /// Maximum number of backtracking steps a `:regex` pattern built at
+    /// runtime may take on a single value. Constant patterns use the limit
+    /// configured in the [`Compiler`](crate::Compiler).
+    pub fn set_regex_backtrack_limit(&mut self, limit: usize) {
+        self.regex_backtrack_limit = limit;
+    }
+
+    pub fn with_regex_backtrack_limit(mut self, limit: usize) -> Self {
+        self.regex_backtrack_limit = limit;
+        self
+    }
+
+    /// Rejects `:regex` patterns built at runtime that use look-around,
+    /// backreferences or any other feature that requires backtracking.
+    pub fn set_linear_regex(&mut self, value: bool) {
+        self.linear_regex = value;
+    }
+
+    pub fn with_linear_regex(mut self, value: bool) -> Self {
+        self.linear_regex = value;
+        self
+    }
+
+    /// Maximum number of `:regex` patterns built at runtime that are kept
+    /// compiled. The cache is shared with all clones of this runtime, a
+    /// size of zero disables it.
+    pub fn set_regex_cache_size(&mut self, size: usize) {
+        self.regex_cache = Arc::new(RegexCache::new(size));
+    }
+
+    pub fn with_regex_cache_size(mut self, size: usize) -> Self {
+        self.regex_cache = Arc::new(RegexCache::new(size));
+        self
+    }
+
+    /// Number of `:regex` patterns built at runtime that were found in the
+    /// cache.
+    pub fn regex_cache_hits(&self) -> u64 {
+        self.regex_cache.hits()
+    }
+
+    /// Number of `:regex` patterns built at runtime that had to be compiled.
+    pub fn regex_cache_misses(&self) -> u64 {
+        self.regex_cache.misses()
+    }
+
     pub fn set_max_out_messages(&mut self, size: usize) {
         self.max_out_messages = size;
     }
@@ -607,6 +707,24 @@ impl Runtime {
         self
     }
 
+    /// Registers a comparator that scripts can use after requiring
+    /// `comparator-<name>`.
+    pub fn set_comparator(&mut self, name: impl Into<String>, comparator: CustomComparator) {
+        let name = name.into();
+        self.allowed_capabilities
+            .insert(Capability::Comparator(Comparator::Other(name.clone())));
+        self.comparators.insert(name, comparator);
+    }
+
+    pub fn with_comparator(
+        mut self,
+        name: impl Into<String>,
+        comparator: CustomComparator,
+    ) -> Self {
+        self.set_comparator(name, comparator);
+        self
+    }
+
     pub fn with_functions(mut self, fnc_map: &mut FunctionMap) -> Self {
         self.functions = std::mem::take(&mut fnc_map.functions);
         self
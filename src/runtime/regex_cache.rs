@@ -0,0 +1,146 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::{
+    fmt::Debug,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use lru::LruCache;
+use regex_automata::hybrid::dfa::DFA;
+
+use super::RuntimeError;
+
+/// Least recently used cache of the regular expressions built at runtime,
+/// shared by all the contexts created from a runtime.
+pub(crate) struct RegexCache {
+    entries: Option<Mutex<LruCache<RegexKey, CachedRegex>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// The limits are part of the key as runtimes cloned with different
+/// settings share the same cache.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct RegexKey {
+    pub expr: String,
+    pub max_size: usize,
+    pub backtrack_limit: usize,
+    pub linear: bool,
+    pub posix: bool,
+    pub kind: RegexKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum RegexKind {
+    Search,
+    /// POSIX expression matching a whole slice of the value.
+    Anchored {
+        match_start: bool,
+        match_end: bool,
+    },
+    /// POSIX expression finding the end of the longest match.
+    Longest,
+}
+
+#[derive(Clone)]
+enum CachedRegex {
+    Regex(Arc<fancy_regex::Regex>),
+    Longest(Arc<DFA>),
+}
+
+impl RegexCache {
+    pub(crate) fn new(size: usize) -> Self {
+        RegexCache {
+            entries: NonZeroUsize::new(size).map(|size| Mutex::new(LruCache::new(size))),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn get_or_build(
+        &self,
+        key: RegexKey,
+        build: impl FnOnce(&RegexKey) -> Result<fancy_regex::Regex, RuntimeError>,
+    ) -> Result<Arc<fancy_regex::Regex>, RuntimeError> {
+        match self.get_or_insert(key, |key| {
+            build(key).map(|regex| CachedRegex::Regex(Arc::new(regex)))
+        })? {
+            CachedRegex::Regex(regex) => Ok(regex),
+            CachedRegex::Longest(_) => unreachable!("regex kind is part of the key"),
+        }
+    }
+
+    pub(crate) fn get_or_build_longest(
+        &self,
+        key: RegexKey,
+        build: impl FnOnce(&RegexKey) -> Result<DFA, RuntimeError>,
+    ) -> Result<Arc<DFA>, RuntimeError> {
+        match self.get_or_insert(key, |key| {
+            build(key).map(|dfa| CachedRegex::Longest(Arc::new(dfa)))
+        })? {
+            CachedRegex::Longest(dfa) => Ok(dfa),
+            CachedRegex::Regex(_) => unreachable!("regex kind is part of the key"),
+        }
+    }
+
+    fn get_or_insert(
+        &self,
+        key: RegexKey,
+        build: impl FnOnce(&RegexKey) -> Result<CachedRegex, RuntimeError>,
+    ) -> Result<CachedRegex, RuntimeError> {
+        if let Some(regex) = self
+            .entries
+            .as_ref()
+            .and_then(|entries| entries.lock().ok()?.get(&key).cloned())
+        {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(regex);
+        }
+
+        // Build outside the lock, a concurrent miss on the same key
+        // just builds the expression twice.
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let regex = build(&key)?;
+        if let Some(mut entries) = self
+            .entries
+            .as_ref()
+            .and_then(|entries| entries.lock().ok())
+        {
+            entries.put(key, regex.clone());
+        }
+        Ok(regex)
+    }
+
+    pub(crate) fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries
+            .as_ref()
+            .and_then(|entries| entries.lock().ok().map(|entries| entries.len()))
+            .unwrap_or(0)
+    }
+}
+
+impl Debug for RegexCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RegexCache")
+            .field("entries", &self.len())
+            .field("hits", &self.hits())
+            .field("misses", &self.misses())
+            .finish()
+    }
+}
@@ -0,0 +1,328 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use crate::{
+    compiler::grammar::actions::action_redirect::{ByTime, Notify, Ret},
+    Context, Event, Importance, Recipient,
+};
+
+use super::RuntimeError;
+
+/// Summary of the actions taken by a script, collected while the context
+/// runs. Obtained from [`Context::take_filter_result`] once `run` returns
+/// `None`.
+#[derive(Debug, Clone, Default)]
+pub struct FilterResult {
+    pub(crate) keep: Option<KeepAction>,
+    pub(crate) file_into: Vec<FileIntoAction>,
+    pub(crate) redirects: Vec<SendAction>,
+    pub(crate) reject: Option<RejectAction>,
+    pub(crate) vacation: Vec<SendAction>,
+    pub(crate) notifications: Vec<NotifyAction>,
+    pub(crate) mail_notifications: Vec<SendAction>,
+    pub(crate) messages: Vec<(usize, Vec<u8>)>,
+    pub(crate) modified_message_id: usize,
+    pub(crate) errors: Vec<RuntimeError>,
+    pub(crate) send_origins: Vec<(usize, SendOrigin)>,
+}
+
+/// Action that produced an outgoing message, messages without a recorded
+/// origin are redirects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SendOrigin {
+    Vacation,
+    Notify,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeepAction {
+    pub flags: Vec<String>,
+    pub message_id: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileIntoAction {
+    pub folder: String,
+    pub flags: Vec<String>,
+    pub mailbox_id: Option<String>,
+    pub special_use: Option<String>,
+    pub create: bool,
+    pub message_id: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SendAction {
+    pub recipient: Recipient,
+    pub notify: Notify,
+    pub return_of_content: Ret,
+    pub by_time: ByTime<i64>,
+    pub message_id: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectAction {
+    pub extended: bool,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotifyAction {
+    pub from: Option<String>,
+    pub importance: Importance,
+    pub options: Vec<String>,
+    pub message: String,
+    pub method: String,
+}
+
+impl Context<'_> {
+    pub fn set_filter_result(&mut self, value: bool) {
+        if !value {
+            self.filter_result = None;
+        } else if self.filter_result.is_none() {
+            self.filter_result = Some(FilterResult::default());
+        }
+    }
+
+    pub fn with_filter_result(mut self, value: bool) -> Self {
+        self.set_filter_result(value);
+        self
+    }
+
+    /// Returns the actions collected so far, with duplicate deliveries
+    /// merged as described in RFC 5228, section 4.2.
+    pub fn take_filter_result(&mut self) -> FilterResult {
+        let mut result = self
+            .filter_result
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default();
+        result.modified_message_id = self.main_message_id;
+        result.resolve_conflicts();
+        result
+    }
+
+    pub(crate) fn set_send_origin(&mut self, message_id: usize, origin: SendOrigin) {
+        if let Some(result) = &mut self.filter_result {
+            result.send_origins.push((message_id, origin));
+        }
+    }
+
+    pub(crate) fn collect_event(&mut self, event: &Event) {
+        let Some(result) = &mut self.filter_result else {
+            return;
+        };
+
+        match event {
+            Event::Keep { flags, message_id } => match &mut result.keep {
+                Some(keep) if keep.message_id == *message_id => {
+                    merge_flags(&mut keep.flags, flags.clone());
+                }
+                keep => {
+                    *keep = Some(KeepAction {
+                        flags: flags.clone(),
+                        message_id: *message_id,
+                    });
+                }
+            },
+            Event::Discard => {
+                result.keep = None;
+            }
+            Event::Reject { extended, reason } => {
+                if result.reject.is_none() {
+                    result.reject = Some(RejectAction {
+                        extended: *extended,
+                        reason: reason.clone(),
+                    });
+                }
+            }
+            Event::FileInto {
+                folder,
+                flags,
+                mailbox_id,
+                special_use,
+                create,
+                message_id,
+            } => {
+                result.file_into.push(FileIntoAction {
+                    folder: folder.clone(),
+                    flags: flags.clone(),
+                    mailbox_id: mailbox_id.clone(),
+                    special_use: special_use.clone(),
+                    create: *create,
+                    message_id: *message_id,
+                });
+            }
+            Event::SendMessage {
+                recipient,
+                notify,
+                return_of_content,
+                by_time,
+                message_id,
+            } => {
+                let action = SendAction {
+                    recipient: recipient.clone(),
+                    notify: notify.clone(),
+                    return_of_content: return_of_content.clone(),
+                    by_time: by_time.clone(),
+                    message_id: *message_id,
+                };
+                match result
+                    .send_origins
+                    .iter()
+                    .find(|(id, _)| id == message_id)
+                    .map(|(_, origin)| origin)
+                {
+                    Some(SendOrigin::Vacation) => result.vacation.push(action),
+                    Some(SendOrigin::Notify) => result.mail_notifications.push(action),
+                    None => result.redirects.push(action),
+                }
+            }
+            Event::Notify {
+                from,
+                importance,
+                options,
+                message,
+                method,
+            } => {
+                result.notifications.push(NotifyAction {
+                    from: from.clone(),
+                    importance: *importance,
+                    options: options.clone(),
+                    message: message.clone(),
+                    method: method.clone(),
+                });
+            }
+            Event::CreatedMessage {
+                message_id,
+                message,
+            } => {
+                result.messages.push((*message_id, message.clone()));
+            }
+            Event::IncludeScript { .. }
+            | Event::MailboxExists { .. }
+            | Event::ListContains { .. }
+            | Event::DuplicateId { .. }
+            | Event::SetEnvelope { .. }
+            | Event::Function { .. } => (),
+        }
+    }
+
+    pub(crate) fn collect_error(&mut self, error: &RuntimeError) {
+        if let Some(result) = &mut self.filter_result {
+            result.errors.push(error.clone());
+        }
+    }
+}
+
+impl FilterResult {
+    fn resolve_conflicts(&mut self) {
+        // Filing the same message into a mailbox more than once, or into
+        // INBOX when it is also kept, results in a single delivery
+        let mut file_into: Vec<FileIntoAction> = Vec::with_capacity(self.file_into.len());
+        for action in std::mem::take(&mut self.file_into) {
+            if let Some(keep) = self.keep.as_mut().filter(|keep| {
+                keep.message_id == action.message_id
+                    && action.mailbox_id.is_none()
+                    && action.special_use.is_none()
+                    && action.folder.eq_ignore_ascii_case("INBOX")
+            }) {
+                merge_flags(&mut keep.flags, action.flags);
+            } else if let Some(prev_action) = file_into.iter_mut().find(|prev_action| {
+                prev_action.message_id == action.message_id
+                    && prev_action.folder == action.folder
+                    && prev_action.mailbox_id == action.mailbox_id
+                    && prev_action.special_use == action.special_use
+            }) {
+                prev_action.create |= action.create;
+                merge_flags(&mut prev_action.flags, action.flags);
+            } else {
+                file_into.push(action);
+            }
+        }
+        self.file_into = file_into;
+
+        // Redirecting the same message to the same address is done only once
+        let mut redirects: Vec<SendAction> = Vec::with_capacity(self.redirects.len());
+        for action in std::mem::take(&mut self.redirects) {
+            if !redirects.iter().any(|prev_action| {
+                prev_action.message_id == action.message_id
+                    && prev_action.recipient == action.recipient
+            }) {
+                redirects.push(action);
+            }
+        }
+        self.redirects = redirects;
+    }
+
+    /// The message is kept in the user's default mailbox, either because
+    /// of an explicit `keep` or because no action cancelled the implicit keep.
+    pub fn keep(&self) -> Option<&KeepAction> {
+        self.keep.as_ref()
+    }
+
+    pub fn file_into(&self) -> &[FileIntoAction] {
+        &self.file_into
+    }
+
+    pub fn redirects(&self) -> &[SendAction] {
+        &self.redirects
+    }
+
+    pub fn reject(&self) -> Option<&RejectAction> {
+        self.reject.as_ref()
+    }
+
+    pub fn vacation(&self) -> &[SendAction] {
+        &self.vacation
+    }
+
+    /// Notifications delivered through a method other than `mailto`.
+    pub fn notifications(&self) -> &[NotifyAction] {
+        &self.notifications
+    }
+
+    /// Notifications sent as e-mail messages using the `mailto` method.
+    pub fn mail_notifications(&self) -> &[SendAction] {
+        &self.mail_notifications
+    }
+
+    /// Returns the contents of a message created by the script, where
+    /// `message_id` is the value found in the collected actions.
+    pub fn message(&self, message_id: usize) -> Option<&[u8]> {
+        self.messages
+            .iter()
+            .find(|(id, _)| *id == message_id)
+            .map(|(_, message)| message.as_slice())
+    }
+
+    pub fn messages(&self) -> impl Iterator<Item = (usize, &[u8])> {
+        self.messages
+            .iter()
+            .map(|(id, message)| (*id, message.as_slice()))
+    }
+
+    /// Returns the last version of the incoming message if the script
+    /// modified it.
+    pub fn modified_message(&self) -> Option<&[u8]> {
+        if self.modified_message_id > 0 {
+            self.message(self.modified_message_id)
+        } else {
+            None
+        }
+    }
+
+    pub fn errors(&self) -> &[RuntimeError] {
+        &self.errors
+    }
+}
+
+fn merge_flags(flags: &mut Vec<String>, new_flags: Vec<String>) {
+    for flag in new_flags {
+        if !flags.iter().any(|f| f.eq_ignore_ascii_case(&flag)) {
+            flags.push(flag);
+        }
+    }
+}
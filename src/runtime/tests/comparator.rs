@@ -4,29 +4,462 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use std::{borrow::Cow, sync::Arc};
+use std::{borrow::Cow, cell::Cell, cmp::Ordering, sync::Arc};
+
+use regex_automata::{hybrid::dfa::DFA, Anchored, Input};
 
 use crate::{
     compiler::{
         grammar::{Comparator, RelationalMatch},
-        Number, Value,
+        Number, Regex, Value,
+    },
+    runtime::{
+        regex_cache::{RegexCache, RegexKey, RegexKind},
+        RuntimeError, Variable,
     },
-    runtime::Variable,
-    MatchAs,
+    Context, CustomComparator, MatchAs,
 };
 
-use super::glob::GlobPattern;
+use unicode_normalization::UnicodeNormalization;
+
+use super::glob::{CaseFold, GlobPattern};
 
 pub(crate) trait Comparable {
-    fn to_str(&self) -> Cow<str>;
+    fn to_str(&self) -> Cow<'_, str>;
     fn to_number(&self) -> Number;
 }
 
+/// Comparator used by a test, with custom comparators and regular
+/// expression limits taken from the runtime. Errors raised while
+/// evaluating regular expressions are kept until the test calls
+/// `take_error`.
+pub(crate) struct ComparatorRef<'x> {
+    comparator: &'x Comparator,
+    custom: Option<CustomComparator>,
+    max_regex_size: usize,
+    regex_backtrack_limit: usize,
+    linear_regex: bool,
+    posix_regex: bool,
+    regex_cache: Arc<RegexCache>,
+    error: Cell<Option<RuntimeError>>,
+}
+
+impl Context<'_> {
+    pub(crate) fn comparator<'y>(&self, comparator: &'y Comparator) -> ComparatorRef<'y> {
+        ComparatorRef {
+            comparator,
+            custom: if let Comparator::Other(name) = comparator {
+                self.runtime.comparators.get(name).copied()
+            } else {
+                None
+            },
+            max_regex_size: self.runtime.max_regex_size,
+            regex_backtrack_limit: self.runtime.regex_backtrack_limit,
+            linear_regex: self.runtime.linear_regex,
+            posix_regex: self
+                .script_stack
+                .last()
+                .is_some_and(|script_stack| script_stack.script.posix_regex),
+            regex_cache: self.runtime.regex_cache.clone(),
+            error: Cell::new(None),
+        }
+    }
+}
+
+impl CustomComparator {
+    /// Creates a comparator that matches values after mapping them with
+    /// `normalize`, for example by removing everything but the digits of
+    /// a phone number. Values captured by `:matches` are normalized.
+    pub fn new(normalize: fn(&str) -> String) -> Self {
+        CustomComparator {
+            normalize,
+            ordering: None,
+            match_as: MatchAs::Octet,
+        }
+    }
+
+    /// Orders values for `:is` and `:value`. Without an ordering function
+    /// the normalized values are compared octet by octet.
+    pub fn with_ordering(mut self, ordering: fn(&str, &str) -> Ordering) -> Self {
+        self.ordering = Some(ordering);
+        self
+    }
+
+    /// How external lists should compare values when this comparator
+    /// is used with `:list`.
+    pub fn with_match_as(mut self, match_as: MatchAs) -> Self {
+        self.match_as = match_as;
+        self
+    }
+
+    fn is(&self, a: &str, b: &str) -> bool {
+        if let Some(ordering) = self.ordering {
+            ordering(a, b) == Ordering::Equal
+        } else {
+            (self.normalize)(a) == (self.normalize)(b)
+        }
+    }
+
+    fn relational(&self, relation: &RelationalMatch, a: &str, b: &str) -> bool {
+        if let Some(ordering) = self.ordering {
+            relation.cmp(&ordering(a, b), &Ordering::Equal)
+        } else {
+            relation.cmp(&(self.normalize)(a), &(self.normalize)(b))
+        }
+    }
+
+    fn normalize_pattern(&self, pattern: &str) -> String {
+        let mut result = String::with_capacity(pattern.len());
+        let mut literal = String::new();
+        let mut is_escaped = false;
+
+        for char in pattern.chars() {
+            match char {
+                '*' | '?' if !is_escaped => {
+                    self.push_literal(&mut result, &literal);
+                    literal.clear();
+                    result.push(char);
+                }
+                '\\' if !is_escaped => {
+                    is_escaped = true;
+                    continue;
+                }
+                _ => {
+                    literal.push(char);
+                }
+            }
+            is_escaped = false;
+        }
+        self.push_literal(&mut result, &literal);
+
+        result
+    }
+
+    fn push_literal(&self, result: &mut String, literal: &str) {
+        if !literal.is_empty() {
+            for char in (self.normalize)(literal).chars() {
+                if matches!(char, '*' | '?' | '\\') {
+                    result.push('\\');
+                }
+                result.push(char);
+            }
+        }
+    }
+}
+
+impl ComparatorRef<'_> {
+    pub(crate) fn is(&self, a: &impl Comparable, b: &impl Comparable) -> bool {
+        if let Some(custom) = &self.custom {
+            custom.is(a.to_str().as_ref(), b.to_str().as_ref())
+        } else {
+            self.comparator.is(a, b)
+        }
+    }
+
+    pub(crate) fn contains(&self, haystack: &str, needle: &str) -> bool {
+        if let Some(custom) = &self.custom {
+            needle.is_empty() || (custom.normalize)(haystack).contains(&(custom.normalize)(needle))
+        } else {
+            self.comparator.contains(haystack, needle)
+        }
+    }
+
+    pub(crate) fn relational(
+        &self,
+        relation: &RelationalMatch,
+        a: &impl Comparable,
+        b: &impl Comparable,
+    ) -> bool {
+        if let Some(custom) = &self.custom {
+            custom.relational(relation, a.to_str().as_ref(), b.to_str().as_ref())
+        } else {
+            self.comparator.relational(relation, a, b)
+        }
+    }
+
+    pub(crate) fn matches(
+        &self,
+        value: &str,
+        pattern: &str,
+        capture_positions: u64,
+        captured_values: &mut Vec<(usize, String)>,
+    ) -> bool {
+        if let Some(custom) = &self.custom {
+            let pattern = GlobPattern::compile(&custom.normalize_pattern(pattern), CaseFold::None);
+            let value = (custom.normalize)(value);
+            if capture_positions == 0 {
+                pattern.matches(&value)
+            } else {
+                pattern.capture(&value, capture_positions, captured_values)
+            }
+        } else {
+            self.comparator
+                .matches(value, pattern, capture_positions, captured_values)
+        }
+    }
+
+    /// Matches a `:matches` pattern, using the pattern compiled with the
+    /// script when the key is a constant.
+    pub(crate) fn glob(
+        &self,
+        pattern: &Value,
+        pattern_expr: &Variable,
+        value: &str,
+        capture_positions: u64,
+        captured_values: &mut Vec<(usize, String)>,
+    ) -> bool {
+        match pattern {
+            Value::Glob(glob) if self.custom.is_none() => {
+                if capture_positions == 0 {
+                    glob.pattern.matches(value)
+                } else {
+                    glob.pattern
+                        .capture(value, capture_positions, captured_values)
+                }
+            }
+            _ => self.matches(
+                value,
+                pattern_expr.to_string().as_ref(),
+                capture_positions,
+                captured_values,
+            ),
+        }
+    }
+
+    pub(crate) fn regex(
+        &self,
+        pattern: &Value,
+        pattern_expr: &Variable,
+        value: &str,
+        capture_positions: u64,
+        captured_values: &mut Vec<(usize, String)>,
+    ) -> bool {
+        let result = if let Value::Regex(regex) = pattern {
+            let lazy_regex = regex.regex.0.load();
+            if let Some(fancy_regex) = lazy_regex.as_ref() {
+                self.eval_regex(
+                    fancy_regex,
+                    &regex.expr,
+                    value,
+                    capture_positions,
+                    captured_values,
+                )
+            } else {
+                self.build_regex(&regex.expr).and_then(|fancy_regex| {
+                    let result = self.eval_regex(
+                        &fancy_regex,
+                        &regex.expr,
+                        value,
+                        capture_positions,
+                        captured_values,
+                    );
+                    regex.regex.0.store(Arc::new(Some(fancy_regex)));
+                    result
+                })
+            }
+        } else {
+            return self.regex_expr(
+                pattern_expr.to_string().as_ref(),
+                value,
+                capture_positions,
+                captured_values,
+            );
+        };
+
+        self.regex_result(result)
+    }
+
+    /// Matches a regular expression that is only known at runtime.
+    pub(crate) fn regex_expr(
+        &self,
+        expr: &str,
+        value: &str,
+        capture_positions: u64,
+        captured_values: &mut Vec<(usize, String)>,
+    ) -> bool {
+        let result = self.cached_regex(expr).and_then(|fancy_regex| {
+            self.eval_regex(
+                &fancy_regex,
+                expr,
+                value,
+                capture_positions,
+                captured_values,
+            )
+        });
+
+        self.regex_result(result)
+    }
+
+    fn regex_result(&self, result: Result<bool, RuntimeError>) -> bool {
+        match result {
+            Ok(result) => result,
+            Err(err) => {
+                self.error.set(Some(err));
+                false
+            }
+        }
+    }
+
+    fn build_regex(&self, expr: &str) -> Result<fancy_regex::Regex, RuntimeError> {
+        Regex::build(
+            expr,
+            self.max_regex_size,
+            self.regex_backtrack_limit,
+            self.linear_regex,
+            self.posix_regex,
+        )
+        .map_err(|err| RuntimeError::InvalidRegex(format!("{expr}: {err}")))
+    }
+
+    fn cached_regex(&self, expr: &str) -> Result<Arc<fancy_regex::Regex>, RuntimeError> {
+        self.regex_cache
+            .get_or_build(self.regex_key(expr, RegexKind::Search), |key| {
+                self.build_regex(&key.expr)
+            })
+    }
+
+    fn regex_key(&self, expr: &str, kind: RegexKind) -> RegexKey {
+        RegexKey {
+            expr: expr.to_string(),
+            max_size: self.max_regex_size,
+            backtrack_limit: self.regex_backtrack_limit,
+            linear: self.linear_regex,
+            posix: self.posix_regex,
+            kind,
+        }
+    }
+
+    fn eval_regex(
+        &self,
+        regex: &fancy_regex::Regex,
+        expr: &str,
+        value: &str,
+        capture_positions: u64,
+        captured_values: &mut Vec<(usize, String)>,
+    ) -> Result<bool, RuntimeError> {
+        if !self.posix_regex || capture_positions == 0 {
+            return eval_regex(regex, value, capture_positions, captured_values);
+        }
+
+        // POSIX picks the longest of the leftmost matches, its end is found
+        // by a DFA and the captures by matching the whole pattern against
+        // the slice between both ends.
+        let Some(leftmost) = regex.find(value).map_err(regex_error)? else {
+            return Ok(false);
+        };
+        let start = leftmost.start();
+        let longest = self.longest_regex(expr)?;
+        let mut cache = longest.create_cache();
+        let end = match longest.try_search_fwd(
+            &mut cache,
+            &Input::new(value).range(start..).anchored(Anchored::Yes),
+        ) {
+            Ok(Some(end)) if end.offset() >= leftmost.end() => end.offset(),
+            _ => leftmost.end(),
+        };
+        let anchored_regex = self.anchored_regex(expr, start == 0, end == value.len())?;
+        if eval_regex(
+            &anchored_regex,
+            &value[start..end],
+            capture_positions,
+            captured_values,
+        )? {
+            Ok(true)
+        } else {
+            eval_regex(regex, value, capture_positions, captured_values)
+        }
+    }
+
+    fn anchored_regex(
+        &self,
+        expr: &str,
+        match_start: bool,
+        match_end: bool,
+    ) -> Result<Arc<fancy_regex::Regex>, RuntimeError> {
+        self.regex_cache.get_or_build(
+            self.regex_key(
+                expr,
+                RegexKind::Anchored {
+                    match_start,
+                    match_end,
+                },
+            ),
+            |key| {
+                Regex::build_anchored(
+                    &key.expr,
+                    self.max_regex_size,
+                    self.regex_backtrack_limit,
+                    self.linear_regex,
+                    match_start,
+                    match_end,
+                )
+                .map_err(|err| RuntimeError::InvalidRegex(format!("{expr}: {err}")))
+            },
+        )
+    }
+
+    fn longest_regex(&self, expr: &str) -> Result<Arc<DFA>, RuntimeError> {
+        self.regex_cache
+            .get_or_build_longest(self.regex_key(expr, RegexKind::Longest), |key| {
+                Regex::build_longest(&key.expr, self.max_regex_size)
+                    .map_err(|err| RuntimeError::InvalidRegex(format!("{expr}: {err}")))
+            })
+    }
+
+    /// Returns the first error raised by a regular expression, if any.
+    pub(crate) fn take_error(&self) -> Option<RuntimeError> {
+        self.error.take()
+    }
+
+    /// Case folding applied to the values compared by `is`, `contains`
+    /// and `relational`, which can then be folded once and compared with
+    /// the `_folded` variants.
+    pub(crate) fn value_fold(&self) -> Option<CaseFold> {
+        if self.custom.is_none() {
+            self.comparator.value_fold()
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn is_folded(&self, value: &str, key: &impl Comparable) -> bool {
+        self.fold_key(key.to_str().as_ref()) == value
+    }
+
+    pub(crate) fn contains_folded(&self, haystack: &str, needle: &str) -> bool {
+        needle.is_empty() || haystack.contains(self.fold_key(needle).as_ref())
+    }
+
+    pub(crate) fn relational_folded(
+        &self,
+        relation: &RelationalMatch,
+        value: &str,
+        key: &impl Comparable,
+    ) -> bool {
+        relation.cmp(value, self.fold_key(key.to_str().as_ref()).as_ref())
+    }
+
+    fn fold_key<'y>(&self, key: &'y str) -> Cow<'y, str> {
+        self.value_fold().unwrap_or(CaseFold::None).fold(key)
+    }
+
+    pub(crate) fn as_match(&self) -> MatchAs {
+        if let Some(custom) = &self.custom {
+            custom.match_as
+        } else {
+            self.comparator.as_match()
+        }
+    }
+}
+
 impl Comparator {
     pub(crate) fn is(&self, a: &impl Comparable, b: &impl Comparable) -> bool {
         match self {
             Comparator::Octet => a.to_str() == b.to_str(),
             Comparator::AsciiNumeric => RelationalMatch::Eq.cmp(&a.to_number(), &b.to_number()),
+            Comparator::UnicodeCaseMap => {
+                unicode_casemap(a.to_str().as_ref()) == unicode_casemap(b.to_str().as_ref())
+            }
             _ => a.to_str().to_lowercase() == b.to_str().to_lowercase(),
         }
     }
@@ -35,6 +468,9 @@ impl Comparator {
         needle.is_empty()
             || match self {
                 Comparator::Octet => haystack.contains(needle),
+                Comparator::UnicodeCaseMap => {
+                    unicode_casemap(haystack).contains(&unicode_casemap(needle))
+                }
                 _ => haystack.to_lowercase().contains(&needle.to_lowercase()),
             }
     }
@@ -48,6 +484,10 @@ impl Comparator {
         match self {
             Comparator::Octet => relation.cmp(a.to_str().as_ref(), b.to_str().as_ref()),
             Comparator::AsciiNumeric => relation.cmp(&a.to_number(), &b.to_number()),
+            Comparator::UnicodeCaseMap => relation.cmp(
+                &unicode_casemap(a.to_str().as_ref()),
+                &unicode_casemap(b.to_str().as_ref()),
+            ),
             _ => relation.cmp(&a.to_str().to_lowercase(), &b.to_str().to_lowercase()),
         }
     }
@@ -59,70 +499,104 @@ impl Comparator {
         capture_positions: u64,
         captured_values: &mut Vec<(usize, String)>,
     ) -> bool {
-        let pattern = GlobPattern::compile(pattern, matches!(self, Comparator::AsciiCaseMap));
+        let pattern = GlobPattern::compile(pattern, self.case_fold().unwrap_or(CaseFold::None));
+        if capture_positions == 0 {
+            pattern.matches(value)
+        } else {
+            pattern.capture(value, capture_positions, captured_values)
+        }
+    }
+
+    /// Case folding applied to both values by `is`, `contains` and
+    /// `relational`, if any.
+    pub(crate) fn value_fold(&self) -> Option<CaseFold> {
         match self {
-            Comparator::AsciiCaseMap if capture_positions == 0 => pattern.matches(value),
-            Comparator::AsciiCaseMap => pattern.capture(value, capture_positions, captured_values),
-            _ if capture_positions == 0 => pattern.matches(value),
-            _ => pattern.capture(value, capture_positions, captured_values),
+            Comparator::Octet | Comparator::AsciiNumeric => None,
+            Comparator::UnicodeCaseMap => Some(CaseFold::UnicodeCaseMap),
+            _ => Some(CaseFold::Lowercase),
         }
     }
 
-    pub(crate) fn regex(
-        &self,
-        pattern: &Value,
-        pattern_expr: &Variable,
-        value: &str,
-        capture_positions: u64,
-        captured_values: &mut Vec<(usize, String)>,
-    ) -> bool {
-        if let Value::Regex(regex) = pattern {
-            let lazy_regex = regex.regex.0.load();
-            if let Some(regex) = lazy_regex.as_ref() {
-                eval_regex(regex, value, capture_positions, captured_values)
-            } else {
-                match fancy_regex::Regex::new(&regex.expr) {
-                    Ok(fancy_regex) => {
-                        let result =
-                            eval_regex(&fancy_regex, value, capture_positions, captured_values);
-                        regex.regex.0.store(Arc::new(Some(fancy_regex)));
-                        result
-                    }
-                    Err(err) => {
-                        debug_assert!(false, "Failed to compile regex: {err:?}");
-                        false
-                    }
-                }
-            }
-        } else {
-            match fancy_regex::Regex::new(pattern_expr.to_string().as_ref()) {
-                Ok(regex) => eval_regex(&regex, value, capture_positions, captured_values),
-                Err(err) => {
-                    debug_assert!(false, "Failed to compile regex: {err:?}");
-                    false
-                }
-            }
+    /// Case folding applied to `:matches` patterns, custom comparators
+    /// normalize patterns at runtime and have none.
+    pub(crate) fn case_fold(&self) -> Option<CaseFold> {
+        match self {
+            Comparator::AsciiCaseMap => Some(CaseFold::Lowercase),
+            Comparator::UnicodeCaseMap => Some(CaseFold::UnicodeCaseMap),
+            Comparator::Other(_) => None,
+            _ => Some(CaseFold::None),
         }
     }
 
     pub(crate) fn as_match(&self) -> MatchAs {
         match self {
-            Comparator::AsciiCaseMap => MatchAs::Lowercase,
+            Comparator::AsciiCaseMap | Comparator::UnicodeCaseMap => MatchAs::Lowercase,
             Comparator::AsciiNumeric => MatchAs::Number,
             _ => MatchAs::Octet,
         }
     }
 }
 
+/// Folds a string as described by the "i;unicode-casemap" collation in
+/// RFC 5051: characters are mapped to their titlecase form and the result
+/// is decomposed with NFKD, so that it can be compared octet by octet.
+/// Characters produced by the decomposition, such as the letters of a
+/// ligature, are titlecased as well.
+pub(crate) fn unicode_casemap(value: &str) -> String {
+    value
+        .chars()
+        .map(titlecase)
+        .nfkd()
+        .map(titlecase)
+        .nfkd()
+        .collect()
+}
+
+pub(crate) fn unicode_casemap_char(char: char) -> impl Iterator<Item = char> {
+    std::iter::once(titlecase(char))
+        .nfkd()
+        .map(titlecase)
+        .nfkd()
+}
+
+/// Simple titlecase mapping from UnicodeData.txt, which only differs from
+/// the simple uppercase mapping for a few characters.
+fn titlecase(char: char) -> char {
+    match char {
+        'Ǆ' | 'ǅ' | 'ǆ' => 'ǅ',
+        'Ǉ' | 'ǈ' | 'ǉ' => 'ǈ',
+        'Ǌ' | 'ǋ' | 'ǌ' => 'ǋ',
+        'Ǳ' | 'ǲ' | 'ǳ' => 'ǲ',
+        // Georgian Mkhedruli letters are their own titlecase
+        '\u{10D0}'..='\u{10FA}' | '\u{10FD}'..='\u{10FF}' => char,
+        // Greek letters with ypogegrammeni map to prosgegrammeni
+        '\u{1F80}'..='\u{1F87}' | '\u{1F90}'..='\u{1F97}' | '\u{1FA0}'..='\u{1FA7}' => {
+            char::from_u32(char as u32 + 8).unwrap_or(char)
+        }
+        '\u{1FB3}' => '\u{1FBC}',
+        '\u{1FC3}' => '\u{1FCC}',
+        '\u{1FF3}' => '\u{1FFC}',
+        // Characters without a single character uppercase mapping
+        // have no simple titlecase mapping either
+        _ => {
+            let mut upper = char.to_uppercase();
+            match (upper.next(), upper.next()) {
+                (Some(upper), None) => upper,
+                _ => char,
+            }
+        }
+    }
+}
+
 fn eval_regex(
     regex: &fancy_regex::Regex,
     value: &str,
     mut capture_positions: u64,
     captured_values: &mut Vec<(usize, String)>,
-) -> bool {
+) -> Result<bool, RuntimeError> {
     if capture_positions == 0 {
-        regex.is_match(value).unwrap_or_default()
-    } else if let Ok(Some(captures)) = regex.captures(value) {
+        regex.is_match(value).map_err(regex_error)
+    } else if let Some(captures) = regex.captures(value).map_err(regex_error)? {
         captured_values.clear();
         while capture_positions != 0 {
             let index = 63 - capture_positions.leading_zeros();
@@ -131,14 +605,21 @@ fn eval_regex(
                 captured_values.push((index as usize, match_var.as_str().to_string()));
             }
         }
-        true
+        Ok(true)
     } else {
-        false
+        Ok(false)
+    }
+}
+
+fn regex_error(err: fancy_regex::Error) -> RuntimeError {
+    match err {
+        fancy_regex::Error::RuntimeError(_) => RuntimeError::RegexLimitReached,
+        err => RuntimeError::InvalidRegex(err.to_string()),
     }
 }
 
 impl Comparable for Variable {
-    fn to_str(&self) -> Cow<str> {
+    fn to_str(&self) -> Cow<'_, str> {
         self.to_string()
     }
 
@@ -148,7 +629,7 @@ impl Comparable for Variable {
 }
 
 impl Comparable for &str {
-    fn to_str(&self) -> Cow<str> {
+    fn to_str(&self) -> Cow<'_, str> {
         (*self).into()
     }
 
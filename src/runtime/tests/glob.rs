@@ -4,25 +4,58 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use std::char::REPLACEMENT_CHARACTER;
+use std::{borrow::Cow, char::REPLACEMENT_CHARACTER};
 
 use crate::MAX_MATCH_VARIABLES;
 
+use super::comparator::{unicode_casemap, unicode_casemap_char};
+
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    any(test, feature = "serde"),
+    derive(serde::Serialize, serde::Deserialize)
+)]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Serialize, rkyv::Deserialize, rkyv::Archive)
+)]
 pub struct GlobPattern {
     pattern: Vec<PatternChar>,
-    to_lower: bool,
+    case_fold: CaseFold,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    any(test, feature = "serde"),
+    derive(serde::Serialize, serde::Deserialize)
+)]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Serialize, rkyv::Deserialize, rkyv::Archive)
+)]
+pub enum CaseFold {
+    None,
+    Lowercase,
+    UnicodeCaseMap,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    any(test, feature = "serde"),
+    derive(serde::Serialize, serde::Deserialize)
+)]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Serialize, rkyv::Deserialize, rkyv::Archive)
+)]
 pub enum PatternChar {
-    WildcardMany { num: usize, match_pos: usize },
-    WildcardSingle { match_pos: usize },
-    Char { char: char, match_pos: usize },
+    WildcardMany { num: usize },
+    WildcardSingle,
+    Char { char: char },
 }
 
 impl GlobPattern {
-    pub fn compile(pattern: &str, to_lower: bool) -> Self {
+    pub fn compile(pattern: &str, case_fold: CaseFold) -> Self {
         let mut chars = Vec::new();
         let mut is_escaped = false;
         let mut str = pattern.chars().peekable();
@@ -35,10 +68,10 @@ impl GlobPattern {
                         num += 1;
                         str.next();
                     }
-                    chars.push(PatternChar::WildcardMany { num, match_pos: 0 });
+                    chars.push(PatternChar::WildcardMany { num });
                 }
                 '?' if !is_escaped => {
-                    chars.push(PatternChar::WildcardSingle { match_pos: 0 });
+                    chars.push(PatternChar::WildcardSingle);
                 }
                 '\\' if !is_escaped => {
                     is_escaped = true;
@@ -48,29 +81,25 @@ impl GlobPattern {
                     if is_escaped {
                         is_escaped = false;
                     }
-                    if to_lower && char.is_uppercase() {
-                        for char in char.to_lowercase() {
-                            chars.push(PatternChar::Char { char, match_pos: 0 });
-                        }
-                    } else {
-                        chars.push(PatternChar::Char { char, match_pos: 0 });
-                    }
+                    case_fold.fold_char(char, |_, char| {
+                        chars.push(PatternChar::Char { char });
+                    });
                 }
             }
         }
 
         GlobPattern {
             pattern: chars,
-            to_lower,
+            case_fold,
         }
     }
 
     // Credits: Algorithm ported from https://research.swtch.com/glob
     pub fn matches(&self, value: &str) -> bool {
-        let value = if self.to_lower {
-            value.to_lowercase().chars().collect::<Vec<_>>()
-        } else {
-            value.chars().collect::<Vec<_>>()
+        let value = match self.case_fold {
+            CaseFold::None => value.chars().collect::<Vec<_>>(),
+            CaseFold::Lowercase => value.to_lowercase().chars().collect::<Vec<_>>(),
+            CaseFold::UnicodeCaseMap => unicode_casemap(value).chars().collect::<Vec<_>>(),
         };
 
         let mut px = 0;
@@ -80,19 +109,17 @@ impl GlobPattern {
 
         while px < self.pattern.len() || nx < value.len() {
             match self.pattern.get(px) {
-                Some(PatternChar::Char { char, .. }) => {
+                Some(PatternChar::Char { char }) => {
                     if matches!(value.get(nx), Some(nc) if nc == char ) {
                         px += 1;
                         nx += 1;
                         continue;
                     }
                 }
-                Some(PatternChar::WildcardSingle { .. }) => {
-                    if nx < value.len() {
-                        px += 1;
-                        nx += 1;
-                        continue;
-                    }
+                Some(PatternChar::WildcardSingle) if nx < value.len() => {
+                    px += 1;
+                    nx += 1;
+                    continue;
                 }
                 Some(PatternChar::WildcardMany { .. }) => {
                     next_px = px;
@@ -113,28 +140,24 @@ impl GlobPattern {
     }
 
     pub fn capture(
-        mut self,
+        &self,
         value_: &str,
         capture_positions: u64,
         captured_values: &mut Vec<(usize, String)>,
     ) -> bool {
-        let value = if self.to_lower {
+        let value = if self.case_fold != CaseFold::None {
             let mut value = Vec::with_capacity(value_.len());
             for char in value_.chars() {
-                if char.is_uppercase() {
-                    for (pos, lowerchar) in char.to_lowercase().enumerate() {
-                        value.push((
-                            lowerchar,
-                            if pos == 0 {
-                                char
-                            } else {
-                                REPLACEMENT_CHARACTER
-                            },
-                        ));
-                    }
-                } else {
-                    value.push((char, char));
-                }
+                self.case_fold.fold_char(char, |pos, folded_char| {
+                    value.push((
+                        folded_char,
+                        if pos == 0 {
+                            char
+                        } else {
+                            REPLACEMENT_CHARACTER
+                        },
+                    ));
+                });
             }
             value
         } else {
@@ -145,27 +168,26 @@ impl GlobPattern {
         let mut nx = 0;
         let mut next_px = 0;
         let mut next_nx = 0;
+        let mut match_pos = vec![0usize; self.pattern.len()];
 
         while px < self.pattern.len() || nx < value.len() {
-            match self.pattern.get_mut(px) {
-                Some(PatternChar::Char { char, match_pos }) => {
+            match self.pattern.get(px) {
+                Some(PatternChar::Char { char }) => {
                     if matches!(value.get(nx), Some(nc) if &nc.0 == char ) {
-                        *match_pos = nx;
+                        match_pos[px] = nx;
                         px += 1;
                         nx += 1;
                         continue;
                     }
                 }
-                Some(PatternChar::WildcardSingle { match_pos }) => {
-                    if nx < value.len() {
-                        *match_pos = nx;
-                        px += 1;
-                        nx += 1;
-                        continue;
-                    }
+                Some(PatternChar::WildcardSingle) if nx < value.len() => {
+                    match_pos[px] = nx;
+                    px += 1;
+                    nx += 1;
+                    continue;
                 }
-                Some(PatternChar::WildcardMany { match_pos, .. }) => {
-                    *match_pos = nx;
+                Some(PatternChar::WildcardMany { .. }) => {
+                    match_pos[px] = nx;
                     next_px = px;
                     next_nx = nx + 1;
                     px += 1;
@@ -189,10 +211,10 @@ impl GlobPattern {
         }
 
         let mut wildcard_pos: usize = 1;
-        for item in &self.pattern {
+        for (item, match_pos) in self.pattern.iter().zip(match_pos) {
             if wildcard_pos <= MAX_MATCH_VARIABLES as usize {
                 last_pos = match item {
-                    PatternChar::WildcardMany { mut num, match_pos } => {
+                    PatternChar::WildcardMany { mut num } => {
                         while num > 1 {
                             if capture_positions & (1 << wildcard_pos) != 0 {
                                 captured_values.push((wildcard_pos, String::with_capacity(0)));
@@ -202,7 +224,7 @@ impl GlobPattern {
                         }
 
                         if capture_positions & (1 << wildcard_pos) != 0 {
-                            if let Some(range) = value.get(last_pos..*match_pos) {
+                            if let Some(range) = value.get(last_pos..match_pos) {
                                 captured_values.push((
                                     wildcard_pos,
                                     range
@@ -224,9 +246,9 @@ impl GlobPattern {
                         wildcard_pos += 1;
                         match_pos
                     }
-                    PatternChar::WildcardSingle { match_pos } => {
+                    PatternChar::WildcardSingle => {
                         if capture_positions & (1 << wildcard_pos) != 0 {
-                            if let Some((char, orig_char)) = value.get(*match_pos) {
+                            if let Some((char, orig_char)) = value.get(match_pos) {
                                 captured_values.push((
                                     wildcard_pos,
                                     (if orig_char != &REPLACEMENT_CHARACTER {
@@ -244,7 +266,7 @@ impl GlobPattern {
                         wildcard_pos += 1;
                         match_pos
                     }
-                    PatternChar::Char { match_pos, .. } => match_pos,
+                    PatternChar::Char { .. } => match_pos,
                 } + 1;
             } else {
                 break;
@@ -254,9 +276,35 @@ impl GlobPattern {
     }
 }
 
+impl CaseFold {
+    pub(crate) fn fold(self, value: &str) -> Cow<'_, str> {
+        match self {
+            CaseFold::None => value.into(),
+            CaseFold::Lowercase => value.to_lowercase().into(),
+            CaseFold::UnicodeCaseMap => unicode_casemap(value).into(),
+        }
+    }
+
+    fn fold_char(self, char: char, mut push: impl FnMut(usize, char)) {
+        match self {
+            CaseFold::Lowercase if char.is_uppercase() => {
+                for (pos, char) in char.to_lowercase().enumerate() {
+                    push(pos, char);
+                }
+            }
+            CaseFold::UnicodeCaseMap => {
+                for (pos, char) in unicode_casemap_char(char).enumerate() {
+                    push(pos, char);
+                }
+            }
+            _ => push(0, char),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::runtime::tests::glob::GlobPattern;
+    use crate::runtime::tests::glob::{CaseFold, GlobPattern};
 
     #[test]
     fn glob_match() {
@@ -286,10 +334,10 @@ mod tests {
                 vec!["Straße"],
             ),
         ] {
-            let p = GlobPattern::compile(pattern, true);
+            let p = GlobPattern::compile(pattern, CaseFold::Lowercase);
             let mut match_values = Vec::new();
             assert!(
-                p.clone().capture(value, u64::MAX ^ 1, &mut match_values),
+                p.capture(value, u64::MAX ^ 1, &mut match_values),
                 "{value:?} {pattern:?}",
             );
 
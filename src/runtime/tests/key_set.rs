@@ -0,0 +1,124 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::sync::{Arc, OnceLock};
+
+use ahash::AHashSet;
+use aho_corasick::AhoCorasick;
+
+use crate::compiler::{
+    grammar::{Comparator, MatchType},
+    Value,
+};
+
+use super::glob::CaseFold;
+
+/// Minimum number of constant keys for an `:is` or `:contains` test to
+/// be matched in a single pass.
+pub(crate) const MIN_KEY_SET_SIZE: usize = 8;
+
+/// Constant keys of an `:is` or `:contains` test, folded as required by
+/// the comparator and indexed in a hash set or an Aho-Corasick automaton.
+#[derive(Debug, Clone)]
+#[cfg_attr(
+    any(test, feature = "serde"),
+    derive(serde::Serialize, serde::Deserialize)
+)]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Serialize, rkyv::Deserialize, rkyv::Archive)
+)]
+pub struct KeySet {
+    pub keys: Vec<String>,
+    pub case_fold: CaseFold,
+    pub contains: bool,
+    #[cfg_attr(feature = "rkyv", rkyv(with = rkyv::with::Skip))]
+    #[cfg_attr(any(test, feature = "serde"), serde(skip, default))]
+    pub index: LazyKeyIndex,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LazyKeyIndex(Arc<OnceLock<KeyIndex>>);
+
+#[derive(Debug)]
+enum KeyIndex {
+    Set(AHashSet<String>),
+    Automaton(AhoCorasick),
+    // Used when the automaton cannot be built
+    List,
+}
+
+impl KeySet {
+    pub(crate) fn new(
+        match_type: &MatchType,
+        comparator: &Comparator,
+        key_list: &[Value],
+    ) -> Option<Self> {
+        let contains = match match_type {
+            MatchType::Is => false,
+            MatchType::Contains => true,
+            _ => return None,
+        };
+        let case_fold = match comparator {
+            Comparator::AsciiNumeric | Comparator::Other(_) => return None,
+            _ => comparator.value_fold().unwrap_or(CaseFold::None),
+        };
+        if key_list.len() < MIN_KEY_SET_SIZE {
+            return None;
+        }
+
+        let keys = key_list
+            .iter()
+            .map(|key| match key {
+                Value::Text(key) => Some(case_fold.fold(key).into_owned()),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let key_set = KeySet {
+            keys,
+            case_fold,
+            contains,
+            index: LazyKeyIndex::default(),
+        };
+        key_set.index();
+        Some(key_set)
+    }
+
+    pub(crate) fn matches(&self, value: &str) -> bool {
+        self.matches_folded(self.case_fold.fold(value).as_ref())
+    }
+
+    /// Matches a value already folded as required by the comparator.
+    pub(crate) fn matches_folded(&self, value: &str) -> bool {
+        match self.index() {
+            KeyIndex::Set(keys) => keys.contains(value),
+            KeyIndex::Automaton(automaton) => automaton.is_match(value),
+            KeyIndex::List => self.keys.iter().any(|key| value.contains(key.as_str())),
+        }
+    }
+
+    fn index(&self) -> &KeyIndex {
+        self.index.0.get_or_init(|| {
+            if !self.contains {
+                KeyIndex::Set(self.keys.iter().cloned().collect())
+            } else if let Ok(automaton) = AhoCorasick::new(&self.keys) {
+                KeyIndex::Automaton(automaton)
+            } else {
+                KeyIndex::List
+            }
+        })
+    }
+}
+
+impl PartialEq for KeySet {
+    fn eq(&self, other: &Self) -> bool {
+        self.keys == other.keys
+            && self.case_fold == other.case_fold
+            && self.contains == other.contains
+    }
+}
+
+impl Eq for KeySet {}
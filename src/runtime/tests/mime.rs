@@ -61,14 +61,16 @@ impl<'x> Context<'x> {
         &'z self,
         mut message: &'x Message<'x>,
         ct_filter: &[ContentTypeFilter],
-        visitor_fnc: &mut impl FnMut(&MessagePart, &[u8]) -> bool,
+        visitor_fnc: &mut impl FnMut(usize, &MessagePart, &[u8]) -> bool,
     ) -> bool {
         let mut iter_stack = Vec::new();
         let mut iter = vec![self.part].into_iter();
+        let mut part_num = 0;
 
         loop {
             while let Some(part_id) = iter.next() {
                 if let Some(subpart) = message.parts.get(part_id as usize) {
+                    part_num += 1;
                     let process_part = if !ct_filter.is_empty() {
                         let mut process_part = false;
                         let (ct, cst) = if let Some(ct) = subpart.content_type() {
@@ -106,7 +108,8 @@ impl<'x> Context<'x> {
                     } else {
                         true
                     };
-                    if process_part && visitor_fnc(subpart, message.raw_message.as_ref()) {
+                    if process_part && visitor_fnc(part_num, subpart, message.raw_message.as_ref())
+                    {
                         return true;
                     }
                     match &subpart.body {
@@ -13,6 +13,7 @@ use super::RuntimeError;
 
 pub mod comparator;
 pub mod glob;
+pub mod key_set;
 pub mod mime;
 pub mod test_address;
 pub mod test_body;
@@ -27,7 +27,12 @@ use super::TestResult;
 
 impl TestAddress {
     pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
-        let key_list = ctx.eval_values(&self.key_list);
+        let comparator = ctx.comparator(&self.comparator);
+        let key_list = if self.key_set.is_none() {
+            ctx.eval_values(&self.key_list)
+        } else {
+            Vec::new()
+        };
         let header_list = ctx.parse_header_names(&self.header_list);
 
         let result = match &self.match_type {
@@ -39,13 +44,15 @@ impl TestAddress {
                     self.mime_anychild,
                     |header, _, _| {
                         ctx.find_addresses(header, &self.address_part, |value| {
+                            if let Some(key_set) = &self.key_set {
+                                return key_set.matches(value);
+                            }
                             for key in &key_list {
                                 if is_is {
-                                    if self.comparator.is(&value, key) {
+                                    if comparator.is(&value, key) {
                                         return true;
                                     }
-                                } else if self.comparator.contains(value, key.to_string().as_ref())
-                                {
+                                } else if comparator.contains(value, key.to_string().as_ref()) {
                                     return true;
                                 }
                             }
@@ -61,7 +68,7 @@ impl TestAddress {
                 |header, _, _| {
                     ctx.find_addresses(header, &self.address_part, |value| {
                         for key in &key_list {
-                            if self.comparator.relational(rel_match, &value, key) {
+                            if comparator.relational(rel_match, &value, key) {
                                 return true;
                             }
                         }
@@ -81,15 +88,16 @@ impl TestAddress {
                             for (pattern_expr, pattern) in key_list.iter().zip(self.key_list.iter())
                             {
                                 if is_matches {
-                                    if self.comparator.matches(
+                                    if comparator.glob(
+                                        pattern,
+                                        pattern_expr,
                                         value,
-                                        pattern_expr.to_string().as_ref(),
                                         *capture_positions,
                                         &mut captured_positions,
                                     ) {
                                         return true;
                                     }
-                                } else if self.comparator.regex(
+                                } else if comparator.regex(
                                     pattern,
                                     pattern_expr,
                                     value,
@@ -103,6 +111,9 @@ impl TestAddress {
                         })
                     },
                 );
+                if let Some(err) = comparator.take_error() {
+                    return TestResult::Error(err);
+                }
                 if !captured_positions.is_empty() {
                     ctx.set_match_variables(captured_positions);
                 }
@@ -155,7 +166,7 @@ impl TestAddress {
                         event: Event::ListContains {
                             lists: ctx.eval_values_owned(&self.key_list),
                             values,
-                            match_as: self.comparator.as_match(),
+                            match_as: comparator.as_match(),
                         },
                         is_not: self.is_not,
                     };
@@ -4,7 +4,9 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use mail_parser::{decoders::html::html_to_text, MimeHeaders, PartType};
+use std::{borrow::Cow, ops::Deref, sync::Arc};
+
+use mail_parser::{decoders::html::html_to_text, MessagePart, MimeHeaders, PartType};
 
 use crate::{
     compiler::{
@@ -14,6 +16,7 @@ use crate::{
         },
         Number,
     },
+    runtime::text_cache::TextKey,
     Context,
 };
 
@@ -21,8 +24,13 @@ use super::{mime::ContentTypeFilter, TestResult};
 
 impl TestBody {
     pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
+        let comparator = ctx.comparator(&self.comparator);
+        let key_list = if self.key_set.is_none() {
+            ctx.eval_values(&self.key_list)
+        } else {
+            Vec::new()
+        };
         // Check Subject (not a Sieve standard)
-        let key_list = ctx.eval_values(&self.key_list);
         if self.include_subject {
             let subject = if !matches!(&self.body_transform, BodyTransform::Raw) {
                 ctx.message.subject().unwrap_or_default()
@@ -30,24 +38,24 @@ impl TestBody {
                 ctx.message.header_raw("Subject").unwrap_or_default()
             };
 
+            if self
+                .key_set
+                .as_ref()
+                .is_some_and(|key_set| key_set.matches(subject))
+            {
+                return TestResult::Bool(true ^ self.is_not);
+            }
+
             for (key, pattern) in key_list.iter().zip(self.key_list.iter()) {
                 let result = match &self.match_type {
-                    MatchType::Is => self.comparator.is(&subject, key),
-                    MatchType::Contains => {
-                        self.comparator.contains(subject, key.to_string().as_ref())
-                    }
-                    MatchType::Value(rel_match) => {
-                        self.comparator.relational(rel_match, &subject, key)
+                    MatchType::Is => comparator.is(&subject, key),
+                    MatchType::Contains => comparator.contains(subject, key.to_string().as_ref()),
+                    MatchType::Value(rel_match) => comparator.relational(rel_match, &subject, key),
+                    MatchType::Matches(_) => {
+                        comparator.glob(pattern, key, subject, 0, &mut Vec::new())
                     }
-                    MatchType::Matches(_) => self.comparator.matches(
-                        subject,
-                        key.to_string().as_ref(),
-                        0,
-                        &mut Vec::new(),
-                    ),
                     MatchType::Regex(_) => {
-                        self.comparator
-                            .regex(pattern, key, subject, 0, &mut Vec::new())
+                        comparator.regex(pattern, key, subject, 0, &mut Vec::new())
                     }
                     _ => break,
                 };
@@ -56,6 +64,10 @@ impl TestBody {
                     return TestResult::Bool(result ^ self.is_not);
                 }
             }
+
+            if let Some(err) = comparator.take_error() {
+                return TestResult::Error(err);
+            }
         }
 
         let ct_filter = match &self.body_transform {
@@ -76,14 +88,26 @@ impl TestBody {
             }
         };
 
+        // Bodies are folded once per run when possible
+        let value_fold = comparator.value_fold().filter(|_| {
+            matches!(
+                &self.match_type,
+                MatchType::Is | MatchType::Contains | MatchType::Value(_)
+            )
+        });
+
         let result = if let MatchType::Count(rel_match) = &self.match_type {
             let mut count = 0;
             let mut result = false;
 
-            ctx.find_nested_parts(&ctx.message, &ct_filter, &mut |_part, _raw_message| {
-                count += 1;
-                false
-            });
+            ctx.find_nested_parts(
+                &ctx.message,
+                &ct_filter,
+                &mut |_part_num, _part, _raw_message| {
+                    count += 1;
+                    false
+                },
+            );
 
             for key in &self.key_list {
                 if rel_match.cmp(&Number::from(count), &ctx.eval_value(key).to_number()) {
@@ -94,131 +118,192 @@ impl TestBody {
 
             result
         } else {
-            ctx.find_nested_parts(&ctx.message, &ct_filter, &mut |part, raw_message| {
-                let text = match (&self.body_transform, &part.body) {
-                    (BodyTransform::Content(_), PartType::Message(message)) => {
-                        if let Some(part) = message.parts.first() {
-                            String::from_utf8_lossy(
-                                raw_message
-                                    .get(
-                                        part.raw_header_offset() as usize
-                                            ..part.raw_body_offset() as usize,
-                                    )
-                                    .unwrap_or(b""),
-                            )
-                        } else {
-                            return false;
-                        }
+            ctx.find_nested_parts(
+                &ctx.message,
+                &ct_filter,
+                &mut |part_num, part, raw_message| {
+                    let Some(text) =
+                        ctx.body_text(&self.body_transform, part_num, part, raw_message)
+                    else {
+                        return false;
+                    };
+                    let folded = value_fold.map(|case_fold| {
+                        ctx.text_cache.get_or_insert(
+                            TextKey::body(
+                                ctx.part,
+                                part_num,
+                                &self.body_transform,
+                                Some(case_fold),
+                            ),
+                            || case_fold.fold(&text).into_owned(),
+                        )
+                    });
+                    if let Some(key_set) = &self.key_set {
+                        return match &folded {
+                            Some(value) => key_set.matches_folded(value),
+                            None => key_set.matches(&text),
+                        };
                     }
-                    (BodyTransform::Content(_), PartType::Multipart(_)) => {
-                        if let Some(boundary) =
-                            part.content_type().and_then(|ct| ct.attribute("boundary"))
-                        {
-                            let mime_body = std::str::from_utf8(
-                                raw_message
-                                    .get(
-                                        part.raw_body_offset() as usize
-                                            ..part.raw_end_offset() as usize,
-                                    )
-                                    .unwrap_or(b""),
-                            )
-                            .unwrap_or("");
-                            let mut mime_part = String::with_capacity(64);
-                            if let Some((prologue, epilogue)) =
-                                mime_body.split_once(&format!("\n--{boundary}"))
-                            {
-                                mime_part.push_str(prologue);
-                                if let Some((_, epilogue)) =
-                                    epilogue.rsplit_once(&format!("\n--{boundary}--"))
-                                {
-                                    mime_part.push_str(epilogue);
+                    let mut result = false;
+
+                    for (key, pattern) in key_list.iter().zip(self.key_list.iter()) {
+                        result = match &self.match_type {
+                            MatchType::Is => match &folded {
+                                Some(value) => comparator.is_folded(value, key),
+                                None => comparator.is(&text.as_ref(), key),
+                            },
+                            MatchType::Contains => match &folded {
+                                Some(value) => {
+                                    comparator.contains_folded(value, key.to_string().as_ref())
                                 }
+                                None => {
+                                    comparator.contains(text.as_ref(), key.to_string().as_ref())
+                                }
+                            },
+                            MatchType::Value(rel_match) => match &folded {
+                                Some(value) => comparator.relational_folded(rel_match, value, key),
+                                None => comparator.relational(rel_match, &text.as_ref(), key),
+                            },
+                            MatchType::Matches(_) => {
+                                comparator.glob(pattern, key, text.as_ref(), 0, &mut Vec::new())
                             }
-                            mime_part.into()
-                        } else {
-                            String::from_utf8_lossy(
-                                raw_message
-                                    .get(
-                                        part.raw_body_offset() as usize
-                                            ..part.raw_end_offset() as usize,
-                                    )
-                                    .unwrap_or(b""),
-                            )
-                        }
-                    }
-                    (BodyTransform::Raw, _) => {
-                        match &part.body {
-                            PartType::Text(text) if part.raw_body_offset() == 0 => {
-                                // Inserted part
-                                text.as_ref().into()
-                            }
-                            _ if part.raw_end_offset() > part.raw_body_offset() => {
-                                String::from_utf8_lossy(
-                                    raw_message
-                                        .get(
-                                            part.raw_body_offset() as usize
-                                                ..part.raw_end_offset() as usize,
-                                        )
-                                        .unwrap_or(b""),
-                                )
+                            MatchType::Regex(_) => {
+                                comparator.regex(pattern, key, text.as_ref(), 0, &mut Vec::new())
                             }
-                            _ => return false,
-                        }
-                    }
-                    (_, PartType::Text(text))
-                    | (BodyTransform::Content(_), PartType::Html(text)) => text.as_ref().into(),
-                    (_, PartType::Html(html)) => html_to_text(html.as_ref()).into(),
-                    (
-                        BodyTransform::Text,
-                        PartType::Binary(bytes) | PartType::InlineBinary(bytes),
-                    ) if part.content_type().is_some_and(|ct| {
-                        ct.c_type.eq_ignore_ascii_case("application")
-                            && ct.c_subtype.as_ref().is_some_and(|st| st.contains("xml"))
-                    }) =>
-                    {
-                        html_to_text(std::str::from_utf8(bytes.as_ref()).unwrap_or("")).into()
-                    }
-                    (
-                        BodyTransform::Content(_),
-                        PartType::Binary(bytes) | PartType::InlineBinary(bytes),
-                    ) => String::from_utf8_lossy(bytes.as_ref()),
-                    _ => {
-                        return false;
-                    }
-                };
-                let mut result = false;
-
-                for (key, pattern) in key_list.iter().zip(self.key_list.iter()) {
-                    result = match &self.match_type {
-                        MatchType::Is => self.comparator.is(&text.as_ref(), key),
-                        MatchType::Contains => self
-                            .comparator
-                            .contains(text.as_ref(), key.to_string().as_ref()),
-                        MatchType::Value(rel_match) => {
-                            self.comparator.relational(rel_match, &text.as_ref(), key)
-                        }
-                        MatchType::Matches(_) => self.comparator.matches(
-                            text.as_ref(),
-                            key.to_string().as_ref(),
-                            0,
-                            &mut Vec::new(),
-                        ),
-                        MatchType::Regex(_) => {
-                            self.comparator
-                                .regex(pattern, key, text.as_ref(), 0, &mut Vec::new())
-                        }
-                        _ => false,
-                    };
+                            _ => false,
+                        };
 
-                    if result {
-                        break;
+                        if result {
+                            break;
+                        }
                     }
-                }
 
-                result
-            })
+                    result
+                },
+            )
         };
 
+        if let Some(err) = comparator.take_error() {
+            return TestResult::Error(err);
+        }
+
         TestResult::Bool(result ^ self.is_not)
     }
 }
+
+impl Context<'_> {
+    /// Returns the decoded text of a body part, text that had to be
+    /// converted is cached until the message is modified.
+    fn body_text<'y>(
+        &self,
+        transform: &BodyTransform,
+        part_num: usize,
+        part: &'y MessagePart,
+        raw_message: &'y [u8],
+    ) -> Option<BodyText<'y>> {
+        let key = TextKey::body(self.part, part_num, transform, None);
+        if let Some(text) = self.text_cache.get(&key) {
+            return Some(BodyText::Cached(text));
+        }
+        match decode_body(transform, part, raw_message)? {
+            Cow::Borrowed(text) => Some(BodyText::Borrowed(text)),
+            Cow::Owned(text) => Some(BodyText::Cached(self.text_cache.insert(key, text))),
+        }
+    }
+}
+
+enum BodyText<'x> {
+    Borrowed(&'x str),
+    Cached(Arc<str>),
+}
+
+impl Deref for BodyText<'_> {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            BodyText::Borrowed(text) => text,
+            BodyText::Cached(text) => text,
+        }
+    }
+}
+
+/// Returns the text of a body part as seen by the given transform.
+fn decode_body<'y>(
+    transform: &BodyTransform,
+    part: &'y MessagePart,
+    raw_message: &'y [u8],
+) -> Option<Cow<'y, str>> {
+    let text = match (transform, &part.body) {
+        (BodyTransform::Content(_), PartType::Message(message)) => {
+            if let Some(part) = message.parts.first() {
+                String::from_utf8_lossy(
+                    raw_message
+                        .get(part.raw_header_offset() as usize..part.raw_body_offset() as usize)
+                        .unwrap_or(b""),
+                )
+            } else {
+                return None;
+            }
+        }
+        (BodyTransform::Content(_), PartType::Multipart(_)) => {
+            if let Some(boundary) = part.content_type().and_then(|ct| ct.attribute("boundary")) {
+                let mime_body = std::str::from_utf8(
+                    raw_message
+                        .get(part.raw_body_offset() as usize..part.raw_end_offset() as usize)
+                        .unwrap_or(b""),
+                )
+                .unwrap_or("");
+                let mut mime_part = String::with_capacity(64);
+                if let Some((prologue, epilogue)) = mime_body.split_once(&format!("\n--{boundary}"))
+                {
+                    mime_part.push_str(prologue);
+                    if let Some((_, epilogue)) = epilogue.rsplit_once(&format!("\n--{boundary}--"))
+                    {
+                        mime_part.push_str(epilogue);
+                    }
+                }
+                mime_part.into()
+            } else {
+                String::from_utf8_lossy(
+                    raw_message
+                        .get(part.raw_body_offset() as usize..part.raw_end_offset() as usize)
+                        .unwrap_or(b""),
+                )
+            }
+        }
+        (BodyTransform::Raw, _) => {
+            match &part.body {
+                PartType::Text(text) if part.raw_body_offset() == 0 => {
+                    // Inserted part
+                    text.as_ref().into()
+                }
+                _ if part.raw_end_offset() > part.raw_body_offset() => String::from_utf8_lossy(
+                    raw_message
+                        .get(part.raw_body_offset() as usize..part.raw_end_offset() as usize)
+                        .unwrap_or(b""),
+                ),
+                _ => return None,
+            }
+        }
+        (_, PartType::Text(text)) | (BodyTransform::Content(_), PartType::Html(text)) => {
+            text.as_ref().into()
+        }
+        (_, PartType::Html(html)) => html_to_text(html.as_ref()).into(),
+        (BodyTransform::Text, PartType::Binary(bytes) | PartType::InlineBinary(bytes))
+            if part.content_type().is_some_and(|ct| {
+                ct.c_type.eq_ignore_ascii_case("application")
+                    && ct.c_subtype.as_ref().is_some_and(|st| st.contains("xml"))
+            }) =>
+        {
+            html_to_text(std::str::from_utf8(bytes.as_ref()).unwrap_or("")).into()
+        }
+        (BodyTransform::Content(_), PartType::Binary(bytes) | PartType::InlineBinary(bytes)) => {
+            String::from_utf8_lossy(bytes.as_ref())
+        }
+        _ => {
+            return None;
+        }
+    };
+    Some(text)
+}
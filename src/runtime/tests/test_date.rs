@@ -23,6 +23,7 @@ use super::TestResult;
 
 impl TestDate {
     pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
+        let comparator = ctx.comparator(&self.comparator);
         let header_name = if let Some(header_name) = ctx.parse_header_name(&self.header_name) {
             header_name
         } else {
@@ -61,7 +62,9 @@ impl TestDate {
                     self.mime_anychild,
                     |header, _, _| {
                         if let Some(dt) = ctx.find_dates(header) {
-                            let value = self.date_part.eval(self.zone.eval(dt.as_ref()).as_ref());
+                            let value = self
+                                .date_part
+                                .eval(self.zone.eval(ctx, dt.as_ref()).as_ref());
                             if !value.is_empty() && !values.iter().any(|v: &String| v.eq(&value)) {
                                 values.push(value);
                             }
@@ -75,7 +78,7 @@ impl TestDate {
                         event: Event::ListContains {
                             lists: ctx.eval_values_owned(&self.key_list),
                             values,
-                            match_as: self.comparator.as_match(),
+                            match_as: comparator.as_match(),
                         },
                         is_not: self.is_not,
                     };
@@ -92,30 +95,29 @@ impl TestDate {
                     self.mime_anychild,
                     |header, _, _| {
                         if let Some(dt) = ctx.find_dates(header) {
-                            let date_part =
-                                self.date_part.eval(self.zone.eval(dt.as_ref()).as_ref());
-                            for key in &key_list {
+                            let date_part = self
+                                .date_part
+                                .eval(self.zone.eval(ctx, dt.as_ref()).as_ref());
+                            for (key, pattern) in key_list.iter().zip(self.key_list.iter()) {
                                 if match &self.match_type {
-                                    MatchType::Is => self.comparator.is(&date_part.as_str(), key),
-                                    MatchType::Contains => self
-                                        .comparator
-                                        .contains(&date_part, key.to_string().as_ref()),
-                                    MatchType::Value(rel_match) => self.comparator.relational(
-                                        rel_match,
-                                        &date_part.as_str(),
+                                    MatchType::Is => comparator.is(&date_part.as_str(), key),
+                                    MatchType::Contains => {
+                                        comparator.contains(&date_part, key.to_string().as_ref())
+                                    }
+                                    MatchType::Value(rel_match) => {
+                                        comparator.relational(rel_match, &date_part.as_str(), key)
+                                    }
+                                    MatchType::Matches(capture_positions) => comparator.glob(
+                                        pattern,
                                         key,
+                                        &date_part,
+                                        *capture_positions,
+                                        &mut captured_values,
                                     ),
-                                    MatchType::Matches(capture_positions) => {
-                                        self.comparator.matches(
-                                            &date_part,
-                                            key.to_string().as_ref(),
-                                            *capture_positions,
-                                            &mut captured_values,
-                                        )
-                                    }
-                                    MatchType::Regex(capture_positions) => self.comparator.matches(
+                                    MatchType::Regex(capture_positions) => comparator.regex(
+                                        pattern,
+                                        key,
                                         &date_part,
-                                        key.to_string().as_ref(),
                                         *capture_positions,
                                         &mut captured_values,
                                     ),
@@ -129,6 +131,11 @@ impl TestDate {
                         false
                     },
                 );
+
+                if let Some(err) = comparator.take_error() {
+                    return TestResult::Error(err);
+                }
+
                 if !captured_values.is_empty() {
                     ctx.set_match_variables(captured_values);
                 }
@@ -142,6 +149,7 @@ impl TestDate {
 
 impl TestCurrentDate {
     pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
+        let comparator = ctx.comparator(&self.comparator);
         let mut result = false;
 
         match &self.match_type {
@@ -154,19 +162,13 @@ impl TestCurrentDate {
                 }
             }
             MatchType::List => {
-                let value = self.date_part.eval(
-                    &(if let Some(zone) = self.zone {
-                        DateTime::from_timestamp(ctx.current_time).to_timezone(zone)
-                    } else {
-                        DateTime::from_timestamp(ctx.current_time)
-                    }),
-                );
+                let value = self.date_part.eval(&ctx.current_date(self.zone.as_ref()));
                 if !value.is_empty() {
                     return TestResult::Event {
                         event: Event::ListContains {
                             lists: ctx.eval_values_owned(&self.key_list),
                             values: vec![value],
-                            match_as: self.comparator.as_match(),
+                            match_as: comparator.as_match(),
                         },
                         is_not: self.is_not,
                     };
@@ -174,35 +176,30 @@ impl TestCurrentDate {
             }
             _ => {
                 let mut captured_values = Vec::new();
-                let date_part = self.date_part.eval(
-                    &(if let Some(zone) = self.zone {
-                        DateTime::from_timestamp(ctx.current_time).to_timezone(zone)
-                    } else {
-                        DateTime::from_timestamp(ctx.current_time)
-                    }),
-                );
+                let date_part = self.date_part.eval(&ctx.current_date(self.zone.as_ref()));
 
-                for key in &self.key_list {
-                    let key = ctx.eval_value(key);
+                for pattern in &self.key_list {
+                    let key = ctx.eval_value(pattern);
 
                     if match &self.match_type {
-                        MatchType::Is => self.comparator.is(&date_part.as_str(), &key),
-                        MatchType::Contains => self
-                            .comparator
-                            .contains(&date_part, key.to_string().as_ref()),
+                        MatchType::Is => comparator.is(&date_part.as_str(), &key),
+                        MatchType::Contains => {
+                            comparator.contains(&date_part, key.to_string().as_ref())
+                        }
                         MatchType::Value(rel_match) => {
-                            self.comparator
-                                .relational(rel_match, &date_part.as_str(), &key)
+                            comparator.relational(rel_match, &date_part.as_str(), &key)
                         }
-                        MatchType::Matches(capture_positions) => self.comparator.matches(
+                        MatchType::Matches(capture_positions) => comparator.glob(
+                            pattern,
+                            &key,
                             &date_part,
-                            key.to_string().as_ref(),
                             *capture_positions,
                             &mut captured_values,
                         ),
-                        MatchType::Regex(capture_positions) => self.comparator.matches(
+                        MatchType::Regex(capture_positions) => comparator.regex(
+                            pattern,
+                            &key,
                             &date_part,
-                            key.to_string().as_ref(),
                             *capture_positions,
                             &mut captured_values,
                         ),
@@ -213,6 +210,10 @@ impl TestCurrentDate {
                     }
                 }
 
+                if let Some(err) = comparator.take_error() {
+                    return TestResult::Error(err);
+                }
+
                 if !captured_values.is_empty() {
                     ctx.set_match_variables(captured_values);
                 }
@@ -283,11 +284,41 @@ impl DatePart {
 }
 
 impl Zone {
-    pub(crate) fn eval<'x>(&self, dt: &'x DateTime) -> Cow<'x, DateTime> {
+    pub(crate) fn eval<'x>(&self, ctx: &Context, dt: &'x DateTime) -> Cow<'x, DateTime> {
         match self {
-            Zone::Time(tz) => Cow::Owned(dt.to_timezone(*tz)),
             Zone::Original => Cow::Borrowed(dt),
-            Zone::Local => Cow::Owned(DateTime::from_timestamp(dt.to_timestamp())),
+            Zone::Local => Cow::Owned(ctx.local_date(dt.to_timestamp())),
+            Zone::Time(_) | Zone::Named(_) => {
+                Cow::Owned(dt.to_timezone(self.offset(dt.to_timestamp())))
+            }
+        }
+    }
+
+    /// Returns the offset from UTC in seconds at the given time.
+    pub(crate) fn offset(&self, timestamp: i64) -> i64 {
+        match self {
+            Zone::Time(offset) => *offset,
+            Zone::Named(name) => tzdb::tz_by_name(name)
+                .and_then(|tz| tz.find_local_time_type(timestamp).ok())
+                .map_or(0, |local_time| local_time.ut_offset() as i64),
+            Zone::Original | Zone::Local => 0,
+        }
+    }
+}
+
+impl Context<'_> {
+    fn current_date(&self, zone: Option<&Zone>) -> DateTime {
+        match zone {
+            Some(zone) => DateTime::from_timestamp(self.current_time)
+                .to_timezone(zone.offset(self.current_time)),
+            None => self.local_date(self.current_time),
+        }
+    }
+
+    fn local_date(&self, timestamp: i64) -> DateTime {
+        match &self.time_zone {
+            Some(zone) => DateTime::from_timestamp(timestamp).to_timezone(zone.offset(timestamp)),
+            None => DateTime::from_timestamp(timestamp),
         }
     }
 }
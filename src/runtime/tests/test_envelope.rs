@@ -18,6 +18,7 @@ use super::TestResult;
 
 impl TestEnvelope {
     pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
+        let comparator = ctx.comparator(&self.comparator);
         let key_list = ctx.eval_values(&self.key_list);
 
         let result = match &self.match_type {
@@ -27,10 +28,10 @@ impl TestEnvelope {
                 ctx.find_envelopes(self, |value| {
                     for key in &key_list {
                         if is_is {
-                            if self.comparator.is(&value, key) {
+                            if comparator.is(&value, key) {
                                 return true;
                             }
-                        } else if self.comparator.contains(value, key.to_string().as_ref()) {
+                        } else if comparator.contains(value, key.to_string().as_ref()) {
                             return true;
                         }
                     }
@@ -40,7 +41,7 @@ impl TestEnvelope {
             }
             MatchType::Value(rel_match) => ctx.find_envelopes(self, |value| {
                 for key in &key_list {
-                    if self.comparator.relational(rel_match, &value, key) {
+                    if comparator.relational(rel_match, &value, key) {
                         return true;
                     }
                 }
@@ -54,15 +55,16 @@ impl TestEnvelope {
                 let result = ctx.find_envelopes(self, |value| {
                     for (pattern_expr, pattern) in key_list.iter().zip(self.key_list.iter()) {
                         if is_matches {
-                            if self.comparator.matches(
+                            if comparator.glob(
+                                pattern,
+                                pattern_expr,
                                 value,
-                                pattern_expr.to_string().as_ref(),
                                 *capture_positions,
                                 &mut captured_positions,
                             ) {
                                 return true;
                             }
-                        } else if self.comparator.regex(
+                        } else if comparator.regex(
                             pattern,
                             pattern_expr,
                             value,
@@ -76,6 +78,10 @@ impl TestEnvelope {
                     false
                 });
 
+                if let Some(err) = comparator.take_error() {
+                    return TestResult::Error(err);
+                }
+
                 if !captured_positions.is_empty() {
                     ctx.set_match_variables(captured_positions);
                 }
@@ -119,7 +125,7 @@ impl TestEnvelope {
                         event: Event::ListContains {
                             lists: ctx.eval_values_owned(&self.key_list),
                             values,
-                            match_as: self.comparator.as_match(),
+                            match_as: comparator.as_match(),
                         },
                         is_not: self.is_not,
                     };
@@ -133,7 +139,7 @@ impl TestEnvelope {
 }
 
 impl Context<'_> {
-    fn find_envelopes(
+    pub(crate) fn find_envelopes(
         &self,
         test_envelope: &TestEnvelope,
         mut cb: impl FnMut(&str) -> bool,
@@ -152,8 +158,11 @@ impl Context<'_> {
                         }
                     }
                     Envelope::ByTimeAbsolute if test_envelope.zone.is_some() => {
-                        if let Some(dt) = DateTime::parse_rfc3339(value.to_string().as_ref()) {
-                            cb(&dt.to_timezone(test_envelope.zone.unwrap()).to_rfc3339())
+                        if let (Some(dt), Some(zone)) = (
+                            DateTime::parse_rfc3339(value.to_string().as_ref()),
+                            &test_envelope.zone,
+                        ) {
+                            cb(&dt.to_timezone(zone.offset(dt.to_timestamp())).to_rfc3339())
                         } else {
                             cb("")
                         }
@@ -16,6 +16,7 @@ use super::TestResult;
 
 impl TestHasFlag {
     pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
+        let comparator = ctx.comparator(&self.comparator);
         let mut variable_list_ = None;
         let variable_list = if !self.variable_list.is_empty() {
             &self.variable_list
@@ -53,27 +54,23 @@ impl TestHasFlag {
                         Some(flags) if !flags.is_empty() => {
                             for flag in flags.to_string().split(' ') {
                                 if match &self.match_type {
-                                    MatchType::Is => self.comparator.is(&flag, &check_flag),
-                                    MatchType::Contains => {
-                                        self.comparator.contains(flag, check_flag)
-                                    }
+                                    MatchType::Is => comparator.is(&flag, &check_flag),
+                                    MatchType::Contains => comparator.contains(flag, check_flag),
                                     MatchType::Value(rel_match) => {
-                                        self.comparator.relational(rel_match, &flag, &check_flag)
-                                    }
-                                    MatchType::Matches(capture_positions) => {
-                                        self.comparator.matches(
-                                            flag,
-                                            check_flag,
-                                            *capture_positions,
-                                            &mut captured_values,
-                                        )
+                                        comparator.relational(rel_match, &flag, &check_flag)
                                     }
-                                    MatchType::Regex(capture_positions) => self.comparator.matches(
+                                    MatchType::Matches(capture_positions) => comparator.matches(
                                         flag,
                                         check_flag,
                                         *capture_positions,
                                         &mut captured_values,
                                     ),
+                                    MatchType::Regex(capture_positions) => comparator.regex_expr(
+                                        check_flag,
+                                        flag,
+                                        *capture_positions,
+                                        &mut captured_values,
+                                    ),
                                     MatchType::Count(_) | MatchType::List => false,
                                 } {
                                     return true;
@@ -85,6 +82,11 @@ impl TestHasFlag {
                 }
                 false
             });
+
+            if let Some(err) = comparator.take_error() {
+                return TestResult::Error(err);
+            }
+
             if !captured_values.is_empty() {
                 ctx.set_match_variables(captured_values);
             }
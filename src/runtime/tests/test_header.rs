@@ -4,6 +4,8 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
+use std::sync::Arc;
+
 use mail_parser::{parsers::MessageStream, Header, HeaderName, HeaderValue};
 
 use crate::{
@@ -11,15 +13,20 @@ use crate::{
         grammar::{actions::action_mime::MimeOpts, tests::test_header::TestHeader, MatchType},
         Number, Value,
     },
-    runtime::Variable,
+    runtime::{text_cache::TextKey, Variable},
     Context, Event,
 };
 
-use super::{mime::SubpartIterator, TestResult};
+use super::{glob::CaseFold, mime::SubpartIterator, TestResult};
 
 impl TestHeader {
     pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
-        let key_list = ctx.eval_values(&self.key_list);
+        let comparator = ctx.comparator(&self.comparator);
+        let key_list = if self.key_set.is_none() {
+            ctx.eval_values(&self.key_list)
+        } else {
+            Vec::new()
+        };
         let header_list = ctx.parse_header_names(&self.header_list);
         let mime_opts = match &self.mime_opts {
             MimeOpts::Type => MimeOpts::Type,
@@ -29,6 +36,11 @@ impl TestHeader {
             MimeOpts::None => MimeOpts::None,
         };
 
+        // Decoded values are folded once per run when possible
+        let value_fold = comparator
+            .value_fold()
+            .filter(|_| matches!(mime_opts, MimeOpts::None));
+
         let result = match &self.match_type {
             MatchType::Is | MatchType::Contains => {
                 let is_is = matches!(&self.match_type, MatchType::Is);
@@ -36,15 +48,29 @@ impl TestHeader {
                     &header_list,
                     self.index,
                     self.mime_anychild,
-                    |header, _, _| {
+                    |header, part_id, pos| {
+                        if let Some(case_fold) = value_fold {
+                            let value = ctx.folded_header_value(header, part_id, pos, case_fold);
+                            return if let Some(key_set) = &self.key_set {
+                                key_set.matches_folded(&value)
+                            } else if is_is {
+                                key_list.iter().any(|key| comparator.is_folded(&value, key))
+                            } else {
+                                key_list.iter().any(|key| {
+                                    comparator.contains_folded(&value, key.to_string().as_ref())
+                                })
+                            };
+                        }
                         ctx.find_header_values(header, &mime_opts, |value| {
+                            if let Some(key_set) = &self.key_set {
+                                return key_set.matches(value);
+                            }
                             for key in &key_list {
                                 if is_is {
-                                    if self.comparator.is(&value, key) {
+                                    if comparator.is(&value, key) {
                                         return true;
                                     }
-                                } else if self.comparator.contains(value, key.to_string().as_ref())
-                                {
+                                } else if comparator.contains(value, key.to_string().as_ref()) {
                                     return true;
                                 }
                             }
@@ -57,10 +83,16 @@ impl TestHeader {
                 &header_list,
                 self.index,
                 self.mime_anychild,
-                |header, _, _| {
+                |header, part_id, pos| {
+                    if let Some(case_fold) = value_fold {
+                        let value = ctx.folded_header_value(header, part_id, pos, case_fold);
+                        return key_list
+                            .iter()
+                            .any(|key| comparator.relational_folded(rel_match, &value, key));
+                    }
                     ctx.find_header_values(header, &mime_opts, |value| {
                         for key in &key_list {
-                            if self.comparator.relational(rel_match, &value, key) {
+                            if comparator.relational(rel_match, &value, key) {
                                 return true;
                             }
                         }
@@ -80,15 +112,16 @@ impl TestHeader {
                             for (pattern_expr, pattern) in key_list.iter().zip(self.key_list.iter())
                             {
                                 if is_matches {
-                                    if self.comparator.matches(
+                                    if comparator.glob(
+                                        pattern,
+                                        pattern_expr,
                                         value,
-                                        pattern_expr.to_string().as_ref(),
                                         *capture_positions,
                                         &mut captured_values,
                                     ) {
                                         return true;
                                     }
-                                } else if self.comparator.regex(
+                                } else if comparator.regex(
                                     pattern,
                                     pattern_expr,
                                     value,
@@ -102,6 +135,9 @@ impl TestHeader {
                         })
                     },
                 );
+                if let Some(err) = comparator.take_error() {
+                    return TestResult::Error(err);
+                }
                 if !captured_values.is_empty() {
                     ctx.set_match_variables(captured_values);
                 }
@@ -172,7 +208,7 @@ impl TestHeader {
                         event: Event::ListContains {
                             lists: ctx.eval_values_owned(&self.key_list),
                             values,
-                            match_as: self.comparator.as_match(),
+                            match_as: comparator.as_match(),
                         },
                         is_not: self.is_not,
                     };
@@ -274,6 +310,32 @@ impl Context<'_> {
         false
     }
 
+    /// Returns the decoded value of a header folded with `case_fold`,
+    /// which is cached until the message is modified.
+    pub(crate) fn folded_header_value(
+        &self,
+        header: &Header,
+        part_id: u32,
+        pos: usize,
+        case_fold: CaseFold,
+    ) -> Arc<str> {
+        self.text_cache.get_or_insert(
+            TextKey::Header {
+                part_id,
+                pos,
+                case_fold,
+            },
+            || {
+                let mut folded = String::new();
+                self.find_header_values(header, &MimeOpts::None, |value| {
+                    folded = case_fold.fold(value).into_owned();
+                    false
+                });
+                folded
+            },
+        )
+    }
+
     #[allow(unused_assignments)]
     pub(crate) fn find_header_values(
         &self,
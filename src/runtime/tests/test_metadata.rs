@@ -19,6 +19,7 @@ use super::TestResult;
 
 impl TestMetadata {
     pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
+        let comparator = ctx.comparator(&self.comparator);
         let metadata = match &self.medatata {
             Metadata::Server { annotation } => Metadata::Server {
                 annotation: ctx.eval_value(annotation).to_string().into_owned(),
@@ -67,20 +68,17 @@ impl TestMetadata {
             for pattern in &self.key_list {
                 let key = ctx.eval_value(pattern);
                 result = match &self.match_type {
-                    MatchType::Is => self.comparator.is(&value, &key),
-                    MatchType::Contains => {
-                        self.comparator.contains(value, key.to_string().as_ref())
-                    }
-                    MatchType::Value(relation) => {
-                        self.comparator.relational(relation, &value, &key)
-                    }
-                    MatchType::Matches(capture_positions) => self.comparator.matches(
+                    MatchType::Is => comparator.is(&value, &key),
+                    MatchType::Contains => comparator.contains(value, key.to_string().as_ref()),
+                    MatchType::Value(relation) => comparator.relational(relation, &value, &key),
+                    MatchType::Matches(capture_positions) => comparator.glob(
+                        pattern,
+                        &key,
                         value,
-                        key.to_string().as_ref(),
                         *capture_positions,
                         &mut captured_values,
                     ),
-                    MatchType::Regex(capture_positions) => self.comparator.regex(
+                    MatchType::Regex(capture_positions) => comparator.regex(
                         pattern,
                         &key,
                         value,
@@ -95,6 +93,10 @@ impl TestMetadata {
                 }
             }
 
+            if let Some(err) = comparator.take_error() {
+                return TestResult::Error(err);
+            }
+
             if !captured_values.is_empty() {
                 ctx.set_match_variables(captured_values);
             }
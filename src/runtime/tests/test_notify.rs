@@ -45,13 +45,14 @@ impl TestValidNotifyMethod {
 
 impl TestNotifyMethodCapability {
     pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
+        let comparator = ctx.comparator(&self.comparator);
         let uri_ = ctx.eval_value(&self.notification_uri);
         let uri = uri_.to_string();
         if !ctx
             .eval_value(&self.notification_capability)
             .to_string()
             .eq_ignore_ascii_case("online")
-            || !validate_uri(uri.as_ref()).is_some_and( |scheme| {
+            || !validate_uri(uri.as_ref()).is_some_and(|scheme| {
                 ctx.runtime
                     .valid_notification_uris
                     .contains(&Cow::from(scheme))
@@ -71,22 +72,14 @@ impl TestNotifyMethodCapability {
             for pattern in &self.key_list {
                 let key = ctx.eval_value(pattern);
                 if match &self.match_type {
-                    MatchType::Is => self.comparator.is(&"maybe", &key),
-                    MatchType::Contains => {
-                        self.comparator.contains("maybe", key.to_string().as_ref())
+                    MatchType::Is => comparator.is(&"maybe", &key),
+                    MatchType::Contains => comparator.contains("maybe", key.to_string().as_ref()),
+                    MatchType::Value(relation) => comparator.relational(relation, &"maybe", &key),
+                    MatchType::Matches(_) => {
+                        comparator.glob(pattern, &key, "maybe", 0, &mut Vec::new())
                     }
-                    MatchType::Value(relation) => {
-                        self.comparator.relational(relation, &"maybe", &key)
-                    }
-                    MatchType::Matches(_) => self.comparator.matches(
-                        "maybe",
-                        key.to_string().as_ref(),
-                        0,
-                        &mut Vec::new(),
-                    ),
                     MatchType::Regex(_) => {
-                        self.comparator
-                            .regex(pattern, &key, "maybe", 0, &mut Vec::new())
+                        comparator.regex(pattern, &key, "maybe", 0, &mut Vec::new())
                     }
                     _ => false,
                 } {
@@ -95,6 +88,10 @@ impl TestNotifyMethodCapability {
             }
         }
 
+        if let Some(err) = comparator.take_error() {
+            return TestResult::Error(err);
+        }
+
         TestResult::Bool(false ^ self.is_not)
     }
 }
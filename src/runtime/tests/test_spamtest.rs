@@ -20,6 +20,7 @@ use super::TestResult;
 
 impl TestSpamTest {
     pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
+        let comparator = ctx.comparator(&self.comparator);
         let status = if self.percent {
             ctx.spam_status.as_percentage()
         } else {
@@ -29,18 +30,19 @@ impl TestSpamTest {
         let mut captured_values = Vec::new();
 
         let result = match &self.match_type {
-            MatchType::Is => self.comparator.is(&status, &value),
-            MatchType::Contains => self
-                .comparator
-                .contains(status.to_string().as_ref(), value.to_string().as_ref()),
-            MatchType::Value(rel_match) => self.comparator.relational(rel_match, &status, &value),
-            MatchType::Matches(capture_positions) => self.comparator.matches(
+            MatchType::Is => comparator.is(&status, &value),
+            MatchType::Contains => {
+                comparator.contains(status.to_string().as_ref(), value.to_string().as_ref())
+            }
+            MatchType::Value(rel_match) => comparator.relational(rel_match, &status, &value),
+            MatchType::Matches(capture_positions) => comparator.glob(
+                &self.value,
+                &value,
                 status.to_string().as_ref(),
-                value.to_string().as_ref(),
                 *capture_positions,
                 &mut captured_values,
             ),
-            MatchType::Regex(capture_positions) => self.comparator.regex(
+            MatchType::Regex(capture_positions) => comparator.regex(
                 &self.value,
                 &value,
                 status.to_string().as_ref(),
@@ -58,6 +60,10 @@ impl TestSpamTest {
             MatchType::List => false,
         };
 
+        if let Some(err) = comparator.take_error() {
+            return TestResult::Error(err);
+        }
+
         if !captured_values.is_empty() {
             ctx.set_match_variables(captured_values);
         }
@@ -68,23 +74,25 @@ impl TestSpamTest {
 
 impl TestVirusTest {
     pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
+        let comparator = ctx.comparator(&self.comparator);
         let status = ctx.virus_status.as_number();
         let value = ctx.eval_value(&self.value);
         let mut captured_values = Vec::new();
 
         let result = match &self.match_type {
-            MatchType::Is => self.comparator.is(&status, &value),
-            MatchType::Contains => self
-                .comparator
-                .contains(status.to_string().as_ref(), value.to_string().as_ref()),
-            MatchType::Value(rel_match) => self.comparator.relational(rel_match, &status, &value),
-            MatchType::Matches(capture_positions) => self.comparator.matches(
+            MatchType::Is => comparator.is(&status, &value),
+            MatchType::Contains => {
+                comparator.contains(status.to_string().as_ref(), value.to_string().as_ref())
+            }
+            MatchType::Value(rel_match) => comparator.relational(rel_match, &status, &value),
+            MatchType::Matches(capture_positions) => comparator.glob(
+                &self.value,
+                &value,
                 status.to_string().as_ref(),
-                value.to_string().as_ref(),
                 *capture_positions,
                 &mut captured_values,
             ),
-            MatchType::Regex(capture_positions) => self.comparator.regex(
+            MatchType::Regex(capture_positions) => comparator.regex(
                 &self.value,
                 &value,
                 status.to_string().as_ref(),
@@ -102,6 +110,10 @@ impl TestVirusTest {
             MatchType::List => false,
         };
 
+        if let Some(err) = comparator.take_error() {
+            return TestResult::Error(err);
+        }
+
         if !captured_values.is_empty() {
             ctx.set_match_variables(captured_values);
         }
@@ -16,6 +16,7 @@ use super::TestResult;
 
 impl TestString {
     pub(crate) fn exec(&self, ctx: &mut Context, empty_is_null: bool) -> TestResult {
+        let comparator = ctx.comparator(&self.comparator);
         let mut result = false;
 
         match &self.match_type {
@@ -49,7 +50,7 @@ impl TestString {
                         event: Event::ListContains {
                             lists: ctx.eval_values_owned(&self.key_list),
                             values,
-                            match_as: self.comparator.as_match(),
+                            match_as: comparator.as_match(),
                         },
                         is_not: self.is_not,
                     };
@@ -64,21 +65,22 @@ impl TestString {
                     for source in &sources {
                         if !empty_is_null || !source.is_empty() {
                             result = match &self.match_type {
-                                MatchType::Is => self.comparator.is(source, &key),
-                                MatchType::Contains => self.comparator.contains(
+                                MatchType::Is => comparator.is(source, &key),
+                                MatchType::Contains => comparator.contains(
                                     source.to_string().as_ref(),
                                     key.to_string().as_ref(),
                                 ),
                                 MatchType::Value(relation) => {
-                                    self.comparator.relational(relation, source, &key)
+                                    comparator.relational(relation, source, &key)
                                 }
-                                MatchType::Matches(capture_positions) => self.comparator.matches(
+                                MatchType::Matches(capture_positions) => comparator.glob(
+                                    pattern,
+                                    &key,
                                     source.to_string().as_ref(),
-                                    key.to_string().as_ref(),
                                     *capture_positions,
                                     &mut captured_values,
                                 ),
-                                MatchType::Regex(capture_positions) => self.comparator.regex(
+                                MatchType::Regex(capture_positions) => comparator.regex(
                                     pattern,
                                     &key,
                                     source.to_string().as_ref(),
@@ -95,6 +97,10 @@ impl TestString {
                     }
                 }
 
+                if let Some(err) = comparator.take_error() {
+                    return TestResult::Error(err);
+                }
+
                 if !captured_values.is_empty() {
                     ctx.set_match_variables(captured_values);
                 }
@@ -0,0 +1,95 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::{
+    mem::Discriminant,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use ahash::AHashMap;
+
+use crate::compiler::grammar::tests::test_body::BodyTransform;
+
+use super::tests::glob::CaseFold;
+
+/// Decoded and case-folded header values and bodies, kept for the duration
+/// of a filter run and dropped as soon as the message is modified.
+#[derive(Debug, Default)]
+pub(crate) struct TextCache(Mutex<AHashMap<TextKey, Arc<str>>>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum TextKey {
+    Header {
+        part_id: u32,
+        pos: usize,
+        case_fold: CaseFold,
+    },
+    // Parts are identified by the part the search started from and
+    // their position in the traversal, stable until the message is modified
+    Body {
+        root_id: u32,
+        part_num: usize,
+        transform: Discriminant<BodyTransform>,
+        case_fold: Option<CaseFold>,
+    },
+}
+
+impl TextCache {
+    pub(crate) fn get(&self, key: &TextKey) -> Option<Arc<str>> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(key)
+            .cloned()
+    }
+
+    pub(crate) fn insert(&self, key: TextKey, text: impl Into<Arc<str>>) -> Arc<str> {
+        let text = text.into();
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(key, text.clone());
+        text
+    }
+
+    pub(crate) fn get_or_insert(&self, key: TextKey, build: impl FnOnce() -> String) -> Arc<str> {
+        if let Some(text) = self.get(&key) {
+            text
+        } else {
+            self.insert(key, build())
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.0
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+}
+
+impl TextKey {
+    pub(crate) fn body(
+        root_id: u32,
+        part_num: usize,
+        transform: &BodyTransform,
+        case_fold: Option<CaseFold>,
+    ) -> Self {
+        TextKey::Body {
+            root_id,
+            part_num,
+            transform: std::mem::discriminant(transform),
+            case_fold,
+        }
+    }
+}
+
+// Cached values refer to the message of the original context
+impl Clone for TextCache {
+    fn clone(&self) -> Self {
+        TextCache::default()
+    }
+}
@@ -0,0 +1,242 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use mail_parser::HeaderName;
+
+use crate::{
+    compiler::grammar::{actions::action_mime::MimeOpts, test::Test, SourcePosition},
+    Context, Event,
+};
+
+use super::{tests::TestResult, Variable};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Trace {
+    Test {
+        position: Option<SourcePosition>,
+        name: &'static str,
+        values: Vec<String>,
+        keys: Vec<String>,
+        result: Option<bool>,
+        match_vars: Vec<(usize, String)>,
+    },
+    Action {
+        position: Option<SourcePosition>,
+        event: Event,
+    },
+}
+
+impl Context<'_> {
+    pub(crate) fn exec_test_traced(&mut self, test: &Test) -> TestResult {
+        let position = self.current_position();
+        let values = test.trace_values(self);
+        let keys = test.trace_keys(self);
+        let prev_vars_match = self.vars_match.clone();
+
+        let result = test.exec(self);
+
+        let match_vars = self
+            .vars_match
+            .iter()
+            .enumerate()
+            .filter(|(num, var)| {
+                prev_vars_match
+                    .get(*num)
+                    .is_none_or(|prev_var| prev_var.to_string() != var.to_string())
+            })
+            .map(|(num, var)| (num, var.to_string().into_owned()))
+            .collect();
+
+        self.add_trace(Trace::Test {
+            position,
+            name: test.name(),
+            values,
+            keys,
+            result: match &result {
+                TestResult::Bool(result) => Some(*result),
+                TestResult::Event { .. } | TestResult::Error(_) => None,
+            },
+            match_vars,
+        });
+
+        result
+    }
+
+    pub(crate) fn trace_eval(&mut self) {
+        self.add_trace(Trace::Test {
+            position: self.current_position(),
+            name: "eval",
+            values: vec![],
+            keys: vec![],
+            result: Some(self.test_result),
+            match_vars: vec![],
+        });
+    }
+
+    pub(crate) fn trace_test_result(&mut self) {
+        let test_result = self.test_result;
+        if let Some(Trace::Test {
+            result: result @ None,
+            ..
+        }) = self.trace.as_mut().and_then(|trace| trace.last_mut())
+        {
+            *result = Some(test_result);
+        }
+    }
+
+    pub(crate) fn trace_event(&mut self, event: &Event) {
+        if !matches!(
+            event,
+            Event::IncludeScript { .. }
+                | Event::MailboxExists { .. }
+                | Event::ListContains { .. }
+                | Event::DuplicateId { .. }
+                | Event::Function { .. }
+        ) {
+            self.add_trace(Trace::Action {
+                position: self.current_position(),
+                event: event.clone(),
+            });
+        }
+    }
+
+    fn add_trace(&mut self, entry: Trace) {
+        if let Some(trace) = &mut self.trace {
+            trace.push(entry);
+        }
+    }
+
+    fn header_values(
+        &self,
+        header_names: &[HeaderName],
+        index: Option<i32>,
+        any_child: bool,
+    ) -> Vec<String> {
+        let mut values = Vec::new();
+        self.find_headers(header_names, index, any_child, |header, _, _| {
+            self.find_header_values(header, &MimeOpts::None::<Variable>, |value| {
+                values.push(value.to_string());
+                false
+            })
+        });
+        values
+    }
+}
+
+impl Test {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Test::True => "true",
+            Test::False => "false",
+            Test::Address(_) => "address",
+            Test::Envelope(_) => "envelope",
+            Test::Exists(_) => "exists",
+            Test::Header(_) => "header",
+            Test::Size(_) => "size",
+            Test::Invalid(_) => "invalid",
+            Test::Body(_) => "body",
+            Test::Convert(_) => "convert",
+            Test::Date(_) => "date",
+            Test::CurrentDate(_) => "currentdate",
+            Test::Duplicate(_) => "duplicate",
+            Test::String(_) => "string",
+            Test::Environment(_) => "environment",
+            Test::NotifyMethodCapability(_) => "notify_method_capability",
+            Test::ValidNotifyMethod(_) => "valid_notify_method",
+            Test::ValidExtList(_) => "valid_ext_list",
+            Test::Ihave(_) => "ihave",
+            Test::HasFlag(_) => "hasflag",
+            Test::MailboxExists(_) => "mailboxexists",
+            Test::Metadata(_) => "metadata",
+            Test::MetadataExists(_) => "metadataexists",
+            Test::MailboxIdExists(_) => "mailboxidexists",
+            Test::SpamTest(_) => "spamtest",
+            Test::VirusTest(_) => "virustest",
+            Test::SpecialUseExists(_) => "specialuse_exists",
+            Test::Vacation(_) => "vacation",
+            #[cfg(test)]
+            Test::TestCmd { .. } => "test",
+        }
+    }
+
+    fn trace_keys(&self, ctx: &Context) -> Vec<String> {
+        match self {
+            Test::Address(test) => ctx.eval_values_owned(&test.key_list),
+            Test::Envelope(test) => ctx.eval_values_owned(&test.key_list),
+            Test::Header(test) => ctx.eval_values_owned(&test.key_list),
+            Test::Body(test) => ctx.eval_values_owned(&test.key_list),
+            Test::Date(test) => ctx.eval_values_owned(&test.key_list),
+            Test::CurrentDate(test) => ctx.eval_values_owned(&test.key_list),
+            Test::String(test) | Test::Environment(test) => ctx.eval_values_owned(&test.key_list),
+            Test::NotifyMethodCapability(test) => ctx.eval_values_owned(&test.key_list),
+            Test::Metadata(test) => ctx.eval_values_owned(&test.key_list),
+            Test::HasFlag(test) => ctx.eval_values_owned(&test.flags),
+            Test::Exists(test) => ctx.eval_values_owned(&test.header_names),
+            Test::ValidNotifyMethod(test) => ctx.eval_values_owned(&test.notification_uris),
+            Test::ValidExtList(test) => ctx.eval_values_owned(&test.list_names),
+            Test::MailboxExists(test) => ctx.eval_values_owned(&test.mailbox_names),
+            Test::Ihave(test) => test.capabilities.iter().map(|c| c.to_string()).collect(),
+            Test::SpamTest(test) => ctx.eval_values_owned(std::slice::from_ref(&test.value)),
+            Test::VirusTest(test) => ctx.eval_values_owned(std::slice::from_ref(&test.value)),
+            Test::Size(test) => vec![test.limit.to_string()],
+            _ => vec![],
+        }
+    }
+
+    fn trace_values(&self, ctx: &Context) -> Vec<String> {
+        match self {
+            Test::Header(test) => ctx.header_values(
+                &ctx.parse_header_names(&test.header_list),
+                test.index,
+                test.mime_anychild,
+            ),
+            Test::Date(test) => ctx
+                .parse_header_name(&test.header_name)
+                .map(|header_name| {
+                    ctx.header_values(&[header_name], test.index, test.mime_anychild)
+                })
+                .unwrap_or_default(),
+            Test::Address(test) => {
+                let mut values = Vec::new();
+                ctx.find_headers(
+                    &ctx.parse_header_names(&test.header_list),
+                    test.index,
+                    test.mime_anychild,
+                    |header, _, _| {
+                        ctx.find_addresses(header, &test.address_part, |value| {
+                            values.push(value.to_string());
+                            false
+                        })
+                    },
+                );
+                values
+            }
+            Test::Envelope(test) => {
+                let mut values = Vec::new();
+                ctx.find_envelopes(test, |value| {
+                    values.push(value.to_string());
+                    false
+                });
+                values
+            }
+            Test::String(test) | Test::Environment(test) => ctx.eval_values_owned(&test.source),
+            Test::HasFlag(test) => test
+                .variable_list
+                .iter()
+                .filter_map(|var| ctx.get_variable(var))
+                .map(|flags| flags.to_string().into_owned())
+                .collect(),
+            Test::Size(_) => vec![ctx.message_size.to_string()],
+            Test::SpamTest(test) => vec![if test.percent {
+                ctx.spam_status.as_percentage().to_string().into_owned()
+            } else {
+                ctx.spam_status.as_number().to_string().into_owned()
+            }],
+            Test::VirusTest(_) => vec![ctx.virus_status.as_number().to_string().into_owned()],
+            _ => vec![],
+        }
+    }
+}